@@ -0,0 +1,91 @@
+use std::cell::RefCell;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use tide::{Middleware, Next, Request};
+
+thread_local! {
+    /// Upstreams asked by the `Traced` future being polled on this thread.
+    static ASKED: RefCell<Option<Vec<String>>> = const { RefCell::new(None) };
+}
+
+/// Notes that `upstream` was asked, for the request being traced if any.
+pub fn note(upstream: &str) {
+    ASKED.with(|asked| {
+        if let Some(asked) = asked.borrow_mut().as_mut() {
+            asked.push(upstream.into());
+        }
+    });
+}
+
+/// Upstreams asked so far by the request being traced, in order, one entry
+/// per query.
+pub fn asked() -> Vec<String> {
+    ASKED.with(|asked| asked.borrow().clone().unwrap_or_default())
+}
+
+/// `future`, collecting what `note` is told while it is polled. Tasks it
+/// spawns are not traced.
+pub struct Traced<F> {
+    future: Pin<Box<F>>,
+    asked: Vec<String>,
+}
+
+impl<F: Future> Traced<F> {
+    pub fn new(future: F) -> Self {
+        Self {
+            future: Box::pin(future),
+            asked: Vec::new(),
+        }
+    }
+}
+
+impl<F: Future> Future for Traced<F> {
+    type Output = F::Output;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<F::Output> {
+        let this = &mut *self;
+        let outer = ASKED.with(|asked| asked.replace(Some(std::mem::take(&mut this.asked))));
+        let poll = this.future.as_mut().poll(cx);
+        this.asked = ASKED.with(|asked| asked.replace(outer)).unwrap_or_default();
+        poll
+    }
+}
+
+/// Traces the upstreams each request asks, for the diagnostics of
+/// `VERBOSE_ERRORS`.
+pub struct Attempts;
+
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for Attempts {
+    async fn handle(&self, req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        Ok(Traced::new(next.run(req)).await)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{asked, note, Traced};
+
+    #[async_std::test]
+    async fn traced() {
+        note("untraced");
+        assert!(asked().is_empty());
+        let first = Traced::new(async {
+            note("a");
+            async_std::task::yield_now().await;
+            note("b");
+            asked()
+        });
+        let second = Traced::new(async {
+            note("c");
+            async_std::task::yield_now().await;
+            asked()
+        });
+        let (first, second) = futures_util::future::join(first, second).await;
+        assert_eq!(first, ["a", "b"]);
+        assert_eq!(second, ["c"]);
+        assert!(asked().is_empty());
+    }
+}
@@ -0,0 +1,346 @@
+use std::io;
+use std::net::SocketAddr;
+
+use async_std::net::TcpStream;
+use futures_util::stream::{self, Stream, TryStreamExt};
+use serde::Deserialize;
+use tide::{Body, Request, Response, StatusCode};
+use trust_dns_resolver::proto::op::{Message, ResponseCode};
+use trust_dns_resolver::proto::rr::{Name, Record, RecordType};
+
+use crate::{bind, cors, params, rdata, wire, Format, State};
+
+pub const AXFR_NOT_ALLOWED: &str = "axfr_not_allowed";
+pub const AXFR_REFUSED: &str = "axfr_refused";
+pub const AXFR_ERROR: &str = "axfr_error";
+const RECORD_LIMIT: &str = "record_limit";
+const BYTE_LIMIT: &str = "byte_limit";
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct AxfrQuery {
+    format: Option<Format>,
+}
+
+/// `/axfr/:zone`: transfers an allowed zone from its master and streams
+/// it as zone-file text, or NDJSON with `format=json`. Dropping the body,
+/// as on client disconnect, closes the connection to the master.
+pub async fn handler(req: Request<State>) -> tide::Result {
+    let zone = req
+        .param("zone")?
+        .trim_end_matches('.')
+        .to_ascii_lowercase();
+    let query: AxfrQuery = params::query(&req, &[])?;
+    let opts = &req.state().opts;
+    let master = match opts.axfr_masters.get(&zone) {
+        Some(master) if opts.axfr_allowed_zones.contains(&zone) => *master,
+        _ => {
+            return Ok(Response::builder(StatusCode::Forbidden)
+                .body(AXFR_NOT_ALLOWED)
+                .build())
+        }
+    };
+    let name = match Name::from_ascii(&zone) {
+        Ok(name) => name,
+        Err(_) => return Ok(Response::builder(StatusCode::BadRequest).build()),
+    };
+    let (conn, first) = match start(master, name).await {
+        Ok(started) => started,
+        Err(err) => {
+            tide::log::warn!("axfr", { zone: zone, master: master.to_string(), error: err.to_string() });
+            return Ok(Response::builder(StatusCode::BadGateway)
+                .body(AXFR_ERROR)
+                .build());
+        }
+    };
+    if first.response_code() != ResponseCode::NoError {
+        return Ok(Response::builder(StatusCode::Forbidden)
+            .header(cors::X_RCODE, rcode_name(first.response_code()))
+            .body(AXFR_REFUSED)
+            .build());
+    }
+    if !matches!(first.answers().first(), Some(record) if record.rr_type() == RecordType::SOA) {
+        return Ok(Response::builder(StatusCode::BadGateway)
+            .body(AXFR_ERROR)
+            .build());
+    }
+    let json = query.format == Some(Format::Json);
+    let limits = Limits {
+        records: opts.axfr_max_records,
+        bytes: opts.axfr_max_bytes,
+    };
+    let body = records(conn, first, json, limits).into_async_read();
+    let mime = if json {
+        "application/x-ndjson"
+    } else {
+        "text/plain"
+    };
+    Ok(Response::builder(StatusCode::Ok)
+        .body(Body::from_reader(body, None))
+        .content_type(mime)
+        .build())
+}
+
+#[derive(Clone, Copy)]
+struct Limits {
+    records: usize,
+    bytes: usize,
+}
+
+/// Connects to `master`, asks for the zone and reads the first message.
+async fn start(master: SocketAddr, zone: Name) -> io::Result<(TcpStream, Message)> {
+    let mut conn = bind::tcp_connect(master).await?;
+    let mut message = wire::query(zone, RecordType::AXFR, false);
+    message.set_recursion_desired(false);
+    wire::write_tcp(&mut conn, &message).await?;
+    let first = wire::read_tcp(&mut conn).await?;
+    Ok((conn, first))
+}
+
+/// Mnemonic such as `REFUSED` or `NOTAUTH`.
+pub fn rcode_name(code: ResponseCode) -> String {
+    format!("{:?}", code).to_ascii_uppercase()
+}
+
+struct Transfer {
+    conn: TcpStream,
+    /// Next message to render, the first one is read by `start`.
+    next: Option<Message>,
+    json: bool,
+    limits: Limits,
+    records: usize,
+    bytes: usize,
+    done: bool,
+}
+
+/// Rendered records, a chunk per message. The zone ends at the second SOA;
+/// exceeding a limit or failing ends it early with an error line.
+fn records(
+    conn: TcpStream,
+    first: Message,
+    json: bool,
+    limits: Limits,
+) -> impl Stream<Item = io::Result<Vec<u8>>> + Send + Sync + Unpin + 'static {
+    let transfer = Transfer {
+        conn,
+        next: Some(first),
+        json,
+        limits,
+        records: 0,
+        bytes: 0,
+        done: false,
+    };
+    Box::pin(stream::unfold(transfer, |mut t| async move {
+        if t.done {
+            return None;
+        }
+        let message = match t.next.take() {
+            Some(message) => Ok(message),
+            None => wire::read_tcp(&mut t.conn).await,
+        };
+        let answers = match message {
+            Ok(message) if message.response_code() == ResponseCode::NoError => {
+                message.answers().to_vec()
+            }
+            Ok(message) => {
+                t.done = true;
+                let err = format!("{} {}", AXFR_REFUSED, rcode_name(message.response_code()));
+                return Some((Ok(t.error_line(&err)), t));
+            }
+            Err(err) => {
+                t.done = true;
+                tide::log::warn!("axfr", { error: err.to_string() });
+                return Some((Ok(t.error_line(AXFR_ERROR)), t));
+            }
+        };
+        let mut chunk = Vec::new();
+        for record in &answers {
+            t.records += 1;
+            // The opening SOA is record 1, the closing one ends the zone.
+            if t.records > 1 && record.rr_type() == RecordType::SOA {
+                t.done = true;
+            }
+            let line = t.line(record);
+            if t.records > t.limits.records {
+                t.done = true;
+                chunk.extend(t.error_line(RECORD_LIMIT));
+                break;
+            }
+            if t.bytes + chunk.len() + line.len() > t.limits.bytes {
+                t.done = true;
+                chunk.extend(t.error_line(BYTE_LIMIT));
+                break;
+            }
+            chunk.extend(line.into_bytes());
+            if t.done {
+                break;
+            }
+        }
+        t.bytes += chunk.len();
+        Some((Ok(chunk), t))
+    }))
+}
+
+impl Transfer {
+    fn line(&self, record: &Record) -> String {
+        if self.json {
+            format!("{}\n", rdata::json(record))
+        } else {
+            format!(
+                "{} {} IN {} {}\n",
+                record.name().to_ascii(),
+                record.ttl(),
+                rdata::type_name(record.rr_type()),
+                rdata::text(record.rdata())
+            )
+        }
+    }
+
+    fn error_line(&self, error: &str) -> Vec<u8> {
+        if self.json {
+            format!("{}\n", serde_json::json!({ "error": error })).into_bytes()
+        } else {
+            format!("; error: {}\n", error).into_bytes()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::net::SocketAddr;
+    use std::sync::Arc;
+
+    use async_std::io::{ReadExt, WriteExt};
+    use async_std::net::TcpListener;
+    use tide::http::{Method, Request, Response, Url};
+    use trust_dns_resolver::proto::op::{Message, MessageType, ResponseCode};
+    use trust_dns_resolver::proto::rr::rdata::SOA;
+    use trust_dns_resolver::proto::rr::{Name, RData, Record};
+
+    use crate::opts::Opts;
+    use crate::server;
+    use crate::tests::state;
+    use crate::upstream::mock::Mock;
+
+    /// Master answering one transfer with `messages`, or `rcode`.
+    async fn master(rcode: ResponseCode, messages: Vec<Vec<Record>>) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        async_std::task::spawn(async move {
+            let (mut conn, _) = listener.accept().await.unwrap();
+            let mut len = [0; 2];
+            conn.read_exact(&mut len).await.unwrap();
+            let mut buf = vec![0; u16::from_be_bytes(len).into()];
+            conn.read_exact(&mut buf).await.unwrap();
+            let query = Message::from_vec(&buf).unwrap();
+            let messages = if rcode == ResponseCode::NoError {
+                messages
+            } else {
+                vec![vec![]]
+            };
+            for records in messages {
+                let mut message = Message::new();
+                message
+                    .set_id(query.id())
+                    .set_message_type(MessageType::Response)
+                    .set_response_code(rcode);
+                message.add_answers(records);
+                let buf = message.to_vec().unwrap();
+                let mut framed = (buf.len() as u16).to_be_bytes().to_vec();
+                framed.extend(buf);
+                if conn.write_all(&framed).await.is_err() {
+                    return;
+                }
+            }
+        });
+        addr
+    }
+
+    async fn get(master: SocketAddr, path: &str, max_records: usize) -> Response {
+        let mut state = state(Mock::default());
+        state.opts = Arc::new(Opts {
+            axfr_allowed_zones: vec!["zone.example".into()],
+            axfr_masters: HashMap::from([("zone.example".into(), master)]),
+            axfr_max_records: max_records,
+            ..Opts::default()
+        });
+        let url = Url::parse("http://localhost").unwrap().join(path).unwrap();
+        server(state)
+            .respond(Request::new(Method::Get, url))
+            .await
+            .unwrap()
+    }
+
+    fn zone() -> Vec<Vec<Record>> {
+        let origin = Name::from_ascii("zone.example.").unwrap();
+        let soa = RData::SOA(SOA::new(
+            Name::from_ascii("ns.zone.example.").unwrap(),
+            Name::from_ascii("admin.zone.example.").unwrap(),
+            1,
+            3600,
+            600,
+            86400,
+            60,
+        ));
+        let soa = Record::from_rdata(origin.clone(), 300, soa);
+        let a = Record::from_rdata(origin, 300, RData::A("192.0.2.1".parse().unwrap()));
+        vec![vec![soa.clone(), a], vec![soa]]
+    }
+
+    #[async_std::test]
+    async fn transfer() {
+        let mut res = get(
+            master(ResponseCode::NoError, zone()).await,
+            "/axfr/zone.example",
+            100,
+        )
+        .await;
+        assert_eq!(res.status(), 200);
+        let body = res.body_string().await.unwrap();
+        let lines = body.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[0].starts_with("zone.example. 300 IN SOA ns.zone.example."));
+        assert_eq!(lines[1], "zone.example. 300 IN A 192.0.2.1");
+
+        let mut res = get(
+            master(ResponseCode::NoError, zone()).await,
+            "/axfr/zone.example",
+            2,
+        )
+        .await;
+        let body = res.body_string().await.unwrap();
+        assert!(body.ends_with("; error: record_limit\n"));
+
+        let mut res = get(
+            master(ResponseCode::NoError, zone()).await,
+            "/axfr/zone.example?format=json",
+            100,
+        )
+        .await;
+        let body = res.body_string().await.unwrap();
+        let first: serde_json::Value = serde_json::from_str(body.lines().next().unwrap()).unwrap();
+        assert_eq!(first["type"], "SOA");
+    }
+
+    #[async_std::test]
+    async fn refused() {
+        let mut res = get(
+            master(ResponseCode::Refused, vec![]).await,
+            "/axfr/zone.example",
+            100,
+        )
+        .await;
+        assert_eq!(res.status(), 403);
+        assert_eq!(res["X-Rcode"], "REFUSED");
+        assert_eq!(res.body_string().await.unwrap(), "axfr_refused");
+        let mut res = get(
+            master(ResponseCode::NoError, zone()).await,
+            "/axfr/other.example",
+            100,
+        )
+        .await;
+        assert_eq!(res.status(), 403);
+        assert_eq!(res.body_string().await.unwrap(), "axfr_not_allowed");
+    }
+}
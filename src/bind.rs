@@ -0,0 +1,406 @@
+use std::future::Future;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::ops::RangeInclusive;
+use std::pin::Pin;
+use std::sync::atomic::AtomicU64;
+use std::sync::{Mutex, OnceLock};
+use std::task::{ready, Context, Poll};
+
+use async_std::io::{Read as AsyncRead, Write as AsyncWrite};
+use async_trait::async_trait;
+use rand::Rng;
+use tokio1::net::{TcpSocket, TcpStream, UdpSocket};
+use trust_dns_resolver::name_server::{
+    GenericConnection, GenericConnectionProvider, RuntimeProvider,
+};
+use trust_dns_resolver::proto::iocompat::AsyncIoTokioAsStd;
+use trust_dns_resolver::proto::tcp::{Connect, DnsTcpStream};
+use trust_dns_resolver::proto::udp;
+use trust_dns_resolver::proto::TokioTime;
+use trust_dns_resolver::{AsyncResolver, TokioHandle};
+
+use crate::egress::Gate;
+use crate::metrics::Metrics;
+
+/// Source addresses of upstream sockets, at most one per family.
+static BIND_ADDRS: OnceLock<Vec<IpAddr>> = OnceLock::new();
+
+/// Network interface upstream sockets are bound to, Linux only.
+static INTERFACE: OnceLock<String> = OnceLock::new();
+
+/// Source ports of upstream UDP sockets, ephemeral when unset.
+static PORT_RANGE: OnceLock<RangeInclusive<u16>> = OnceLock::new();
+
+/// Random ports of the range tried before giving up, when in use.
+const PORT_ATTEMPTS: usize = 16;
+
+/// TCP connections opened to upstreams and masters. Sockets come from the
+/// runtime provider, which has no state of its own to count them in.
+pub static TCP_CONNECTS: AtomicU64 = AtomicU64::new(0);
+
+pub type BoundResolver = AsyncResolver<GenericConnection, GenericConnectionProvider<BoundRuntime>>;
+
+/// Tokio runtime whose sockets leave from the `BIND_ADDRS`.
+#[derive(Clone, Copy)]
+pub struct BoundRuntime;
+
+impl RuntimeProvider for BoundRuntime {
+    type Handle = TokioHandle;
+    type Timer = TokioTime;
+    type Udp = BoundUdp;
+    type Tcp = BoundTcp;
+}
+
+/// Sets the source addresses once at startup, checking that they can be
+/// bound.
+pub fn set(addrs: Vec<IpAddr>) -> io::Result<()> {
+    for addr in &addrs {
+        std::net::UdpSocket::bind((*addr, 0))?;
+    }
+    BIND_ADDRS
+        .set(addrs)
+        .map_err(|_| io::Error::other("bind addresses already set"))
+}
+
+/// Sets the interface once at startup, checking that a socket can be
+/// bound to it.
+pub fn set_interface(name: String) -> io::Result<()> {
+    check_interface(&name)?;
+    INTERFACE
+        .set(name)
+        .map_err(|_| io::Error::other("interface already set"))
+}
+
+/// Binding to a device needs `CAP_NET_RAW` before Linux 5.7.
+#[cfg(any(target_os = "android", target_os = "linux"))]
+fn check_interface(name: &str) -> io::Result<()> {
+    let socket = std::net::UdpSocket::bind(("0.0.0.0", 0))?;
+    socket2::SockRef::from(&socket).bind_device(Some(name.as_bytes()))
+}
+
+#[cfg(not(any(target_os = "android", target_os = "linux")))]
+fn check_interface(_name: &str) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "binding to an interface needs Linux",
+    ))
+}
+
+/// Binds `socket` to the interface, if one is set.
+#[cfg(any(target_os = "android", target_os = "linux"))]
+fn bind_device<S: std::os::unix::io::AsRawFd>(socket: &S) -> io::Result<()> {
+    match INTERFACE.get() {
+        Some(name) => socket2::SockRef::from(socket).bind_device(Some(name.as_bytes())),
+        None => Ok(()),
+    }
+}
+
+#[cfg(not(any(target_os = "android", target_os = "linux")))]
+fn bind_device<S>(_socket: &S) -> io::Result<()> {
+    Ok(())
+}
+
+/// Sets the UDP source port range once at startup.
+pub fn set_ports(range: RangeInclusive<u16>) -> io::Result<()> {
+    PORT_RANGE
+        .set(range)
+        .map_err(|_| io::Error::other("source ports already set"))
+}
+
+/// Binds `bind` to `addr`, on a random port of the range if one is set.
+async fn bind_port<S, F, Fut>(addr: SocketAddr, bind: F) -> io::Result<S>
+where
+    F: Fn(SocketAddr) -> Fut,
+    Fut: Future<Output = io::Result<S>>,
+{
+    let range = match PORT_RANGE.get() {
+        Some(range) => range.clone(),
+        None => return bind(addr).await,
+    };
+    let mut result = Err(io::ErrorKind::AddrInUse.into());
+    for _ in 0..PORT_ATTEMPTS {
+        let port = rand::thread_rng().gen_range(range.clone());
+        result = bind(SocketAddr::new(addr.ip(), port)).await;
+        match &result {
+            Err(err) if err.kind() == io::ErrorKind::AddrInUse => continue,
+            _ => break,
+        }
+    }
+    result
+}
+
+/// Bind address of the family of `addr`.
+fn bind_ip(addrs: &[IpAddr], addr: SocketAddr) -> Option<IpAddr> {
+    addrs
+        .iter()
+        .copied()
+        .find(|ip| ip.is_ipv4() == addr.is_ipv4())
+}
+
+/// `addr` with its IP replaced by the bind address of its family, if any.
+fn local_addr(addrs: &[IpAddr], addr: SocketAddr) -> SocketAddr {
+    match bind_ip(addrs, addr) {
+        Some(ip) => SocketAddr::new(ip, addr.port()),
+        None => addr,
+    }
+}
+
+/// UDP socket for queries to `server`, from the bind address if any.
+pub async fn udp_socket(server: SocketAddr) -> io::Result<async_std::net::UdpSocket> {
+    let any = match server {
+        SocketAddr::V4(_) => SocketAddr::from(([0; 4], 0)),
+        SocketAddr::V6(_) => SocketAddr::from(([0; 16], 0)),
+    };
+    let socket = bind_port(local_addr(bind_addrs(), any), |addr| {
+        async_std::net::UdpSocket::bind(addr)
+    })
+    .await?;
+    bind_device(&socket)?;
+    Ok(socket)
+}
+
+/// TCP connection to `server`, from the bind address if any.
+pub async fn tcp_connect(server: SocketAddr) -> io::Result<async_std::net::TcpStream> {
+    let connect = async {
+        let AsyncIoTokioAsStd(stream) = BoundTcp::connect(server).await?.stream;
+        stream.into_std().map(async_std::net::TcpStream::from)
+    };
+    async_std::io::timeout(crate::wire::TIMEOUT, connect).await
+}
+
+/// Source addresses upstream sockets are bound to, empty for any.
+pub fn bind_addrs() -> &'static [IpAddr] {
+    BIND_ADDRS.get().map_or(&[], Vec::as_slice)
+}
+
+/// Network interface upstream sockets are bound to, if any.
+pub fn interface() -> Option<&'static str> {
+    INTERFACE.get().map(String::as_str)
+}
+
+/// UDP socket of the resolver, which binds one per query.
+pub struct BoundUdp {
+    socket: UdpSocket,
+    gate: Mutex<Gate>,
+}
+
+#[async_trait]
+impl udp::UdpSocket for BoundUdp {
+    type Time = TokioTime;
+
+    /// Called with an unspecified address and a random port, replaced by
+    /// one of the source port range if set.
+    async fn bind(addr: SocketAddr) -> io::Result<Self> {
+        let socket = bind_port(local_addr(bind_addrs(), addr), UdpSocket::bind).await?;
+        bind_device(&socket)?;
+        Ok(BoundUdp {
+            socket,
+            gate: Default::default(),
+        })
+    }
+
+    fn poll_recv_from(
+        &self,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<(usize, SocketAddr)>> {
+        udp::UdpSocket::poll_recv_from(&self.socket, cx, buf)
+    }
+
+    fn poll_send_to(
+        &self,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+        target: SocketAddr,
+    ) -> Poll<io::Result<usize>> {
+        let mut gate = self.gate.lock().unwrap();
+        ready!(gate.poll_budget(cx))?;
+        let sent = ready!(udp::UdpSocket::poll_send_to(&self.socket, cx, buf, target));
+        match sent {
+            Ok(_) => gate.sent(),
+            Err(_) => gate.failed(),
+        }
+        Poll::Ready(sent)
+    }
+}
+
+/// TCP connection of the resolver, which may carry several queries.
+pub struct BoundTcp {
+    stream: AsyncIoTokioAsStd<TcpStream>,
+    gate: Mutex<Gate>,
+    framing: Framing,
+}
+
+/// Where a stream is in the length-prefixed messages written to it, so
+/// that each query is admitted before its first byte.
+#[derive(Default)]
+struct Framing {
+    /// First byte of the length, when only it is written.
+    high: Option<u8>,
+    /// Bytes of the message left to write after its length.
+    remaining: usize,
+}
+
+impl Framing {
+    fn at_start(&self) -> bool {
+        self.high.is_none() && self.remaining == 0
+    }
+
+    /// Length of `buf` up to the end of the message being written, so that
+    /// a write holds one message at most.
+    fn within(&self, buf: &[u8]) -> usize {
+        if self.remaining > 0 {
+            return self.remaining.min(buf.len());
+        }
+        let (length, taken) = match (self.high, buf) {
+            (Some(high), [low, ..]) => ([high, *low], 1),
+            (None, [high, low, ..]) => ([*high, *low], 2),
+            _ => return buf.len(),
+        };
+        taken + usize::from(u16::from_be_bytes(length)).min(buf.len() - taken)
+    }
+
+    fn wrote(&mut self, mut buf: &[u8]) {
+        while let Some((&byte, rest)) = buf.split_first() {
+            if self.remaining > 0 {
+                let n = self.remaining.min(buf.len());
+                self.remaining -= n;
+                buf = &buf[n..];
+                continue;
+            }
+            match self.high.take() {
+                Some(high) => self.remaining = usize::from(u16::from_be_bytes([high, byte])),
+                None => self.high = Some(byte),
+            }
+            buf = rest;
+        }
+    }
+}
+
+impl DnsTcpStream for BoundTcp {
+    type Time = TokioTime;
+}
+
+#[async_trait]
+impl Connect for BoundTcp {
+    async fn connect(addr: SocketAddr) -> io::Result<Self> {
+        let socket = match addr {
+            SocketAddr::V4(_) => TcpSocket::new_v4()?,
+            SocketAddr::V6(_) => TcpSocket::new_v6()?,
+        };
+        if let Some(ip) = bind_ip(bind_addrs(), addr) {
+            socket.bind(SocketAddr::new(ip, 0))?;
+        }
+        bind_device(&socket)?;
+        let stream = socket.connect(addr).await?;
+        Metrics::inc(&TCP_CONNECTS);
+        stream.set_nodelay(true)?;
+        Ok(BoundTcp {
+            stream: AsyncIoTokioAsStd(stream),
+            gate: Default::default(),
+            framing: Default::default(),
+        })
+    }
+}
+
+impl AsyncRead for BoundTcp {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for BoundTcp {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        let gate = this.gate.get_mut().unwrap();
+        if this.framing.at_start() && !buf.is_empty() {
+            ready!(gate.poll_budget(cx))?;
+        }
+        let buf = &buf[..this.framing.within(buf)];
+        let written = match ready!(Pin::new(&mut this.stream).poll_write(cx, buf)) {
+            Ok(written) => written,
+            Err(err) => {
+                gate.failed();
+                return Poll::Ready(Err(err));
+            }
+        };
+        this.framing.wrote(&buf[..written]);
+        if this.framing.at_start() {
+            gate.sent();
+        }
+        Poll::Ready(Ok(written))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_close(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+    use std::sync::atomic::Ordering;
+
+    use async_std::net::TcpListener;
+
+    use super::{check_interface, local_addr, tcp_connect, Framing, TCP_CONNECTS};
+
+    #[test]
+    fn local() {
+        let addrs = ["192.0.2.1".parse().unwrap()];
+        let any4: SocketAddr = "0.0.0.0:5300".parse().unwrap();
+        let any6: SocketAddr = "[::]:5300".parse().unwrap();
+        assert_eq!(local_addr(&addrs, any4), "192.0.2.1:5300".parse().unwrap());
+        assert_eq!(local_addr(&addrs, any6), any6);
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn interface() {
+        assert!(check_interface("bdns-none0").is_err());
+        // Unprivileged kernels before 5.7 refuse any device.
+        if let Err(err) = check_interface("lo") {
+            assert_eq!(err.kind(), std::io::ErrorKind::PermissionDenied);
+        }
+    }
+
+    #[test]
+    fn framing() {
+        let mut framing = Framing::default();
+        let buf = [0, 3, 1, 2, 3, 0, 1, 4];
+        assert_eq!(framing.within(&buf), 5);
+        framing.wrote(&buf[..1]);
+        assert!(!framing.at_start());
+        assert_eq!(framing.within(&buf[1..]), 4);
+        framing.wrote(&buf[1..3]);
+        assert_eq!(framing.within(&buf[3..]), 2);
+        framing.wrote(&buf[3..5]);
+        assert!(framing.at_start());
+        assert_eq!(framing.within(&buf[5..]), 3);
+        framing.wrote(&buf[5..]);
+        assert!(framing.at_start());
+    }
+
+    #[async_std::test]
+    async fn connects() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let before = TCP_CONNECTS.load(Ordering::Relaxed);
+        tcp_connect(addr).await.unwrap();
+        tcp_connect(addr).await.unwrap();
+        assert!(TCP_CONNECTS.load(Ordering::Relaxed) >= before + 2);
+    }
+}
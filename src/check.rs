@@ -0,0 +1,254 @@
+use std::env;
+use std::fs;
+use std::panic::{self, UnwindSafe};
+use std::path::Path;
+
+use tide_rustls::rustls::internal::pemfile::{certs, pkcs8_private_keys, rsa_private_keys};
+use tide_rustls::rustls::sign::any_supported_type;
+use tide_rustls::rustls::{PrivateKey, SignatureScheme};
+use trust_dns_resolver::proto::op::ResponseCode;
+use trust_dns_resolver::proto::rr::RecordType;
+
+use crate::opts::{
+    get_opts, parse_bind_addrs, parse_dns, parse_port_range, DEFAULT_CACHE_SIZE, DEFAULT_DNS,
+    ENV_BIND_INTERFACE, ENV_CERT_FILE, ENV_DNS, ENV_KEY_FILE, ENV_PSL_FILE, ENV_SHADOW_DNS,
+    ENV_SOURCE_PORT_RANGE, ENV_UPSTREAM_BIND_ADDR,
+};
+use crate::upstream::{self, Upstream};
+use crate::zones;
+use crate::{bind, upstream_resolver};
+
+/// Signed with the key and verified with the certificate to pair them.
+const CHALLENGE: &[u8] = b"bdns-resolver key check";
+
+/// Schemes offered to the key, one per key type webpki can verify.
+const SCHEMES: &[SignatureScheme] = &[
+    SignatureScheme::ED25519,
+    SignatureScheme::ECDSA_NISTP256_SHA256,
+    SignatureScheme::ECDSA_NISTP384_SHA384,
+    SignatureScheme::RSA_PKCS1_SHA256,
+];
+
+type Outcome = Result<String, String>;
+
+/// Runs every check, printing a PASS/FAIL line each, and returns whether
+/// all passed. `probe` also queries each upstream.
+pub async fn run(probe: bool) -> bool {
+    let mut report = vec![
+        ("config", check_config()),
+        (
+            "tls",
+            check_tls(env::var(ENV_CERT_FILE).ok(), env::var(ENV_KEY_FILE).ok()),
+        ),
+        ("bind", check_bind()),
+        (
+            "psl",
+            check_psl(env::var(ENV_PSL_FILE).ok().filter(|v| !v.is_empty())),
+        ),
+    ];
+    let resolvers = resolvers();
+    report.extend(resolvers.iter().map(|(dns, resolver)| {
+        let outcome = match resolver {
+            Ok(_) => Ok(dns.clone()),
+            Err(err) => Err(err.clone()),
+        };
+        ("upstream", outcome)
+    }));
+    if let Some(dns) = env::var(ENV_SHADOW_DNS).ok().filter(|v| !v.is_empty()) {
+        report.push((
+            "shadow",
+            upstream_resolver(&dns, DEFAULT_CACHE_SIZE, 1).map(|_| dns),
+        ));
+    }
+    if probe {
+        for resolver in resolvers.iter().filter_map(|(_, r)| r.as_ref().ok()) {
+            report.push(("probe", check_probe(resolver).await));
+        }
+    }
+    for (name, outcome) in &report {
+        match outcome {
+            Ok(detail) => println!("PASS {}: {}", name, detail),
+            Err(err) => println!("FAIL {}: {}", name, err),
+        }
+    }
+    report.iter().all(|(_, outcome)| outcome.is_ok())
+}
+
+/// Runs `f`, turning a panic into its message without printing it.
+fn catch<T>(f: impl FnOnce() -> T + UnwindSafe) -> Result<T, String> {
+    let hook = panic::take_hook();
+    panic::set_hook(Box::new(|_| {}));
+    let result = panic::catch_unwind(f);
+    panic::set_hook(hook);
+    result.map_err(|err| {
+        err.downcast_ref::<&str>()
+            .map(ToString::to_string)
+            .or_else(|| err.downcast_ref::<String>().cloned())
+            .unwrap_or_else(|| "panicked".into())
+    })
+}
+
+/// Every env variable, as parsed at startup.
+fn check_config() -> Outcome {
+    catch(get_opts).map(|_| "ok".into())
+}
+
+/// The certificate and key load the way the listener loads them, and the
+/// key belongs to the certificate.
+fn check_tls(cert_file: Option<String>, key_file: Option<String>) -> Outcome {
+    let (cert_file, key_file) = match (cert_file, key_file) {
+        (None, None) => return Ok("disabled".into()),
+        (Some(cert_file), Some(key_file)) => (cert_file, key_file),
+        _ => {
+            return Err(format!(
+                "{} and {} must be set together",
+                ENV_CERT_FILE, ENV_KEY_FILE
+            ))
+        }
+    };
+    let pem = fs::read(&cert_file).map_err(|err| format!("{}: {}", cert_file, err))?;
+    let cert = certs(&mut pem.as_slice())
+        .ok()
+        .and_then(|certs| certs.into_iter().next())
+        .ok_or_else(|| format!("{}: no certificate", cert_file))?;
+    let key = load_key(&key_file)?;
+    let signer = any_supported_type(&key)
+        .ok()
+        .and_then(|key| key.choose_scheme(SCHEMES))
+        .ok_or_else(|| format!("{}: unsupported key", key_file))?;
+    let signature = signer
+        .sign(CHALLENGE)
+        .map_err(|err| format!("{}: {}", key_file, err))?;
+    let alg = match signer.get_scheme() {
+        SignatureScheme::ED25519 => &webpki::ED25519,
+        SignatureScheme::ECDSA_NISTP256_SHA256 => &webpki::ECDSA_P256_SHA256,
+        SignatureScheme::ECDSA_NISTP384_SHA384 => &webpki::ECDSA_P384_SHA384,
+        _ => &webpki::RSA_PKCS1_2048_8192_SHA256,
+    };
+    webpki::EndEntityCert::from(&cert.0)
+        .map_err(|err| format!("{}: {:?}", cert_file, err))?
+        .verify_signature(alg, CHALLENGE, &signature)
+        .map_err(|_| format!("{} does not match {}", key_file, cert_file))?;
+    Ok(cert_file)
+}
+
+/// PKCS#8 first, then PKCS#1, like the listener.
+fn load_key(key_file: &str) -> Result<PrivateKey, String> {
+    let pem = fs::read(key_file).map_err(|err| format!("{}: {}", key_file, err))?;
+    pkcs8_private_keys(&mut pem.as_slice())
+        .ok()
+        .filter(|keys| !keys.is_empty())
+        .or_else(|| rsa_private_keys(&mut pem.as_slice()).ok())
+        .and_then(|keys| keys.into_iter().next())
+        .ok_or_else(|| format!("{}: no private key", key_file))
+}
+
+/// `PSL_FILE` loads as a public suffix list, as zone statistics load it.
+fn check_psl(psl_file: Option<String>) -> Outcome {
+    match psl_file {
+        Some(path) => zones::Suffixes::load(Path::new(&path))
+            .map(|_| path.clone())
+            .map_err(|err| format!("{}: {}", path, err)),
+        None => Ok("builtin".into()),
+    }
+}
+
+/// Upstream source addresses and ports parse, the addresses and the
+/// interface can be bound.
+fn check_bind() -> Outcome {
+    let addrs = catch(|| parse_bind_addrs(&env::var(ENV_UPSTREAM_BIND_ADDR).unwrap_or_default()))?;
+    let ports = catch(|| parse_port_range(&env::var(ENV_SOURCE_PORT_RANGE).unwrap_or_default()))?;
+    let detail = format!("{:?} ports {:?}", addrs, ports);
+    bind::set(addrs).map_err(|err| format!("cannot bind {}: {}", detail, err))?;
+    if let Some(ports) = ports {
+        bind::set_ports(ports).map_err(|err| err.to_string())?;
+    }
+    match env::var(ENV_BIND_INTERFACE) {
+        Ok(name) if !name.is_empty() => {
+            bind::set_interface(name.clone())
+                .map_err(|err| format!("cannot bind to interface {}: {}", name, err))?;
+            Ok(format!("{} interface {}", detail, name))
+        }
+        _ => Ok(detail),
+    }
+}
+
+/// A resolver per `DNS` entry.
+fn resolvers() -> Vec<(String, Result<upstream::Resolver, String>)> {
+    parse_dns(&env::var(ENV_DNS).unwrap_or_else(|_| DEFAULT_DNS.into()))
+        .into_iter()
+        .map(|dns| {
+            let resolver = upstream_resolver(&dns, DEFAULT_CACHE_SIZE, 1);
+            (dns, resolver)
+        })
+        .collect()
+}
+
+/// The upstream answers a root NS query without failing.
+async fn check_probe(resolver: &upstream::Resolver) -> Outcome {
+    match resolver.query(".", RecordType::NS, false).await {
+        Ok(message) if message.response_code() == ResponseCode::NoError => {
+            Ok(resolver.name().into())
+        }
+        Ok(message) => Err(format!("{}: {}", resolver.name(), message.response_code())),
+        Err(err) => Err(format!("{}: {}", resolver.name(), err)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::{catch, check_psl, check_tls};
+
+    /// Writes a self-signed certificate and its key, returning their paths.
+    fn pair(name: &str) -> (String, String) {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+        let dir = std::env::temp_dir();
+        let cert_file = dir.join(format!("bdns-{}-{}.crt", name, std::process::id()));
+        let key_file = dir.join(format!("bdns-{}-{}.key", name, std::process::id()));
+        fs::write(&cert_file, cert.serialize_pem().unwrap()).unwrap();
+        fs::write(&key_file, cert.serialize_private_key_pem()).unwrap();
+        (
+            cert_file.to_string_lossy().into(),
+            key_file.to_string_lossy().into(),
+        )
+    }
+
+    #[test]
+    fn tls() {
+        let (cert, key) = pair("one");
+        let (other_cert, other) = pair("two");
+        assert_eq!(check_tls(None, None).unwrap(), "disabled");
+        assert_eq!(
+            check_tls(Some(cert.clone()), Some(key.clone())).unwrap(),
+            cert
+        );
+        let err = check_tls(Some(cert.clone()), Some(other.clone())).unwrap_err();
+        assert!(err.contains("does not match"), "{}", err);
+        assert!(check_tls(Some(cert.clone()), None).is_err());
+        assert!(check_tls(Some(key.clone()), Some(key.clone())).is_err());
+        for file in [cert, key, other_cert, other] {
+            let _ = fs::remove_file(file);
+        }
+    }
+
+    #[test]
+    fn psl() {
+        assert_eq!(check_psl(None).unwrap(), "builtin");
+        let path = std::env::temp_dir().join(format!("bdns-check-{}.dat", std::process::id()));
+        let path = path.to_string_lossy().into_owned();
+        let err = check_psl(Some(path.clone())).unwrap_err();
+        assert!(err.starts_with(&path), "{}", err);
+        fs::write(&path, "// ===BEGIN ICANN DOMAINS===\ncom\n").unwrap();
+        assert_eq!(check_psl(Some(path.clone())).unwrap(), path);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn panics() {
+        assert_eq!(catch(|| 1).unwrap(), 1);
+        let err = catch(|| -> u8 { panic!("invalid {}: {}", "KEY", "v") }).unwrap_err();
+        assert_eq!(err, "invalid KEY: v");
+    }
+}
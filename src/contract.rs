@@ -0,0 +1,167 @@
+use tide::http::mime;
+use tide::{Middleware, Next, Request};
+
+use crate::{State, EXISTS, HOST_NOT_ALLOWED, NOT_FOUND};
+
+/// `api_contract` of `/version` with `text_contract`.
+pub const TEXT_V1: &str = "text/v1";
+
+/// Answer of a host refused by policy under the contract.
+const BLOCKED: &str = "bl";
+
+/// Plain-text conventions for scripts. With `text_contract`, every
+/// plain-text body ends with exactly one newline and every failure answers
+/// one token: `nx`, `xx`, `bl` for hosts outside the allowlist, or
+/// `err:<code>`, the code being the former body or else the status.
+/// `strict=1` empties the body of any failure, contract or not.
+pub struct Contract;
+
+#[tide::utils::async_trait]
+impl Middleware<State> for Contract {
+    async fn handle(&self, req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        let strict = req
+            .url()
+            .query_pairs()
+            .any(|(key, value)| key == "strict" && value == "1");
+        let enabled = req.state().opts.text_contract;
+        let mut res = next.run(req).await;
+        let failed = !res.status().is_success();
+        if strict && failed {
+            res.take_body();
+            return Ok(res);
+        }
+        // Streamed bodies, like zone transfers, are left alone.
+        if !enabled || res.len().is_none() {
+            return Ok(res);
+        }
+        let is_text = match res.content_type() {
+            Some(mime) => mime.essence() == mime::PLAIN.essence(),
+            None => failed,
+        };
+        if !is_text {
+            return Ok(res);
+        }
+        let body = res.take_body().into_string().await?;
+        let mut body = if failed {
+            token(res.status() as u16, body.trim_end())
+        } else {
+            body.trim_end_matches('\n').to_string()
+        };
+        body.push('\n');
+        res.set_body(body);
+        res.set_content_type(mime::PLAIN);
+        Ok(res)
+    }
+}
+
+/// One-token body of a failure answered `body`.
+fn token(status: u16, body: &str) -> String {
+    match body {
+        NOT_FOUND | EXISTS => body.into(),
+        HOST_NOT_ALLOWED => BLOCKED.into(),
+        "" => format!("err:{}", status),
+        code if code.contains(char::is_whitespace) => format!("err:{}", status),
+        code => format!("err:{}", code),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use tide::http::{Method, Request, Response, Url};
+    use trust_dns_resolver::proto::op::ResponseCode;
+
+    use super::token;
+    use crate::opts::Opts;
+    use crate::tests::state;
+    use crate::upstream::mock::Mock;
+    use crate::{server, State};
+
+    fn contract_state(text_contract: bool) -> State {
+        let mut state = state(
+            Mock::default()
+                .ips("two.example", &["192.0.2.1", "192.0.2.2"])
+                .rcode("fail.example", ResponseCode::ServFail),
+        );
+        state.opts = Arc::new(Opts {
+            text_contract,
+            allowlist: Some(vec!["example".into()]),
+            ..Opts::default()
+        });
+        state
+    }
+
+    /// Status and exact body.
+    async fn get(state: &State, path: &str) -> (u16, String) {
+        let url = Url::parse("http://localhost").unwrap().join(path).unwrap();
+        let mut res: Response = server(state.clone())
+            .respond(Request::new(Method::Get, url))
+            .await
+            .unwrap();
+        (res.status() as u16, res.body_string().await.unwrap())
+    }
+
+    #[async_std::test]
+    async fn text_v1() {
+        let state = contract_state(true);
+        for (path, status, expected) in [
+            ("/r/two.example?r=0", 200, "192.0.2.1\n192.0.2.2\n"),
+            (
+                "/r/two.example?r=0&trailing_newline=1",
+                200,
+                "192.0.2.1\n192.0.2.2\n",
+            ),
+            ("/r/two.example?r=0&n=1", 200, "192.0.2.1\n"),
+            ("/x/two.example", 200, "xx\n"),
+            ("/ping", 200, "OK\n"),
+            ("/r/none.example", 404, "nx\n"),
+            ("/x/none.example", 404, "nx\n"),
+            ("/r/fail.example", 502, "err:servfail\n"),
+            ("/r/two.example.net", 403, "bl\n"),
+            ("/r/-bad.example", 400, "err:400\n"),
+            ("/r/two.example?t=BOGUS", 400, "err:400\n"),
+            ("/nope", 404, "err:404\n"),
+            ("/r/none.example?strict=1", 404, ""),
+            ("/r/fail.example?strict=1", 502, ""),
+            ("/r/two.example?r=0&n=1&strict=1", 200, "192.0.2.1\n"),
+        ] {
+            assert_eq!(
+                get(&state, path).await,
+                (status, expected.to_string()),
+                "{}",
+                path
+            );
+        }
+        // JSON keeps its own shape.
+        let (status, json) = get(&state, "/r/two.example?format=json&n=1").await;
+        assert_eq!(status, 200);
+        assert!(json.ends_with('}'));
+        let (_, version) = get(&state, "/version").await;
+        assert!(version.contains(r#""api_contract":"text/v1""#));
+    }
+
+    #[async_std::test]
+    async fn legacy() {
+        let state = contract_state(false);
+        for (path, status, expected) in [
+            ("/r/two.example?r=0", 200, "192.0.2.1\n192.0.2.2"),
+            ("/r/fail.example", 502, "servfail"),
+            ("/r/two.example.net", 403, "host_not_allowed"),
+            ("/r/fail.example?strict=1", 502, ""),
+        ] {
+            assert_eq!(get(&state, path).await, (status, expected.to_string()));
+        }
+        let (_, version) = get(&state, "/version").await;
+        assert!(version.contains(r#""api_contract":null"#));
+    }
+
+    #[test]
+    fn tokens() {
+        assert_eq!(token(404, "nx"), "nx");
+        assert_eq!(token(403, "host_not_allowed"), "bl");
+        assert_eq!(token(504, "deadline_exceeded"), "err:deadline_exceeded");
+        assert_eq!(token(500, ""), "err:500");
+        assert_eq!(token(500, "Internal Server Error"), "err:500");
+    }
+}
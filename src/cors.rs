@@ -0,0 +1,135 @@
+use tide::http::Method;
+use tide::{Middleware, Next, Request, Response, StatusCode};
+
+pub const X_ANSWER_COUNT: &str = "X-Answer-Count";
+pub const X_FAMILY: &str = "X-Family";
+pub const X_ANY_MINIMAL: &str = "X-Any-Minimal";
+pub const X_RCODE: &str = "X-Rcode";
+pub const X_TRUNCATED: &str = "X-Truncated";
+pub const X_PARAM_ADJUSTED: &str = "X-Param-Adjusted";
+pub const X_CACHE: &str = "X-Cache";
+pub const LINK: &str = "Link";
+
+/// Response headers scripts on other origins may read.
+const EXPOSE_HEADERS: &[&str] = &[
+    X_ANSWER_COUNT,
+    X_FAMILY,
+    X_ANY_MINIMAL,
+    X_RCODE,
+    X_TRUNCATED,
+    X_PARAM_ADJUSTED,
+    X_CACHE,
+    LINK,
+];
+
+/// Allows any origin and answers preflight requests, including Private
+/// Network Access ones when `allow_private_network` is set.
+pub struct Cors {
+    pub allow_private_network: bool,
+}
+
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for Cors {
+    async fn handle(&self, req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        let mut res = if req.method() == Method::Options
+            && req.header("Access-Control-Request-Method").is_some()
+        {
+            self.preflight(&req)
+        } else {
+            let mut res = next.run(req).await;
+            res.insert_header("Access-Control-Expose-Headers", EXPOSE_HEADERS.join(", "));
+            res
+        };
+        res.append_header("Access-Control-Allow-Origin", "*");
+        Ok(res)
+    }
+}
+
+impl Cors {
+    fn preflight<State>(&self, req: &Request<State>) -> Response {
+        let mut res = Response::new(StatusCode::NoContent);
+        res.insert_header("Access-Control-Allow-Methods", "GET");
+        if let Some(headers) = req.header("Access-Control-Request-Headers") {
+            res.insert_header("Access-Control-Allow-Headers", headers.as_str());
+        }
+        let private_network = req
+            .header("Access-Control-Request-Private-Network")
+            .is_some_and(|v| v.as_str() == "true");
+        if private_network && self.allow_private_network {
+            res.insert_header("Access-Control-Allow-Private-Network", "true");
+        }
+        res
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use tide::http::{Method, Request, Response, Url};
+
+    use crate::opts::Opts;
+    use crate::server;
+    use crate::tests::state;
+    use crate::upstream::mock::Mock;
+
+    async fn preflight(allow_private_network: bool) -> Response {
+        let mut state = state(Mock::default());
+        state.opts = Arc::new(Opts {
+            allow_private_network,
+            ..Default::default()
+        });
+        let url = Url::parse("http://localhost/r/one.example").unwrap();
+        let mut req = Request::new(Method::Options, url);
+        req.insert_header("Origin", "https://app.example");
+        req.insert_header("Access-Control-Request-Method", "GET");
+        req.insert_header("Access-Control-Request-Private-Network", "true");
+        server(state).respond(req).await.unwrap()
+    }
+
+    #[async_std::test]
+    async fn expose_headers() {
+        let state = state(Mock::default().ips("one.example", &["192.0.2.1"]));
+        let url = Url::parse("http://localhost/r/one.example").unwrap();
+        let res: Response = server(state)
+            .respond(Request::new(Method::Get, url))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), 200);
+        assert_eq!(res["Access-Control-Allow-Origin"], "*");
+        assert_eq!(
+            res["Access-Control-Expose-Headers"],
+            "X-Answer-Count, X-Family, X-Any-Minimal, X-Rcode, X-Truncated, X-Param-Adjusted, X-Cache, Link"
+        );
+        assert_eq!(res["X-Answer-Count"], "1");
+    }
+
+    #[async_std::test]
+    async fn disabled() {
+        let mut state = state(Mock::default().ips("one.example", &["192.0.2.1"]));
+        state.opts = Arc::new(Opts {
+            cors_enabled: false,
+            ..Default::default()
+        });
+        let url = Url::parse("http://localhost/r/one.example").unwrap();
+        let res: Response = server(state)
+            .respond(Request::new(Method::Get, url))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), 200);
+        assert!(res.header("Access-Control-Allow-Origin").is_none());
+        assert!(res.header("Access-Control-Expose-Headers").is_none());
+    }
+
+    #[async_std::test]
+    async fn private_network() {
+        let res = preflight(true).await;
+        assert_eq!(res.status(), 204);
+        assert_eq!(res["Access-Control-Allow-Origin"], "*");
+        assert_eq!(res["Access-Control-Allow-Methods"], "GET");
+        assert_eq!(res["Access-Control-Allow-Private-Network"], "true");
+        let res = preflight(false).await;
+        assert_eq!(res.status(), 204);
+        assert!(res.header("Access-Control-Allow-Private-Network").is_none());
+    }
+}
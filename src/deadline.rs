@@ -0,0 +1,208 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use tide::{Middleware, Next, Request, Response, StatusCode};
+
+use crate::State;
+
+pub const HANDLER_TIMEOUT: &str = "handler_timeout";
+pub const DEADLINE_EXCEEDED: &str = "deadline_exceeded";
+
+/// Taken off the client budget, for the answer to reach it in time.
+const DEADLINE_MARGIN: Duration = Duration::from_millis(5);
+
+/// Cancels a handler still running after `after` and answers 503
+/// `handler_timeout`. Not for streaming routes, which have idle timeouts.
+///
+/// With `deadline_header`, a handler also gets no more than the time the
+/// client has left and answers 504 `deadline_exceeded` past it, at once if
+/// none is left. The upstream lookups are cancelled with the handler.
+pub struct Deadline {
+    pub route: &'static str,
+    pub after: Option<Duration>,
+}
+
+#[tide::utils::async_trait]
+impl Middleware<State> for Deadline {
+    async fn handle(&self, req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        let metrics = req.state().metrics.clone();
+        let client = match &req.state().opts.deadline_header {
+            Some(name) => req
+                .header(name.as_str())
+                .and_then(|value| remaining(value.as_str(), SystemTime::now())),
+            None => None,
+        };
+        let budget = client.map(|left| left.saturating_sub(DEADLINE_MARGIN));
+        if let Some(budget) = budget {
+            tide::log::info!("client deadline", {
+                route: self.route,
+                budget_ms: budget.as_millis() as u64,
+            });
+            if budget.is_zero() {
+                return Ok(exceeded(budget));
+            }
+        }
+        let limit = match (self.after, budget) {
+            (Some(after), Some(budget)) if budget < after => Some((budget, true)),
+            (None, Some(budget)) => Some((budget, true)),
+            (after, _) => after.map(|after| (after, false)),
+        };
+        let (after, client) = match limit {
+            Some(limit) => limit,
+            None => return Ok(next.run(req).await),
+        };
+        let mut res = match async_std::future::timeout(after, next.run(req)).await {
+            Ok(res) => res,
+            Err(_) if client => exceeded(after),
+            Err(_) => {
+                metrics.handler_timeout(self.route);
+                let mut res = Response::new(StatusCode::ServiceUnavailable);
+                res.set_body(HANDLER_TIMEOUT);
+                res
+            }
+        };
+        if let Some(budget) = budget {
+            res.insert_header("Server-Timing", server_timing(budget));
+        }
+        Ok(res)
+    }
+}
+
+fn exceeded(budget: Duration) -> Response {
+    let mut res = Response::new(StatusCode::GatewayTimeout);
+    res.set_body(DEADLINE_EXCEEDED);
+    res.insert_header("Server-Timing", server_timing(budget));
+    res
+}
+
+/// The budget the request was given, in milliseconds.
+fn server_timing(budget: Duration) -> String {
+    format!("deadline;dur={:.1}", budget.as_secs_f64() * 1000.0)
+}
+
+/// Time left until a deadline header, either a `grpc-timeout` style
+/// duration such as `250m` (`H`, `M`, `S`, `m`, `u` or `n`) or a Unix
+/// time in milliseconds. `None` if it is neither.
+fn remaining(value: &str, now: SystemTime) -> Option<Duration> {
+    let value = value.trim();
+    if !value.is_ascii() {
+        return None;
+    }
+    let (amount, unit) = value.split_at(value.len().checked_sub(1)?);
+    let unit_nanos: Option<u64> = match unit {
+        "H" => Some(3_600_000_000_000),
+        "M" => Some(60_000_000_000),
+        "S" => Some(1_000_000_000),
+        "m" => Some(1_000_000),
+        "u" => Some(1_000),
+        "n" => Some(1),
+        _ => None,
+    };
+    match unit_nanos {
+        Some(nanos) => {
+            let amount: u64 = amount.parse().ok()?;
+            Some(Duration::from_nanos(amount.saturating_mul(nanos)))
+        }
+        None => {
+            let at = UNIX_EPOCH + Duration::from_millis(value.parse().ok()?);
+            Some(at.duration_since(now).unwrap_or_default())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    use tide::http::{Method, Request, Response, Url};
+
+    use super::remaining;
+    use crate::opts::Opts;
+    use crate::tests::state;
+    use crate::upstream::mock::Mock;
+    use crate::{server, State};
+
+    async fn get(state: &State, deadline: Option<&str>) -> Response {
+        let url = Url::parse("http://localhost/r/a.example").unwrap();
+        let mut req = Request::new(Method::Get, url);
+        if let Some(deadline) = deadline {
+            req.insert_header("X-Request-Deadline", deadline);
+        }
+        server(state.clone()).respond(req).await.unwrap()
+    }
+
+    #[async_std::test]
+    async fn timeout() {
+        let mut state = state(Mock::default().hang());
+        state.opts = Arc::new(Opts {
+            route_timeout: Some(Duration::from_millis(20)),
+            ..Opts::default()
+        });
+        let mut res = get(&state, None).await;
+        assert_eq!(res.status(), 503);
+        assert_eq!(res.body_string().await.unwrap(), "handler_timeout");
+        assert!(state
+            .metrics
+            .render()
+            .contains("bdns_handler_timeouts_total{route=\"/r\"} 1\n"));
+    }
+
+    #[async_std::test]
+    async fn client_deadline() {
+        let mut answering = state(Mock::default().ips("a.example", &["192.0.2.1"]));
+        let mut hanging = state(Mock::default().hang());
+        // Ignored unless configured.
+        assert_eq!(get(&answering, Some("0m")).await.status(), 200);
+        let opts = Arc::new(Opts {
+            deadline_header: Some("X-Request-Deadline".into()),
+            ..Opts::default()
+        });
+        answering.opts = opts.clone();
+        hanging.opts = opts;
+
+        let mut res = get(&hanging, Some("3m")).await;
+        assert_eq!(res.status(), 504);
+        assert_eq!(res["Server-Timing"], "deadline;dur=0.0");
+        assert_eq!(res.body_string().await.unwrap(), "deadline_exceeded");
+        let mut res = get(&hanging, Some("35m")).await;
+        assert_eq!(res.status(), 504);
+        assert_eq!(res["Server-Timing"], "deadline;dur=30.0");
+        assert_eq!(res.body_string().await.unwrap(), "deadline_exceeded");
+
+        let res = get(&answering, Some("2S")).await;
+        assert_eq!(res.status(), 200);
+        assert_eq!(res["Server-Timing"], "deadline;dur=1995.0");
+        let res = get(&answering, Some("soon")).await;
+        assert_eq!(res.status(), 200);
+        assert!(res.header("Server-Timing").is_none());
+
+        // The route timeout still applies when it is the shorter one.
+        hanging.opts = Arc::new(Opts {
+            deadline_header: Some("X-Request-Deadline".into()),
+            route_timeout: Some(Duration::from_millis(20)),
+            ..Opts::default()
+        });
+        let mut res = get(&hanging, Some("1S")).await;
+        assert_eq!(res.status(), 503);
+        assert_eq!(res["Server-Timing"], "deadline;dur=995.0");
+        assert_eq!(res.body_string().await.unwrap(), "handler_timeout");
+    }
+
+    #[test]
+    fn parse() {
+        let now = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        assert_eq!(remaining("250m", now), Some(Duration::from_millis(250)));
+        assert_eq!(remaining("2S", now), Some(Duration::from_secs(2)));
+        assert_eq!(remaining("1H", now), Some(Duration::from_secs(3600)));
+        assert_eq!(remaining("500u", now), Some(Duration::from_micros(500)));
+        assert_eq!(
+            remaining("1700000000750", now),
+            Some(Duration::from_millis(750))
+        );
+        assert_eq!(remaining("1699999999000", now), Some(Duration::ZERO));
+        for value in ["", "m", "-5m", "1.5S", "soon", "5ms", "5µ"] {
+            assert_eq!(remaining(value, now), None, "{}", value);
+        }
+        assert!(remaining("99999999S", SystemTime::now()).is_some());
+    }
+}
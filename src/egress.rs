@@ -0,0 +1,280 @@
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::AtomicU64;
+use std::sync::{Mutex, OnceLock};
+use std::task::{ready, Context, Poll};
+use std::time::{Duration, Instant};
+
+use trust_dns_resolver::error::{ResolveError, ResolveErrorKind};
+use trust_dns_resolver::proto::error::ProtoErrorKind;
+
+use crate::metrics::{Metrics, Rate};
+
+/// Failure of a query over the `UPSTREAM_QPS` budget.
+pub const UPSTREAM_THROTTLED: &str = "upstream_throttled";
+
+/// Longest a query is held for the budget with `UPSTREAM_QPS_WAIT`.
+pub const MAX_WAIT: Duration = Duration::from_secs(1);
+
+/// Queries sent to the upstreams and masters. Sockets come from the
+/// runtime provider, which has no state of its own to count them in.
+pub static QUERIES: AtomicU64 = AtomicU64::new(0);
+
+/// Queries refused for the `UPSTREAM_QPS` budget.
+pub static THROTTLED: AtomicU64 = AtomicU64::new(0);
+
+/// `QUERIES` by the second, for the current rate.
+pub static RATE: Mutex<Rate> = Mutex::new(Rate::new());
+
+/// Budget of the queries sent, any number when unset.
+static THROTTLE: OnceLock<Throttle> = OnceLock::new();
+
+/// Token bucket of `qps` queries a second, holding a second of them.
+pub struct Throttle {
+    qps: f64,
+    /// Queue for the budget rather than fail at once.
+    wait: bool,
+    bucket: Mutex<Bucket>,
+}
+
+struct Bucket {
+    /// Queries that may go out now, below zero those already queued.
+    tokens: f64,
+    at: Instant,
+}
+
+impl Throttle {
+    pub fn new(qps: u32, wait: bool) -> Self {
+        let qps = f64::from(qps.max(1));
+        Self {
+            qps,
+            wait,
+            bucket: Mutex::new(Bucket {
+                tokens: qps,
+                at: Instant::now(),
+            }),
+        }
+    }
+
+    /// Takes a query out of the budget, answering how long it must wait
+    /// to go out. `None` when it cannot within `MAX_WAIT`, or at all
+    /// without `wait`.
+    fn reserve(&self, now: Instant) -> Option<Duration> {
+        let mut bucket = self.bucket.lock().unwrap();
+        let refill = now.saturating_duration_since(bucket.at).as_secs_f64() * self.qps;
+        bucket.tokens = (bucket.tokens + refill).min(self.qps);
+        bucket.at = bucket.at.max(now);
+        let queued = match self.wait {
+            true => self.qps * MAX_WAIT.as_secs_f64(),
+            false => 0.0,
+        };
+        if bucket.tokens - 1.0 < -queued {
+            return None;
+        }
+        bucket.tokens -= 1.0;
+        Some(Duration::from_secs_f64(
+            (-bucket.tokens).max(0.0) / self.qps,
+        ))
+    }
+}
+
+/// Sets the budget once at startup.
+pub fn set(throttle: Throttle) -> io::Result<()> {
+    THROTTLE
+        .set(throttle)
+        .map_err(|_| io::Error::other("upstream query budget already set"))
+}
+
+/// Error of a query refused for the budget. Resolver errors keep only the
+/// kind of I/O errors when cloned, so the kind tells it apart.
+pub fn refused() -> io::Error {
+    io::Error::new(io::ErrorKind::QuotaExceeded, UPSTREAM_THROTTLED)
+}
+
+/// Whether `err` is a query refused for the budget.
+pub fn throttled(err: &ResolveError) -> bool {
+    let io = match err.kind() {
+        ResolveErrorKind::Io(io) => io,
+        ResolveErrorKind::Proto(proto) => match proto.kind() {
+            ProtoErrorKind::Io(io) => io,
+            _ => return false,
+        },
+        _ => return false,
+    };
+    io.kind() == io::ErrorKind::QuotaExceeded
+}
+
+/// Waits for a query to be allowed out and counts it, failing with
+/// `refused` if it is not. For sockets this crate writes to itself.
+pub async fn admit() -> io::Result<()> {
+    let mut gate = Gate::default();
+    std::future::poll_fn(|cx| gate.poll_budget(cx)).await
+}
+
+/// Admission of the queries a socket sends, one at a time, for sockets
+/// polled by the resolver.
+#[derive(Default)]
+pub struct Gate {
+    wait: Option<Pin<Box<dyn Future<Output = ()> + Send>>>,
+    admitted: bool,
+}
+
+impl Gate {
+    /// Polls for the next query to be allowed out, counting it once it is.
+    /// Admitted, it stays so until `sent`.
+    pub fn poll_admit(
+        &mut self,
+        throttle: Option<&Throttle>,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        if self.admitted {
+            return Poll::Ready(Ok(()));
+        }
+        if let Some(wait) = &mut self.wait {
+            ready!(wait.as_mut().poll(cx));
+            self.wait = None;
+        } else if let Some(throttle) = throttle {
+            match throttle.reserve(Instant::now()) {
+                Some(wait) if wait.is_zero() => {}
+                Some(wait) => {
+                    self.wait = Some(Box::pin(async_std::task::sleep(wait)));
+                    return self.poll_admit(Some(throttle), cx);
+                }
+                None => {
+                    Metrics::inc(&THROTTLED);
+                    return Poll::Ready(Err(refused()));
+                }
+            }
+        }
+        Metrics::inc(&QUERIES);
+        RATE.lock().unwrap().add(Instant::now());
+        self.admitted = true;
+        Poll::Ready(Ok(()))
+    }
+
+    /// Marks the admitted query as sent, the next one is admitted anew.
+    pub fn sent(&mut self) {
+        self.admitted = false;
+    }
+
+    /// Drops the admission of a query that failed to go out, so a retry
+    /// is admitted and counted again.
+    pub fn failed(&mut self) {
+        self.admitted = false;
+    }
+
+    /// `poll_admit` against the budget set at startup.
+    pub fn poll_budget(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.poll_admit(THROTTLE.get(), cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::Ordering;
+    use std::task::{Context, Poll};
+    use std::time::Duration;
+
+    use tide::http::{Method, Request, Response, Url};
+    use trust_dns_resolver::error::ResolveError;
+    use trust_dns_resolver::proto::error::ProtoError;
+
+    use super::{refused, throttled, Gate, Throttle, QUERIES, THROTTLED, UPSTREAM_THROTTLED};
+    use crate::server;
+    use crate::tests::state;
+    use crate::upstream::mock::Mock;
+
+    /// Admits `queries` one after the other, answering how many were
+    /// refused.
+    async fn admit(throttle: &Throttle, queries: usize) -> usize {
+        let mut refused = 0;
+        for _ in 0..queries {
+            let mut gate = Gate::default();
+            match std::future::poll_fn(|cx| gate.poll_admit(Some(throttle), cx)).await {
+                Ok(()) => gate.sent(),
+                Err(err) => {
+                    assert!(throttled(&ProtoError::from(err).into()));
+                    refused += 1;
+                }
+            }
+        }
+        refused
+    }
+
+    #[test]
+    fn bucket() {
+        let throttle = Throttle::new(10, false);
+        let start = throttle.bucket.lock().unwrap().at;
+        for _ in 0..10 {
+            assert_eq!(throttle.reserve(start), Some(Duration::ZERO));
+        }
+        assert_eq!(throttle.reserve(start), None);
+        let later = start + Duration::from_millis(250);
+        assert_eq!(throttle.reserve(later), Some(Duration::ZERO));
+        assert_eq!(throttle.reserve(later), Some(Duration::ZERO));
+        assert_eq!(throttle.reserve(later), None);
+
+        let throttle = Throttle::new(10, true);
+        for _ in 0..10 {
+            throttle.reserve(start).unwrap();
+        }
+        let waits = (0..10).map(|_| throttle.reserve(start).unwrap());
+        assert_eq!(waits.last(), Some(Duration::from_secs(1)));
+        assert_eq!(throttle.reserve(start), None);
+    }
+
+    #[test]
+    fn failed() {
+        let throttle = Throttle::new(1, false);
+        let mut gate = Gate::default();
+        let poll = |gate: &mut Gate| {
+            let waker = futures_util::task::noop_waker();
+            gate.poll_admit(Some(&throttle), &mut Context::from_waker(&waker))
+        };
+        assert!(matches!(poll(&mut gate), Poll::Ready(Ok(()))));
+        // Still admitted while the send is pending.
+        assert!(matches!(poll(&mut gate), Poll::Ready(Ok(()))));
+        gate.failed();
+        // The retry needs a budget of its own, which is spent.
+        assert!(matches!(poll(&mut gate), Poll::Ready(Err(_))));
+    }
+
+    #[async_std::test]
+    async fn burst() {
+        let (queries, throttled) = (
+            QUERIES.load(Ordering::Relaxed),
+            THROTTLED.load(Ordering::Relaxed),
+        );
+        assert_eq!(admit(&Throttle::new(5, false), 20).await, 15);
+        // Other tests send queries too.
+        assert!(QUERIES.load(Ordering::Relaxed) >= queries + 5);
+        assert!(THROTTLED.load(Ordering::Relaxed) >= throttled + 15);
+
+        // Queued, a burst goes out at the budget rate and none fail.
+        assert_eq!(admit(&Throttle::new(20, true), 30).await, 0);
+    }
+
+    #[test]
+    fn refusals() {
+        let err = ResolveError::from(ProtoError::from(refused()));
+        assert!(throttled(&err));
+        // Resolver errors are cloned on their way out of the cache.
+        assert!(throttled(&err.clone()));
+        assert!(!throttled(&ResolveError::from(std::io::Error::other(
+            UPSTREAM_THROTTLED
+        ))));
+    }
+
+    #[async_std::test]
+    async fn unavailable() {
+        let mock = Mock::default()
+            .ips("one.example", &["192.0.2.1"])
+            .throttled();
+        let app = server(state(mock));
+        let url = Url::parse("http://localhost/r/one.example").unwrap();
+        let mut res: Response = app.respond(Request::new(Method::Get, url)).await.unwrap();
+        assert_eq!(res.status(), 503);
+        assert_eq!(res.body_string().await.unwrap(), UPSTREAM_THROTTLED);
+    }
+}
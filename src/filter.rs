@@ -0,0 +1,283 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::sync::Arc;
+
+use regex::{Regex, RegexBuilder};
+use trust_dns_resolver::proto::rr::{Name, RData, Record};
+
+/// An answer record of a lookup, without the CNAME records leading to it.
+pub type ResolvedAnswer = Record;
+
+/// Rewrites the answers of every lookup, after resolution and before they
+/// are ordered, sampled or limited. Filters registered on `State` run in
+/// registration order, each one getting what the previous one returned,
+/// so a rewrite registered before `StripPrivate` can be stripped by it and
+/// not when registered after.
+pub trait AnswerFilter: Send + Sync {
+    fn filter(&self, host: &str, answers: Vec<ResolvedAnswer>) -> Vec<ResolvedAnswer>;
+}
+
+/// Built-in filter listed as `name` in `ANSWER_FILTERS`.
+pub fn builtin(name: &str) -> Option<Arc<dyn AnswerFilter>> {
+    match name {
+        "strip_private" => Some(Arc::new(StripPrivate)),
+        "dedup" => Some(Arc::new(Dedup)),
+        _ => None,
+    }
+}
+
+/// Drops addresses that are not reachable from the internet: private,
+/// shared (CGNAT), loopback, link-local, unique local and unspecified.
+pub struct StripPrivate;
+
+impl AnswerFilter for StripPrivate {
+    fn filter(&self, _host: &str, mut answers: Vec<ResolvedAnswer>) -> Vec<ResolvedAnswer> {
+        answers.retain(|answer| !answer.rdata().to_ip_addr().is_some_and(is_private));
+        answers
+    }
+}
+
+fn is_private(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_private_v4(ip),
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_private_v4(ip),
+            None => is_private_v6(ip),
+        },
+    }
+}
+
+fn is_private_v4(ip: Ipv4Addr) -> bool {
+    let [a, b, ..] = ip.octets();
+    ip.is_private()
+        || ip.is_loopback()
+        || ip.is_link_local()
+        || ip.is_unspecified()
+        // 100.64.0.0/10
+        || a == 100 && b & 0xc0 == 64
+}
+
+fn is_private_v6(ip: Ipv6Addr) -> bool {
+    let first = ip.segments()[0];
+    ip.is_loopback()
+        || ip.is_unspecified()
+        // fc00::/7
+        || first & 0xfe00 == 0xfc00
+        || is_link_local_v6(ip)
+}
+
+/// Drops IPv6 link-local addresses, which mean nothing without the
+/// interface of the zone they came from. The `LINK_LOCAL=drop` default.
+pub struct StripLinkLocal;
+
+impl AnswerFilter for StripLinkLocal {
+    fn filter(&self, _host: &str, mut answers: Vec<ResolvedAnswer>) -> Vec<ResolvedAnswer> {
+        answers.retain(|answer| !is_link_local(answer));
+        answers
+    }
+}
+
+/// An AAAA record in fe80::/10.
+pub fn is_link_local(answer: &ResolvedAnswer) -> bool {
+    match answer.rdata().to_ip_addr() {
+        Some(IpAddr::V6(ip)) => is_link_local_v6(ip),
+        _ => false,
+    }
+}
+
+fn is_link_local_v6(ip: Ipv6Addr) -> bool {
+    ip.segments()[0] & 0xffc0 == 0xfe80
+}
+
+/// Longest `filter` pattern accepted.
+const MAX_PATTERN_LEN: usize = 256;
+
+/// Bytes a compiled `filter` may take. The regex engine runs in linear
+/// time, this bounds what a pattern such as `(a{100}){100}` makes it build.
+const PATTERN_SIZE_LIMIT: usize = 1 << 16;
+
+/// Keeps the records whose target name, of a PTR, CNAME or NS record,
+/// matches the `filter` of a request. Names are matched without their
+/// trailing dot and ignoring case, other records are dropped.
+pub struct NameMatch(Regex);
+
+impl NameMatch {
+    /// `None` when `pattern` is too long, invalid or too large compiled.
+    pub fn new(pattern: &str) -> Option<Self> {
+        if pattern.len() > MAX_PATTERN_LEN {
+            return None;
+        }
+        RegexBuilder::new(pattern)
+            .case_insensitive(true)
+            .size_limit(PATTERN_SIZE_LIMIT)
+            .dfa_size_limit(PATTERN_SIZE_LIMIT)
+            .build()
+            .ok()
+            .map(Self)
+    }
+
+    pub fn is_match(&self, name: &Name) -> bool {
+        self.0.is_match(name.to_string().trim_end_matches('.'))
+    }
+}
+
+impl AnswerFilter for NameMatch {
+    fn filter(&self, _host: &str, mut answers: Vec<ResolvedAnswer>) -> Vec<ResolvedAnswer> {
+        answers.retain(|answer| match answer.rdata() {
+            RData::PTR(name) | RData::CNAME(name) | RData::NS(name) => self.is_match(name),
+            _ => false,
+        });
+        answers
+    }
+}
+
+/// Drops repeated records, as when a name has the same address twice
+/// through different paths, keeping the first of each type and data.
+pub struct Dedup;
+
+impl AnswerFilter for Dedup {
+    fn filter(&self, _host: &str, answers: Vec<ResolvedAnswer>) -> Vec<ResolvedAnswer> {
+        let mut kept: Vec<ResolvedAnswer> = Vec::with_capacity(answers.len());
+        for answer in answers {
+            let seen = kept.iter().any(|other| {
+                other.rr_type() == answer.rr_type() && other.rdata() == answer.rdata()
+            });
+            if !seen {
+                kept.push(answer);
+            }
+        }
+        kept
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+    use std::sync::Arc;
+
+    use tide::http::{Method, Request, Response, Url};
+    use trust_dns_resolver::proto::rr::{Name, RData, Record};
+
+    use super::{
+        builtin, is_private, AnswerFilter, Dedup, NameMatch, ResolvedAnswer, StripPrivate,
+    };
+    use crate::server;
+    use crate::tests::state;
+    use crate::upstream::mock::Mock;
+
+    /// Maps one address to another, like a site mirror would.
+    struct Mirror(IpAddr, IpAddr);
+
+    impl AnswerFilter for Mirror {
+        fn filter(&self, _host: &str, answers: Vec<ResolvedAnswer>) -> Vec<ResolvedAnswer> {
+            answers
+                .into_iter()
+                .map(|answer| match answer.rdata().to_ip_addr() {
+                    Some(ip) if ip == self.0 => {
+                        let rdata = match self.1 {
+                            IpAddr::V4(ip) => RData::A(ip),
+                            IpAddr::V6(ip) => RData::AAAA(ip),
+                        };
+                        Record::from_rdata(answer.name().clone(), answer.ttl(), rdata)
+                    }
+                    _ => answer,
+                })
+                .collect()
+        }
+    }
+
+    #[test]
+    fn private() {
+        for ip in [
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "100.64.0.1",
+            "127.0.0.1",
+            "169.254.1.1",
+            "0.0.0.0",
+            "::1",
+            "fd00::1",
+            "fe80::1",
+            "::ffff:10.0.0.1",
+        ] {
+            assert!(is_private(ip.parse().unwrap()), "{}", ip);
+        }
+        for ip in ["192.0.2.1", "100.128.0.1", "2001:db8::1", "8.8.8.8"] {
+            assert!(!is_private(ip.parse().unwrap()), "{}", ip);
+        }
+    }
+
+    #[test]
+    fn dedup() {
+        let name: Name = "one.example.".parse().unwrap();
+        let a =
+            |ip: &str, ttl| Record::from_rdata(name.clone(), ttl, RData::A(ip.parse().unwrap()));
+        let answers = vec![a("192.0.2.1", 60), a("192.0.2.2", 60), a("192.0.2.1", 30)];
+        let kept = Dedup.filter("one.example", answers);
+        assert_eq!(kept.len(), 2);
+        assert_eq!(kept[0].ttl(), 60);
+        assert!(builtin("dedup").is_some());
+        assert!(builtin("nope").is_none());
+    }
+
+    #[test]
+    fn name_match() {
+        let ptr = |name: &str| {
+            Record::from_rdata(
+                "1.2.0.192.in-addr.arpa.".parse().unwrap(),
+                300,
+                RData::PTR(name.parse().unwrap()),
+            )
+        };
+        let answers = vec![
+            ptr("host.Example.com."),
+            ptr("host.example.com.evil."),
+            ptr("other.example.net."),
+            Record::from_rdata(Name::root(), 300, RData::A("192.0.2.1".parse().unwrap())),
+        ];
+        let filter = NameMatch::new(r"\.example\.com$").unwrap();
+        let kept = filter.filter("1.2.0.192.in-addr.arpa", answers.clone());
+        assert_eq!(kept, vec![answers[0].clone()]);
+        assert!(NameMatch::new("nothing")
+            .unwrap()
+            .filter("", answers)
+            .is_empty());
+
+        assert!(NameMatch::new("(").is_none());
+        assert!(NameMatch::new(&"a".repeat(257)).is_none());
+        assert!(NameMatch::new("(a{100}){100}").is_none());
+    }
+
+    async fn body(filters: Vec<Arc<dyn AnswerFilter>>) -> String {
+        let ips = ["192.0.2.1", "10.0.0.1", "192.0.2.2", "192.0.2.2"];
+        let mut state = state(Mock::default().ips("one.example", &ips));
+        state.filters = Arc::new(filters);
+        let url = Url::parse("http://localhost/r/one.example?r=0").unwrap();
+        let mut res: Response = server(state)
+            .respond(Request::new(Method::Get, url))
+            .await
+            .unwrap();
+        res.body_string().await.unwrap()
+    }
+
+    #[async_std::test]
+    async fn chain() {
+        let mirror = Arc::new(Mirror(
+            "192.0.2.1".parse().unwrap(),
+            "10.0.0.2".parse().unwrap(),
+        ));
+        assert_eq!(
+            body(vec![Arc::new(StripPrivate), Arc::new(Dedup)]).await,
+            "192.0.2.1\n192.0.2.2"
+        );
+        // The mirrored address is private, so the order decides its fate.
+        assert_eq!(
+            body(vec![mirror.clone(), Arc::new(StripPrivate)]).await,
+            "192.0.2.2\n192.0.2.2"
+        );
+        assert_eq!(
+            body(vec![Arc::new(StripPrivate), mirror]).await,
+            "10.0.0.2\n192.0.2.2\n192.0.2.2"
+        );
+    }
+}
@@ -0,0 +1,3335 @@
+// trust-dns errors are large, boxing them at every call site buys nothing.
+#![allow(clippy::result_large_err)]
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashMap};
+use std::env;
+use std::hash::{Hash, Hasher};
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use data_encoding::{BASE64, HEXLOWER};
+use futures_util::future::{self, Either};
+use futures_util::stream::{self, StreamExt};
+use percent_encoding::percent_decode_str;
+
+mod attempts;
+mod axfr;
+mod bind;
+mod check;
+mod contract;
+mod cors;
+mod deadline;
+mod egress;
+mod filter;
+mod lifecycle;
+mod metrics;
+mod openapi;
+mod opts;
+mod params;
+mod persist;
+mod pretty;
+mod probe;
+mod rdata;
+mod shadow;
+mod ui;
+mod upstream;
+mod wire;
+mod ws;
+mod zones;
+
+use rand::rngs::SmallRng;
+use rand::seq::SliceRandom;
+use rand::{Rng, SeedableRng};
+use tide::http::Url;
+use tide::prelude::*;
+use tide::{Request, Response, StatusCode};
+use tide_compress::CompressMiddleware;
+use trust_dns_resolver::config::{NameServerConfigGroup, Protocol, ResolverConfig, ResolverOpts};
+use trust_dns_resolver::error::{ResolveError, ResolveErrorKind};
+use trust_dns_resolver::lookup::Lookup;
+use trust_dns_resolver::proto::op::{Query, ResponseCode};
+use trust_dns_resolver::proto::rr::{Name, RData, Record, RecordType};
+use trust_dns_resolver::TokioHandle;
+
+pub use filter::{AnswerFilter, ResolvedAnswer};
+pub use lifecycle::{run, serve, Builder};
+
+use bind::BoundResolver;
+use metrics::Metrics;
+use opts::{cache_shards, shard_cache_sizes, LinkLocal, Opts, Proto, ShuffleCache, ENV_DNS};
+use upstream::Upstream;
+
+const DEFAULT_N: u8 = 8;
+
+const NOT_FOUND: &str = "nx";
+const SERVFAIL: &str = "servfail";
+const CNAME: &str = "cname";
+const EXISTS: &str = "xx";
+const ALIAS_LOOP: &str = "alias_loop";
+const TRUNCATED_SIZE: &str = "size";
+const SCOPE_LINK_LOCAL: &str = "link-local";
+const CACHE_HIT: &str = "HIT";
+const CACHE_MISS: &str = "MISS";
+const CACHE_STALE: &str = "STALE";
+const OFFSET_RANGE: &str = "offset_range";
+
+/// Request header naming a client session, seeding its answer order.
+const X_SESSION: &str = "X-Session";
+const BAD_HOST: &str = "bad_host";
+const BAD_FILTER: &str = "bad_filter";
+const TYPE_NOT_ALLOWED: &str = "type_not_allowed";
+const HOST_NOT_ALLOWED: &str = "host_not_allowed";
+const DEBUG_DISABLED: &str = "debug_disabled";
+const UNAUTHORIZED: &str = "unauthorized";
+const PROBE_DISABLED: &str = "probe_disabled";
+const UNREACHABLE: &str = "unreachable";
+const UPSTREAM_DOWN: &str = "upstream_down";
+const DEGRADED: &str = "degraded";
+
+/// Queried one by one when an ANY query gets the RFC 8482 minimal answer.
+const ANY_FALLBACK_TYPES: &[RecordType] = &[
+    RecordType::A,
+    RecordType::AAAA,
+    RecordType::CNAME,
+    RecordType::MX,
+    RecordType::NS,
+    RecordType::SOA,
+    RecordType::TXT,
+    RecordType::CAA,
+];
+
+/// Ranges `/r` and `/rr` clamp their numeric parameters to.
+const RESOLVE_LIMITS: &[params::Limit] = &[
+    params::Limit {
+        key: "n",
+        min: 0,
+        max: u8::MAX as i128,
+    },
+    params::Limit {
+        key: "pick",
+        min: 0,
+        max: u8::MAX as i128,
+    },
+    params::Limit {
+        key: "limit_per_type",
+        min: 1,
+        max: u8::MAX as i128,
+    },
+];
+
+/// With `fast=1`, how long the other family may take to join the first.
+const FAST_GRACE: Duration = Duration::from_millis(25);
+
+/// With `EMPTY_RETRY=1`, wait before asking again after an empty answer.
+const EMPTY_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+/// Hosts accepted by a single comma-separated `/r` request.
+const MAX_BATCH_HOSTS: usize = 32;
+
+/// Longest decoded `:host`, room for a full batch of names before they
+/// are converted and validated.
+const MAX_HOST_PARAM: usize = MAX_BATCH_HOSTS * 256;
+
+#[derive(Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum Format {
+    Text,
+    Json,
+    /// `host,type,class,ttl,value` rows with a header, for any record type.
+    Csv,
+}
+
+#[derive(Deserialize)]
+#[serde(default)]
+struct ResolveQuery {
+    n: Option<u8>,
+    /// Answer order: `r=1` (default) shuffles every response, `r=0` keeps
+    /// the upstream order and `stable_shuffle=1` shuffles once per cache
+    /// entry, so the order only changes when the answer is refreshed.
+    /// `pick` samples with the same randomness.
+    r: u8,
+    stable_shuffle: u8,
+    pick: Option<u8>,
+    trailing_newline: Option<u8>,
+    t: Option<String>,
+    format: Format,
+    f: Option<u8>,
+    prefer: Option<u8>,
+    no_cname: u8,
+    unicode: u8,
+    keyed: u8,
+    #[serde(rename = "do")]
+    dnssec_ok: u8,
+    /// Only addresses accepting a TCP connection on this port.
+    reachable: Option<u16>,
+    /// Text lines start with the record type and a tab.
+    label: u8,
+    /// Skips this many answers, in upstream order, before taking `n`.
+    offset: Option<usize>,
+    /// Answers with the first address family to arrive, see `lookup_fast`.
+    fast: u8,
+    /// `wire` answers the upstream response as received, see `resolve_wire`.
+    debug: Option<String>,
+    /// Encoding of `debug=wire` answers, `hex` (default) or `base64`.
+    encoding: Option<String>,
+    /// Regex the names of PTR, CNAME or NS answers must match.
+    filter: Option<String>,
+    /// Answers kept of each record type, within `TYPE_LIMITS`.
+    limit_per_type: Option<u8>,
+}
+
+impl Default for ResolveQuery {
+    fn default() -> Self {
+        Self {
+            n: None,
+            r: 1,
+            stable_shuffle: 0,
+            pick: None,
+            trailing_newline: None,
+            t: None,
+            format: Format::Text,
+            f: None,
+            prefer: None,
+            no_cname: 0,
+            unicode: 0,
+            keyed: 0,
+            dnssec_ok: 0,
+            reachable: None,
+            label: 0,
+            offset: None,
+            fast: 0,
+            debug: None,
+            encoding: None,
+            filter: None,
+            limit_per_type: None,
+        }
+    }
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct ExistsQuery {
+    ttl: u8,
+    /// Record type whose presence is checked, A when omitted.
+    t: Option<String>,
+}
+
+/// What the routes share: the upstreams, options, metrics and answer
+/// filters. The binary builds it from the environment with
+/// `State::builder`, embedders register their own filters and hooks there
+/// first.
+#[derive(Clone)]
+pub struct State {
+    resolver: Arc<dyn Upstream>,
+    rng: Arc<Mutex<SmallRng>>,
+    metrics: Arc<Metrics>,
+    opts: Arc<Opts>,
+    /// Applied in order to the answers of every lookup.
+    filters: Arc<Vec<Arc<dyn AnswerFilter>>>,
+    hooks: Arc<lifecycle::Hooks>,
+    /// Lookups by registrable domain, with `zone_stats`.
+    zones: Option<Arc<zones::ZoneStats>>,
+    /// Answers loaded from `cache_file`, saved back on shutdown.
+    persisted: Option<Arc<persist::Persisted>>,
+}
+
+impl State {
+    pub fn builder() -> Builder {
+        Builder::default()
+    }
+}
+
+async fn resolve(req: Request<State>) -> tide::Result {
+    let host = match normalize_host(req.param("host")?) {
+        Some(host) => host,
+        None => return Ok(Response::builder(StatusCode::BadRequest).build()),
+    };
+    let opts = &req.state().opts;
+    let (host, port) = match opts.strip_port && !host.contains(',') {
+        true => match strip_port(&host) {
+            Some(stripped) => stripped,
+            None => return Ok(Response::builder(StatusCode::BadRequest).build()),
+        },
+        false => (host.as_str(), None),
+    };
+    let (host, hops) = match expand_alias(&opts.aliases, host, opts.max_resolve_depth) {
+        Some(expanded) => expanded,
+        None => {
+            return Ok(Response::builder(StatusCode::LoopDetected)
+                .body(ALIAS_LOOP)
+                .build())
+        }
+    };
+    let query: ResolveQuery = params::query(&req, RESOLVE_LIMITS)?;
+    let rtype = match query.t.as_deref().map(rdata::parse_type) {
+        Some(None) => return Ok(Response::builder(StatusCode::BadRequest).build()),
+        Some(rtype) => rtype,
+        None => None,
+    };
+    let family = query.f.map(family_type);
+    let prefer = query.prefer.map(family_type);
+    let rtype = match (rtype, family, prefer) {
+        (rtype, None, None) => rtype,
+        (None, Some(Some(rtype)), None) => Some(rtype),
+        (None, None, Some(Some(_))) => None,
+        _ => return Ok(Response::builder(StatusCode::BadRequest).build()),
+    };
+    if !type_allowed(&req.state().opts, is_admin(&req), rtype) {
+        return Ok(type_not_allowed());
+    }
+    if let Some(port) = query.reachable {
+        if !req.state().opts.enable_probe {
+            return Ok(Response::builder(StatusCode::Forbidden)
+                .body(PROBE_DISABLED)
+                .build());
+        }
+        let address = matches!(rtype, None | Some(RecordType::A) | Some(RecordType::AAAA));
+        if port == 0 || !address || query.dnssec_ok != 0 {
+            return Ok(Response::builder(StatusCode::BadRequest).build());
+        }
+    }
+    if query.fast != 0 && (rtype.is_some() || prefer.is_some() || query.dnssec_ok != 0) {
+        return Ok(Response::builder(StatusCode::BadRequest).build());
+    }
+    if host.contains(',') {
+        if prefer.is_some()
+            || query.pick.is_some()
+            || query.no_cname != 0
+            || query.dnssec_ok != 0
+            || query.reachable.is_some()
+            || query.offset.is_some()
+            || query.fast != 0
+            || query.debug.is_some()
+            || query.filter.is_some()
+            || query.limit_per_type.is_some()
+        {
+            return Ok(Response::builder(StatusCode::BadRequest).build());
+        }
+        return resolve_batch(req.state(), host, rtype, &query).await;
+    }
+    // Owner names of typed records (SMIMEA, SRV, ...) may carry `_` labels.
+    if !validate_name(host, rtype.is_some()) {
+        return Ok(Response::builder(StatusCode::BadRequest).build());
+    }
+    if query.pick.is_some() && (query.n.is_some() || query.offset.is_some()) {
+        return Ok(Response::builder(StatusCode::BadRequest).build());
+    }
+    let state = req.state();
+    let name_filter = match &query.filter {
+        Some(pattern) => {
+            let names = matches!(
+                rtype,
+                Some(RecordType::PTR | RecordType::CNAME | RecordType::NS)
+            );
+            if !names || query.debug.is_some() || query.dnssec_ok != 0 {
+                return Ok(Response::builder(StatusCode::BadRequest).build());
+            }
+            match filter::NameMatch::new(pattern) {
+                Some(name_filter) => Some(name_filter),
+                None => return Ok(bad_filter()),
+            }
+        }
+        None => None,
+    };
+    if let Some(debug) = &query.debug {
+        if debug != "wire"
+            || prefer.is_some()
+            || query.fast != 0
+            || query.reachable.is_some()
+            || query.pick.is_some()
+        {
+            return Ok(Response::builder(StatusCode::BadRequest).build());
+        }
+        return resolve_wire(&req, host, rtype.unwrap_or(RecordType::A), &query).await;
+    }
+    if query.dnssec_ok != 0 {
+        if !state.opts.enable_debug {
+            return Ok(Response::builder(StatusCode::Forbidden)
+                .body(DEBUG_DISABLED)
+                .build());
+        }
+        return resolve_dnssec(state, host, rtype).await;
+    }
+    let mut families = None;
+    let lookup = match prefer.flatten() {
+        Some(preferred) => lookup_preferred(state, host, preferred)
+            .await
+            .map(|(lookup, served)| (lookup, Some(served))),
+        None if query.fast != 0 => match lookup_fast(state, host).await {
+            Ok((lookup, served)) => {
+                families = Some(served);
+                Ok((lookup, None))
+            }
+            Err(err) => Err(err),
+        },
+        None => query_upstream(state, host, rtype)
+            .await
+            .map(|lookup| (lookup, None)),
+    };
+    let (lookup, served) = match lookup {
+        Ok(lookup) => lookup,
+        Err(err) => return lookup_error(state, host, err, query.format),
+    };
+    let cache_status = if is_stale(state, host, served.or(rtype)) {
+        CACHE_STALE
+    } else if upstream::from_cache(&lookup, Instant::now()) {
+        CACHE_HIT
+    } else {
+        CACHE_MISS
+    };
+    if hops + cname_hops(&lookup, served.or(rtype)) > state.opts.max_resolve_depth {
+        return Ok(Response::builder(StatusCode::LoopDetected)
+            .body(ALIAS_LOOP)
+            .build());
+    }
+    if query.no_cname != 0 && is_alias(&lookup, host) {
+        return Ok(Response::builder(StatusCode::Conflict).body(CNAME).build());
+    }
+    let any = rtype == Some(RecordType::ANY);
+    let mut results = answers(state, host, &lookup, served.or(rtype));
+    let minimal_any = any && is_minimal_any(&results);
+    if minimal_any {
+        results = lookup_any_fallback(state, host).await;
+    }
+    if let Some(name_filter) = &name_filter {
+        results = name_filter.filter(host, results);
+        if results.is_empty() {
+            return Ok(Response::builder(StatusCode::NotFound)
+                .body(NOT_FOUND)
+                .build());
+        }
+    }
+    if let Some(port) = query.reachable {
+        if results.is_empty() {
+            return Ok(Response::builder(StatusCode::NotFound)
+                .body(NOT_FOUND)
+                .build());
+        }
+        results = probe::reachable(state, results, port).await;
+        if results.is_empty() {
+            return Ok(Response::builder(StatusCode::NotFound)
+                .body(UNREACHABLE)
+                .build());
+        }
+    }
+    // In edge mode a session orders answers like a cache entry of its own.
+    let session = req
+        .header(X_SESSION)
+        .map(|v| v.as_str())
+        .filter(|_| state.opts.cache_headers && state.opts.shuffle_cache == ShuffleCache::Edge);
+    let type_limits = &state.opts.type_limits;
+    let (total, type_counts) = if query.stable_shuffle != 0 || session.is_some() {
+        let seed = stable_seed(host, served.or(rtype), lookup.valid_until(), session);
+        let mut rng = SmallRng::seed_from_u64(seed);
+        arrange(&mut results, &query, type_limits, &mut rng)
+    } else {
+        arrange(
+            &mut results,
+            &query,
+            type_limits,
+            &mut *lock_rng(&state.rng),
+        )
+    };
+    let offset = query.offset.unwrap_or(0);
+    if offset > 0 && offset >= total {
+        return Ok(Response::builder(StatusCode::RequestedRangeNotSatisfiable)
+            .body(OFFSET_RANGE)
+            .build());
+    }
+    if results.is_empty() {
+        let mut res = Response::builder(StatusCode::NotFound).body(NOT_FOUND);
+        if minimal_any {
+            res = res.header(cors::X_ANY_MINIMAL, "1");
+        }
+        return Ok(res.build());
+    }
+    let before = results.len();
+    truncate_to_size(&mut results, query.format, state.opts.max_response_bytes);
+    let truncated = results.len() < before;
+    let mut res: Response = if query.format == Format::Json {
+        let answers = results
+            .iter()
+            .map(|record| {
+                let mut answer = answer_json(state, record);
+                if query.unicode != 0 {
+                    rdata::add_unicode_names(&mut answer, record);
+                }
+                answer
+            })
+            .collect::<Vec<_>>();
+        let mut body = json!({ "host": host, "answers": answers });
+        if let Some(port) = port {
+            body["port"] = port.into();
+        }
+        if let Some(families) = &families {
+            let families = families.iter().map(|rtype| family_number(*rtype));
+            body["families"] = families.collect::<Vec<_>>().into();
+        }
+        if minimal_any {
+            body["minimal_any"] = true.into();
+        }
+        if truncated {
+            body["truncated_reason"] = TRUNCATED_SIZE.into();
+        }
+        if let Some(type_counts) = &type_counts {
+            body["types"] = type_counts_json(type_counts);
+        }
+        if query.unicode != 0 {
+            body.as_object_mut()
+                .unwrap()
+                .extend(rdata::unicode_names(host));
+        }
+        body.into()
+    } else if query.format == Format::Csv {
+        csv_body(&results)
+    } else {
+        let lines = results
+            .iter()
+            .map(|record| answer_line(state, record, any, query.label != 0))
+            .collect::<Vec<_>>();
+        let trailing_newline = query
+            .trailing_newline
+            .map_or(state.opts.trailing_newline, |v| v != 0);
+        text_body(&lines, trailing_newline).into()
+    };
+    res.insert_header(cors::X_ANSWER_COUNT, results.len().to_string());
+    if truncated {
+        res.insert_header(cors::X_TRUNCATED, "true");
+    }
+    if minimal_any {
+        res.insert_header(cors::X_ANY_MINIMAL, "1");
+    }
+    if let Some(served) = served {
+        res.insert_header(cors::X_FAMILY, family_number(served).to_string());
+    }
+    if let Some(families) = &families {
+        let families = families
+            .iter()
+            .map(|rtype| family_number(*rtype).to_string())
+            .collect::<Vec<_>>();
+        res.insert_header(cors::X_FAMILY, families.join(","));
+    }
+    if offset + results.len() < total && query.pick.is_none() {
+        res.insert_header(cors::LINK, next_page(req.url(), offset + results.len()));
+    }
+    res.insert_header(cors::X_CACHE, cache_status);
+    if state.opts.cache_headers {
+        let shuffled = query.pick.is_some() || query.r != 0 && query.stable_shuffle == 0;
+        // What is left of the cache entry, not the TTL it started from.
+        let max_age = lookup
+            .valid_until()
+            .saturating_duration_since(Instant::now())
+            .as_secs();
+        cache_headers(&mut res, shuffled, session, max_age).await;
+    }
+    Ok(res)
+}
+
+/// `Cache-Control` of an answer. Orders shuffled per response must not be
+/// shared by caches, unless a session seeded them: then each session is
+/// cached apart, keyed by `Vary: X-Session` and an `ETag` of the body.
+async fn cache_headers(res: &mut Response, shuffled: bool, session: Option<&str>, max_age: u64) {
+    let public = format!("public, max-age={}", max_age);
+    match (shuffled, session) {
+        (false, _) => res.insert_header("Cache-Control", public),
+        (true, Some(session)) => {
+            let body = res.take_body().into_bytes().await.unwrap_or_default();
+            let mut hasher = DefaultHasher::new();
+            session.hash(&mut hasher);
+            body.hash(&mut hasher);
+            res.set_body(body);
+            res.insert_header("Cache-Control", public);
+            res.insert_header("Vary", X_SESSION);
+            res.insert_header("ETag", format!("\"{:016x}\"", hasher.finish()));
+        }
+        (true, None) => res.insert_header("Cache-Control", "private, no-store"),
+    }
+}
+
+/// Whether the answer of `host` was saved before the last restart and
+/// read back from `cache_file`, rather than asked this run. `None` stands
+/// for A and AAAA, either of them being enough.
+fn is_stale(state: &State, host: &str, rtype: Option<RecordType>) -> bool {
+    let persisted = match &state.persisted {
+        Some(persisted) => persisted,
+        None => return false,
+    };
+    match rtype {
+        Some(rtype) => persisted.serves_loaded(host, rtype),
+        None => {
+            persisted.serves_loaded(host, RecordType::A)
+                || persisted.serves_loaded(host, RecordType::AAAA)
+        }
+    }
+}
+
+/// Whether `rtype` may be queried, `None` standing for both A and AAAA.
+/// `admin` requests may query any type with `admin_bypass_types`.
+fn type_allowed(opts: &Opts, admin: bool, rtype: Option<RecordType>) -> bool {
+    if rtype == Some(RecordType::ANY) {
+        return opts.enable_any;
+    }
+    if admin && opts.admin_bypass_types {
+        return true;
+    }
+    let allowed = match &opts.allowed_types {
+        Some(allowed) => allowed,
+        None => return true,
+    };
+    match rtype {
+        Some(rtype) => allowed.contains(&rtype),
+        None => allowed.contains(&RecordType::A) && allowed.contains(&RecordType::AAAA),
+    }
+}
+
+fn type_not_allowed() -> Response {
+    Response::builder(StatusCode::Forbidden)
+        .body(TYPE_NOT_ALLOWED)
+        .build()
+}
+
+/// Follows `aliases` from `host`, with the hops taken. `None` when they
+/// loop or take more than `max_depth` hops.
+fn expand_alias<'a>(
+    aliases: &'a HashMap<String, String>,
+    host: &'a str,
+    max_depth: usize,
+) -> Option<(&'a str, usize)> {
+    let mut host = host;
+    for hops in 0..=max_depth {
+        match aliases.get(&host.to_ascii_lowercase()) {
+            Some(target) => host = target,
+            None => return Some((host, hops)),
+        }
+    }
+    None
+}
+
+/// CNAME hops the upstream followed to the answers of `lookup`.
+fn cname_hops(lookup: &Lookup, rtype: Option<RecordType>) -> usize {
+    if rtype == Some(RecordType::CNAME) {
+        return 0;
+    }
+    lookup
+        .record_iter()
+        .filter(|record| record.rr_type() == RecordType::CNAME)
+        .count()
+}
+
+/// A or AAAA for `f=4|6` and `prefer=4|6`.
+fn family_type(family: u8) -> Option<RecordType> {
+    match family {
+        4 => Some(RecordType::A),
+        6 => Some(RecordType::AAAA),
+        _ => None,
+    }
+}
+
+/// 4 or 6 for A or AAAA.
+fn family_number(rtype: RecordType) -> u8 {
+    if rtype == RecordType::AAAA {
+        6
+    } else {
+        4
+    }
+}
+
+/// `fast=1`: A and AAAA queried apart, answered with the first family to
+/// have addresses and the other one if it follows within `FAST_GRACE`.
+/// A family left behind completes in the background, so the next query
+/// finds it cached. Returns the families answered, A first.
+async fn lookup_fast(state: &State, host: &str) -> Result<(Lookup, Vec<RecordType>), ResolveError> {
+    let spawn = |rtype| {
+        let (state, host) = (state.clone(), host.to_string());
+        async_std::task::spawn(async move { query_upstream(&state, &host, Some(rtype)).await })
+    };
+    let (a, aaaa) = (spawn(RecordType::A), spawn(RecordType::AAAA));
+    let (first, (other, pending)) = match future::select(a, aaaa).await {
+        Either::Left((a, aaaa)) => ((RecordType::A, a), (RecordType::AAAA, aaaa)),
+        Either::Right((aaaa, a)) => ((RecordType::AAAA, aaaa), (RecordType::A, a)),
+    };
+    let answered = |(rtype, result): &(RecordType, Result<Lookup, ResolveError>)| {
+        result
+            .as_ref()
+            .is_ok_and(|lookup| !answers(state, host, lookup, Some(*rtype)).is_empty())
+    };
+    // Dropping the pending task leaves it running.
+    let second = match answered(&first) {
+        true => async_std::future::timeout(FAST_GRACE, pending).await.ok(),
+        false => Some(pending.await),
+    };
+    let mut results = vec![first];
+    results.extend(second.map(|result| (other, result)));
+    results.sort_by_key(|(rtype, _)| *rtype != RecordType::A);
+    let (families, lookups): (Vec<_>, Vec<_>) = results
+        .iter()
+        .filter(|result| answered(result))
+        .map(|(rtype, result)| (*rtype, result.as_ref().unwrap()))
+        .unzip();
+    let lookup = match lookups.as_slice() {
+        [] => {
+            let (_, result) = results.into_iter().next().unwrap();
+            return result.map(|lookup| (lookup, Vec::new()));
+        }
+        [lookup] => (*lookup).clone(),
+        [a, aaaa, ..] => merge_families(a, aaaa),
+    };
+    Ok((lookup, families))
+}
+
+/// A and AAAA queried together, the way trust-dns `lookup_ip` would one
+/// after the other. Either family answers; when both fail it is with the
+/// error of A, unless A only had no such records.
+async fn lookup_dual(state: &State, host: &str) -> Result<Lookup, ResolveError> {
+    let (a, aaaa) = future::join(
+        state.resolver.lookup(host, RecordType::A),
+        state.resolver.lookup(host, RecordType::AAAA),
+    )
+    .await;
+    match (a, aaaa) {
+        (Ok(a), Ok(aaaa)) => Ok(merge_families(&a, &aaaa)),
+        (Ok(lookup), Err(_)) | (Err(_), Ok(lookup)) => Ok(lookup),
+        (Err(err), Err(other)) if is_nodata(&err) => Err(other),
+        (Err(err), Err(_)) => Err(err),
+    }
+}
+
+/// `lookup_dual`, asked once more after `EMPTY_RETRY_DELAY` with
+/// `empty_retry` when the answer was empty but not authoritative.
+async fn lookup_addrs_retried(state: &State, host: &str) -> Result<Lookup, ResolveError> {
+    let result = lookup_dual(state, host).await;
+    match result {
+        Err(err) if state.opts.empty_retry && is_unsure_empty(&err) => {
+            async_std::task::sleep(EMPTY_RETRY_DELAY).await;
+            Metrics::inc(&state.metrics.empty_retries);
+            lookup_dual(state, host).await
+        }
+        result => result,
+    }
+}
+
+/// An answer without records that a glitching upstream may have sent: not
+/// an NXDOMAIN trust-dns trusts, and without the SOA an authoritative
+/// negative answer carries. Those with an SOA are negatively cached, so
+/// asking again could not change them anyway.
+fn is_unsure_empty(err: &ResolveError) -> bool {
+    match err.kind() {
+        ResolveErrorKind::NoRecordsFound {
+            response_code: ResponseCode::NXDomain,
+            trusted: true,
+            ..
+        } => false,
+        ResolveErrorKind::NoRecordsFound {
+            response_code: ResponseCode::NoError | ResponseCode::NXDomain,
+            soa,
+            ..
+        } => soa.is_none(),
+        _ => false,
+    }
+}
+
+/// One lookup of the A records then the AAAA records, expiring with the
+/// first of them.
+fn merge_families(a: &Lookup, aaaa: &Lookup) -> Lookup {
+    // CNAME records leading to the addresses are in both.
+    let records = a
+        .record_iter()
+        .chain(
+            aaaa.record_iter()
+                .filter(|record| record.rr_type() == RecordType::AAAA),
+        )
+        .cloned()
+        .collect::<Vec<_>>();
+    let valid_until = a.valid_until().min(aaaa.valid_until());
+    Lookup::new_with_deadline(a.query().clone(), Arc::from(records), valid_until)
+}
+
+/// Lookup of the `preferred` family, or of the other one if there are no
+/// such addresses, with the family actually served.
+async fn lookup_preferred(
+    state: &State,
+    host: &str,
+    preferred: RecordType,
+) -> Result<(Lookup, RecordType), ResolveError> {
+    match query_upstream(state, host, Some(preferred)).await {
+        Ok(lookup) if !answers(state, host, &lookup, Some(preferred)).is_empty() => {
+            return Ok((lookup, preferred))
+        }
+        Ok(_) => {}
+        Err(err) if is_nodata(&err) => {}
+        Err(err) => return Err(err),
+    }
+    let other = if preferred == RecordType::A {
+        RecordType::AAAA
+    } else {
+        RecordType::A
+    };
+    let lookup = query_upstream(state, host, Some(other)).await?;
+    Ok((lookup, other))
+}
+
+/// The name exists but has no records of the queried type.
+fn is_nodata(err: &ResolveError) -> bool {
+    matches!(
+        err.kind(),
+        ResolveErrorKind::NoRecordsFound {
+            response_code: ResponseCode::NoError,
+            ..
+        }
+    )
+}
+
+/// Resolves `host` and then every address back to its PTR names.
+async fn resolve_reverse(req: Request<State>) -> tide::Result {
+    let host = match normalize_host(req.param("host")?) {
+        Some(host) if validate_host(&host) => host,
+        _ => return Ok(Response::builder(StatusCode::BadRequest).build()),
+    };
+    let host = host.as_str();
+    let query: ResolveQuery = params::query(&req, RESOLVE_LIMITS)?;
+    let state = req.state();
+    let admin = is_admin(&req);
+    if !type_allowed(&state.opts, admin, None)
+        || !type_allowed(&state.opts, admin, Some(RecordType::PTR))
+    {
+        return Ok(type_not_allowed());
+    }
+    let name_filter = match query.filter.as_deref().map(filter::NameMatch::new) {
+        Some(None) => return Ok(bad_filter()),
+        name_filter => name_filter.flatten(),
+    };
+    let mut addrs = match lookup_addrs(state, host, None).await {
+        Ok(addrs) => addrs,
+        Err(err) => return lookup_error(state, host, err, Format::Json),
+    };
+    addrs.truncate(query.n.unwrap_or(DEFAULT_N).into());
+    if addrs.is_empty() {
+        return Ok(Response::builder(StatusCode::NotFound)
+            .body(NOT_FOUND)
+            .build());
+    }
+    let results = stream::iter(addrs)
+        .map(|ip| reverse(state, ip, name_filter.as_ref()))
+        .buffered(state.opts.concurrency)
+        .collect::<Vec<_>>()
+        .await;
+    let named = results.iter().any(|result| result["ptr"] != json!([]));
+    if name_filter.is_some() && !named {
+        return Ok(Response::builder(StatusCode::NotFound)
+            .body(NOT_FOUND)
+            .build());
+    }
+    Ok(json!(results).into())
+}
+
+/// PTR names of `ip` matching `name_filter`, empty when there are none.
+async fn reverse(
+    state: &State,
+    ip: IpAddr,
+    name_filter: Option<&filter::NameMatch>,
+) -> serde_json::Value {
+    let name = Name::from(ip).to_string();
+    match lookup_records(state, &name, Some(RecordType::PTR)).await {
+        Ok(mut records) => {
+            if let Some(name_filter) = name_filter {
+                records = name_filter.filter(&name, records);
+            }
+            let ptr = records
+                .iter()
+                .map(|record| rdata::text(record.rdata()))
+                .collect::<Vec<_>>();
+            json!({ "ip": ip, "ptr": ptr })
+        }
+        Err(err) => match error_code(state, &name, &err) {
+            NOT_FOUND => json!({ "ip": ip, "ptr": [] }),
+            code => json!({ "ip": ip, "ptr": [], "error": code }),
+        },
+    }
+}
+
+/// 400 for a `filter` that is invalid, too long or too costly.
+fn bad_filter() -> Response {
+    Response::builder(StatusCode::BadRequest)
+        .body(BAD_FILTER)
+        .build()
+}
+
+/// Text of `record`, as `TYPE\trdata` with `label`. ANY answers mix
+/// types, so they are `TYPE rdata` even without it.
+fn text_line(record: &Record, any: bool, label: bool) -> String {
+    let text = rdata::text(record.rdata());
+    let rtype = rdata::type_name(record.rr_type());
+    if label {
+        format!("{}\t{}", rtype, text)
+    } else if any {
+        format!("{} {}", rtype, text)
+    } else {
+        text
+    }
+}
+
+/// A link-local answer kept with `LINK_LOCAL=annotate`.
+fn annotated(state: &State, record: &Record) -> bool {
+    state.opts.link_local == LinkLocal::Annotate && filter::is_link_local(record)
+}
+
+/// `rdata::json` of an answer, scoped when `annotated`.
+fn answer_json(state: &State, record: &Record) -> serde_json::Value {
+    let mut answer = rdata::json(record);
+    if annotated(state, record) {
+        answer["scope"] = SCOPE_LINK_LOCAL.into();
+    }
+    answer
+}
+
+/// `text_line` of an answer, ending in a `link-local` field when
+/// `annotated`.
+fn answer_line(state: &State, record: &Record, any: bool, label: bool) -> String {
+    let mut line = text_line(record, any, label);
+    if annotated(state, record) {
+        line.push('\t');
+        line.push_str(SCOPE_LINK_LOCAL);
+    }
+    line
+}
+
+/// Plain-text body, one result per line.
+fn text_body(lines: &[String], trailing_newline: bool) -> String {
+    let mut body = lines.join("\n");
+    if trailing_newline {
+        body.push('\n');
+    }
+    body
+}
+
+/// RFC 4180 table of `records`, CRLF line breaks.
+fn csv_body(records: &[Record]) -> Response {
+    let mut body = format!("{}\r\n", rdata::CSV_HEADER);
+    for record in records {
+        body.push_str(&rdata::csv(record));
+        body.push_str("\r\n");
+    }
+    Response::builder(StatusCode::Ok)
+        .body(body)
+        .content_type("text/csv")
+        .build()
+}
+
+/// Answer records of `host` of type `rtype`, A and AAAA when it is `None`.
+async fn lookup_records(
+    state: &State,
+    host: &str,
+    rtype: Option<RecordType>,
+) -> Result<Vec<Record>, ResolveError> {
+    let lookup = query_upstream(state, host, rtype).await?;
+    Ok(answers(state, host, &lookup, rtype))
+}
+
+/// Raw lookup, with any CNAME records leading to the answers.
+async fn query_upstream(
+    state: &State,
+    host: &str,
+    rtype: Option<RecordType>,
+) -> Result<Lookup, ResolveError> {
+    if let Some(err) = allowlist_error(state, host) {
+        return Err(err);
+    }
+    if let Some(err) = test_host_error(state, host, rtype) {
+        return Err(err);
+    }
+    let (result, label) = match rtype {
+        None => (lookup_addrs_retried(state, host).await, "IP".into()),
+        Some(rtype) => (
+            state.resolver.lookup(host, rtype).await,
+            rdata::type_name(rtype),
+        ),
+    };
+    let outcome = outcome(&result);
+    state.metrics.lookup(&label, outcome);
+    if let Some(zones) = &state.zones {
+        let cache_hit = match &result {
+            Ok(lookup) => upstream::from_cache(lookup, Instant::now()),
+            Err(_) => false,
+        };
+        zones.record(host, cache_hit, outcome == metrics::ERROR);
+    }
+    result
+}
+
+fn outcome(result: &Result<Lookup, ResolveError>) -> &'static str {
+    match result.as_ref().map_err(ResolveError::kind) {
+        Ok(_) => metrics::HIT,
+        Err(ResolveErrorKind::NoRecordsFound {
+            response_code: ResponseCode::NXDomain,
+            ..
+        }) => metrics::NXDOMAIN,
+        Err(ResolveErrorKind::NoRecordsFound {
+            response_code: ResponseCode::NoError,
+            ..
+        }) => metrics::NODATA,
+        Err(_) => metrics::ERROR,
+    }
+}
+
+/// Records of `lookup` answering `rtype`, through the answer filters.
+fn answers(state: &State, host: &str, lookup: &Lookup, rtype: Option<RecordType>) -> Vec<Record> {
+    let records = lookup
+        .record_iter()
+        .filter(|record| match rtype {
+            None => matches!(record.rr_type(), RecordType::A | RecordType::AAAA),
+            Some(RecordType::ANY) => true,
+            Some(rtype) => record.rr_type() == rtype,
+        })
+        .cloned()
+        .collect();
+    let records = match state.opts.link_local {
+        LinkLocal::Drop => filter::StripLinkLocal.filter(host, records),
+        _ => records,
+    };
+    state
+        .filters
+        .iter()
+        .fold(records, |records, filter| filter.filter(host, records))
+}
+
+/// `debug=wire`: the upstream response to a single query as the bytes
+/// received, hex or base64, for when the parsed answers look wrong. It is
+/// neither parsed, filtered nor cached, an NXDOMAIN is a 200 like any
+/// other response. Needs `debug_wire` and `auth_token` as a bearer token.
+async fn resolve_wire(
+    req: &Request<State>,
+    host: &str,
+    rtype: RecordType,
+    query: &ResolveQuery,
+) -> tide::Result {
+    let state = req.state();
+    let token = match (&state.opts.auth_token, state.opts.debug_wire) {
+        (Some(token), true) => token,
+        _ => {
+            return Ok(Response::builder(StatusCode::Forbidden)
+                .body(DEBUG_DISABLED)
+                .build())
+        }
+    };
+    let authorization = req.header("Authorization").map(|values| values.as_str());
+    if !bearer_matches(authorization, token) {
+        return Ok(Response::builder(StatusCode::Unauthorized)
+            .header("WWW-Authenticate", "Bearer")
+            .body(UNAUTHORIZED)
+            .build());
+    }
+    let encoding = match query.encoding.as_deref() {
+        None | Some("hex") => &HEXLOWER,
+        Some("base64") => &BASE64,
+        Some(_) => return Ok(Response::builder(StatusCode::BadRequest).build()),
+    };
+    if let Some(err) = allowlist_error(state, host) {
+        return lookup_error(state, host, err, Format::Text);
+    }
+    match state
+        .resolver
+        .query_wire(host, rtype, query.dnssec_ok != 0)
+        .await
+    {
+        Ok(wire) => Ok(encoding.encode(&wire).into()),
+        Err(err) => lookup_error(state, host, err, Format::Text),
+    }
+}
+
+/// Whether `req` carries `auth_token` as a bearer token.
+fn is_admin(req: &Request<State>) -> bool {
+    let authorization = req.header("Authorization").map(|values| values.as_str());
+    match &req.state().opts.auth_token {
+        Some(token) => bearer_matches(authorization, token),
+        None => false,
+    }
+}
+
+/// Whether `authorization` is `Bearer {token}`, compared in constant time.
+fn bearer_matches(authorization: Option<&str>, token: &str) -> bool {
+    let given = match authorization.and_then(|value| value.strip_prefix("Bearer ")) {
+        Some(given) => given.as_bytes(),
+        None => return false,
+    };
+    given.len() == token.len()
+        && given
+            .iter()
+            .zip(token.as_bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+/// `do=1`: queries with the EDNS DO bit and answers JSON with the RRSIG
+/// and additional records in `meta`, for inspecting signatures. Nothing is
+/// validated. Signatures often make answers several times larger, past the
+/// UDP size, so these queries are slower and may fall back to TCP.
+async fn resolve_dnssec(state: &State, host: &str, rtype: Option<RecordType>) -> tide::Result {
+    if let Some(err) = allowlist_error(state, host) {
+        return lookup_error(state, host, err, Format::Json);
+    }
+    let types = match rtype {
+        Some(rtype) => vec![rtype],
+        None => vec![RecordType::A, RecordType::AAAA],
+    };
+    let (mut answers, mut rrsig, mut additional) = (Vec::new(), Vec::new(), Vec::new());
+    let mut authentic_data = true;
+    for rtype in types {
+        let message = match state.resolver.query(host, rtype, true).await {
+            Ok(message) => message,
+            Err(err) => return lookup_error(state, host, err, Format::Json),
+        };
+        let code = message.response_code();
+        if code != ResponseCode::NoError {
+            let err = no_records(Name::from_str(host)?, rtype, code, None);
+            return lookup_error(state, host, err, Format::Json);
+        }
+        authentic_data &= message.authentic_data();
+        for record in message.answers() {
+            if record.rr_type() == RecordType::Unknown(rdata::RRSIG) {
+                rrsig.push(rdata::json(record));
+            } else {
+                answers.push(rdata::json(record));
+            }
+        }
+        additional.extend(message.additionals().iter().map(rdata::json));
+    }
+    if answers.is_empty() {
+        return Ok(Response::builder(StatusCode::NotFound)
+            .body(NOT_FOUND)
+            .build());
+    }
+    let count = answers.len();
+    let mut res: Response = json!({
+        "host": host,
+        "answers": answers,
+        "meta": {
+            "dnssec_ok": true,
+            "authentic_data": authentic_data,
+            "rrsig": rrsig,
+            "additional": additional,
+        },
+    })
+    .into();
+    res.insert_header(cors::X_ANSWER_COUNT, count.to_string());
+    Ok(res)
+}
+
+/// `/r/a.example,b.example`: answers of every host, concatenated or, with
+/// `keyed=1` and JSON, by host with invalid or failed hosts as `{"error"}`.
+async fn resolve_batch(
+    state: &State,
+    hosts: &str,
+    rtype: Option<RecordType>,
+    query: &ResolveQuery,
+) -> tide::Result {
+    let hosts = hosts
+        .split(',')
+        .map(ToString::to_string)
+        .collect::<Vec<_>>();
+    if hosts.len() > MAX_BATCH_HOSTS {
+        return Ok(Response::builder(StatusCode::BadRequest).build());
+    }
+    let keyed = query.keyed != 0 && query.format == Format::Json;
+    // Aliases are expanded first, their keys need not be valid names. A
+    // loop fails its own host, in `resolve_one`.
+    let max_depth = state.opts.max_resolve_depth;
+    let valid = |host: &String| match expand_alias(&state.opts.aliases, host, max_depth) {
+        Some((host, _)) => validate_name(host, rtype.is_some()),
+        None => true,
+    };
+    if !keyed && !hosts.iter().all(valid) {
+        return Ok(Response::builder(StatusCode::BadRequest).build());
+    }
+    let n = query.n.unwrap_or(DEFAULT_N).into();
+    // Owned hosts, the futures must not borrow from the stream items.
+    let mut results = stream::iter(hosts.clone())
+        .map(|host| async move { resolve_one(state, &host, rtype, n).await })
+        .buffered(state.opts.concurrency)
+        .collect::<Vec<_>>()
+        .await;
+    // Entries share one budget, in request order.
+    let mut budget = state.opts.max_response_bytes;
+    let mut truncated = false;
+    for records in results.iter_mut().filter_map(|result| result.as_mut().ok()) {
+        let before = records.len();
+        budget -= truncate_to_size(records, query.format, budget);
+        truncated |= records.len() < before;
+    }
+    if keyed {
+        let body = hosts
+            .into_iter()
+            .zip(results)
+            .map(|(host, result)| {
+                let value = match result {
+                    Ok(records) => json!(records
+                        .iter()
+                        .map(|record| answer_json(state, record))
+                        .collect::<Vec<_>>()),
+                    Err(code) => json!({ "error": code }),
+                };
+                (host, value)
+            })
+            .collect::<serde_json::Map<_, _>>();
+        // Hosts are the keys, so truncation only shows in the header.
+        let mut res: Response = serde_json::Value::Object(body).into();
+        if truncated {
+            res.insert_header(cors::X_TRUNCATED, "true");
+        }
+        return Ok(res);
+    }
+    let records = results
+        .into_iter()
+        .flat_map(Result::unwrap_or_default)
+        .collect::<Vec<_>>();
+    if records.is_empty() && !truncated {
+        return Ok(Response::builder(StatusCode::NotFound)
+            .body(NOT_FOUND)
+            .build());
+    }
+    let mut res: Response = if query.format == Format::Json {
+        let answers = records
+            .iter()
+            .map(|record| answer_json(state, record))
+            .collect::<Vec<_>>();
+        let mut body = json!({ "answers": answers });
+        if truncated {
+            body["truncated_reason"] = TRUNCATED_SIZE.into();
+        }
+        body.into()
+    } else if query.format == Format::Csv {
+        csv_body(&records)
+    } else {
+        let lines = records
+            .iter()
+            .map(|record| answer_line(state, record, false, query.label != 0))
+            .collect::<Vec<_>>();
+        let trailing_newline = query
+            .trailing_newline
+            .map_or(state.opts.trailing_newline, |v| v != 0);
+        text_body(&lines, trailing_newline).into()
+    };
+    res.insert_header(cors::X_ANSWER_COUNT, records.len().to_string());
+    if truncated {
+        res.insert_header(cors::X_TRUNCATED, "true");
+    }
+    Ok(res)
+}
+
+/// Drops the records past `max_bytes` of output, returning the bytes
+/// of those kept. Sizes leave out the envelope around the answers.
+fn truncate_to_size(records: &mut Vec<Record>, format: Format, max_bytes: usize) -> usize {
+    let mut size = 0;
+    let mut kept = 0;
+    for record in records.iter() {
+        let record_size = match format {
+            Format::Json => rdata::json(record).to_string().len() + 1,
+            Format::Csv => rdata::csv(record).len() + 2,
+            Format::Text => rdata::text(record.rdata()).len() + 1,
+        };
+        if size + record_size > max_bytes {
+            break;
+        }
+        size += record_size;
+        kept += 1;
+    }
+    records.truncate(kept);
+    size
+}
+
+/// Up to `n` records of a batch host, or its error code.
+async fn resolve_one(
+    state: &State,
+    host: &str,
+    rtype: Option<RecordType>,
+    n: usize,
+) -> Result<Vec<Record>, &'static str> {
+    let max_depth = state.opts.max_resolve_depth;
+    let (host, hops) = expand_alias(&state.opts.aliases, host, max_depth).ok_or(ALIAS_LOOP)?;
+    if !validate_name(host, rtype.is_some()) {
+        return Err(BAD_HOST);
+    }
+    match query_upstream(state, host, rtype).await {
+        Ok(lookup) if hops + cname_hops(&lookup, rtype) > max_depth => Err(ALIAS_LOOP),
+        Ok(lookup) => {
+            let mut records = answers(state, host, &lookup, rtype);
+            records.truncate(n);
+            Ok(records)
+        }
+        Err(err) => Err(error_code(state, host, &err)),
+    }
+}
+
+/// Whether an ANY answer is the RFC 8482 stand-in, a lone HINFO `RFC8482`.
+fn is_minimal_any(records: &[Record]) -> bool {
+    match records {
+        [record] => matches!(record.rdata(), RData::HINFO(hinfo) if hinfo.cpu() == b"RFC8482"),
+        _ => false,
+    }
+}
+
+/// Records of the `ANY_FALLBACK_TYPES` queried separately, failures skipped.
+async fn lookup_any_fallback(state: &State, host: &str) -> Vec<Record> {
+    stream::iter(ANY_FALLBACK_TYPES.iter().copied())
+        .map(|rtype| lookup_records(state, host, Some(rtype)))
+        .buffered(state.opts.concurrency)
+        .filter_map(|records| async move { records.ok() })
+        .concat()
+        .await
+}
+
+/// Whether `host` itself is a CNAME.
+fn is_alias(lookup: &Lookup, host: &str) -> bool {
+    let host = host.trim_end_matches('.');
+    lookup.record_iter().any(|record| {
+        record.rr_type() == RecordType::CNAME
+            && record
+                .name()
+                .to_ascii()
+                .trim_end_matches('.')
+                .eq_ignore_ascii_case(host)
+    })
+}
+
+/// Failure for hosts outside the allowlist, counted and logged as they
+/// may show tampering, without asking upstream.
+fn allowlist_error(state: &State, host: &str) -> Option<ResolveError> {
+    let allowlist = state.opts.allowlist.as_ref()?;
+    if host_listed(allowlist, host) {
+        return None;
+    }
+    tide::log::warn!("host not allowed", { host: host });
+    Metrics::inc(&state.metrics.allowlist_denied);
+    Some(ResolveErrorKind::Message(HOST_NOT_ALLOWED).into())
+}
+
+/// Whether `host` is one of the `allowlist` domains or below one.
+fn host_listed(allowlist: &[String], host: &str) -> bool {
+    let host = host.trim_end_matches('.').to_ascii_lowercase();
+    allowlist.iter().any(|domain| {
+        host.strip_suffix(domain.as_str())
+            .is_some_and(|rest| rest.is_empty() || rest.ends_with('.'))
+    })
+}
+
+/// Canned failure for the configured test hosts, without asking upstream.
+fn test_host_error(state: &State, host: &str, rtype: Option<RecordType>) -> Option<ResolveError> {
+    let host = host.trim_end_matches('.');
+    let (_, response_code) = state
+        .opts
+        .test_hosts
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case(host))?;
+    let name = Name::from_str(host).ok()?;
+    Some(no_records(
+        name,
+        rtype.unwrap_or(RecordType::A),
+        *response_code,
+        Some(state.opts.default_record_ttl),
+    ))
+}
+
+/// The error trust-dns gives for an answer without records.
+fn no_records(
+    name: Name,
+    rtype: RecordType,
+    response_code: ResponseCode,
+    negative_ttl: Option<u32>,
+) -> ResolveError {
+    ResolveErrorKind::NoRecordsFound {
+        query: Query::query(name, rtype),
+        soa: None,
+        negative_ttl,
+        response_code,
+        trusted: true,
+    }
+    .into()
+}
+
+/// Addresses of `host`, `rtype` narrows the lookup to A or AAAA records.
+async fn lookup_addrs(
+    state: &State,
+    host: &str,
+    rtype: Option<RecordType>,
+) -> Result<Vec<IpAddr>, ResolveError> {
+    Ok(lookup_records(state, host, rtype)
+        .await?
+        .iter()
+        .filter_map(|record| record.rdata().to_ip_addr())
+        .collect())
+}
+
+/// Response to a failed lookup, JSON diagnostics with `verbose_errors`
+/// when the route answers `format`.
+fn lookup_error(state: &State, host: &str, err: ResolveError, format: Format) -> tide::Result {
+    let code = error_code(state, host, &err);
+    let verbose = state.opts.verbose_errors && format == Format::Json;
+    let status = match code {
+        NOT_FOUND => StatusCode::NotFound,
+        SERVFAIL => StatusCode::BadGateway,
+        HOST_NOT_ALLOWED => StatusCode::Forbidden,
+        egress::UPSTREAM_THROTTLED => StatusCode::ServiceUnavailable,
+        _ if verbose => StatusCode::InternalServerError,
+        _ => return Err(err.into()),
+    };
+    let mut res = Response::builder(status);
+    if let Some(ttl) = negative_ttl(&err).filter(|_| state.opts.cache_headers) {
+        res = res.header("Cache-Control", format!("public, max-age={}", ttl));
+    }
+    Ok(match verbose {
+        true => res.body(diagnostics(code, &err)),
+        false => res.body(code),
+    }
+    .build())
+}
+
+/// `{"error", "kind", "rcode", "upstreams", "tries", "message"}` of a
+/// failed lookup. `upstreams` are those the request asked, in order and
+/// once per query, `tries` how many queries that was.
+fn diagnostics(code: &str, err: &ResolveError) -> serde_json::Value {
+    let (kind, rcode) = match err.kind() {
+        ResolveErrorKind::NoRecordsFound { response_code, .. } => {
+            ("no_records", Some(axfr::rcode_name(*response_code)))
+        }
+        ResolveErrorKind::Timeout => ("timeout", None),
+        ResolveErrorKind::Io(_) => ("io", None),
+        ResolveErrorKind::Proto(_) => ("protocol", None),
+        _ => ("message", None),
+    };
+    let upstreams = attempts::asked();
+    serde_json::json!({
+        "error": code,
+        "kind": kind,
+        "rcode": rcode,
+        "upstreams": upstreams,
+        "tries": upstreams.len(),
+        "message": err.to_string(),
+    })
+}
+
+/// Short machine-readable code for a failed lookup, SERVFAIL is logged and counted.
+fn error_code(state: &State, host: &str, err: &ResolveError) -> &'static str {
+    match err.kind() {
+        ResolveErrorKind::NoRecordsFound {
+            response_code: ResponseCode::ServFail,
+            ..
+        } => {
+            tide::log::warn!("servfail", {
+                host: host,
+                upstream: state.resolver.name(),
+            });
+            Metrics::inc(&state.metrics.servfail);
+            SERVFAIL
+        }
+        ResolveErrorKind::NoRecordsFound { .. } => NOT_FOUND,
+        ResolveErrorKind::Message(HOST_NOT_ALLOWED) => HOST_NOT_ALLOWED,
+        _ if egress::throttled(err) => egress::UPSTREAM_THROTTLED,
+        ResolveErrorKind::Timeout => "timeout",
+        _ => "error",
+    }
+}
+
+async fn metrics(req: Request<State>) -> tide::Result {
+    Ok(req.state().metrics.render().into())
+}
+
+async fn robots_txt(req: Request<State>) -> tide::Result {
+    Ok(Response::builder(StatusCode::Ok)
+        .body(req.state().opts.robots_txt.as_str())
+        .content_type("text/plain")
+        .build())
+}
+
+/// 503 when the upstreams do not answer a root NS query, or when the
+/// recent lookup error rate is above `degraded_error_rate`.
+async fn ready(req: Request<State>) -> tide::Result {
+    let state = req.state();
+    if let Err(err) = state.resolver.query(".", RecordType::NS, false).await {
+        tide::log::warn!("ready", { error: err.to_string() });
+        return Ok(Response::builder(StatusCode::ServiceUnavailable)
+            .body(UPSTREAM_DOWN)
+            .build());
+    }
+    let opts = &state.opts;
+    if let Some(max) = opts.degraded_error_rate {
+        let rate = state
+            .metrics
+            .error_rate(opts.degraded_window, opts.degraded_min_lookups);
+        if rate.is_some_and(|rate| rate > max) {
+            return Ok(Response::builder(StatusCode::ServiceUnavailable)
+                .body(DEGRADED)
+                .build());
+        }
+    }
+    Ok("OK".into())
+}
+
+/// Each upstream with what the last capability probe found.
+async fn admin_upstreams(req: Request<State>) -> tide::Result {
+    Ok(json!({ "upstreams": req.state().resolver.status() }).into())
+}
+
+/// Build version and the effective query policy, for debugging.
+async fn version(req: Request<State>) -> tide::Result {
+    let allowed_types = match &req.state().opts.allowed_types {
+        Some(allowed) => json!(allowed
+            .iter()
+            .map(|t| rdata::type_name(*t))
+            .collect::<Vec<_>>()),
+        None => json!("*"),
+    };
+    Ok(json!({
+        "version": env!("CARGO_PKG_VERSION"),
+        "allowed_types": allowed_types,
+        "upstream_bind_addrs": req.state().opts.upstream_bind_addrs,
+        "api_contract": req.state().opts.text_contract.then_some(contract::TEXT_V1),
+    })
+    .into())
+}
+
+/// Answered records of each type, and whether the caps cut some.
+type TypeCounts = BTreeMap<RecordType, (usize, bool)>;
+
+/// Keeps at most `requested` and its `TYPE_LIMITS` entry of each record
+/// type: the first ones, or a random share with `rng`, left in upstream
+/// order. With either set, the answered count of each type and whether
+/// it was cut, before `n`.
+fn limit_per_type<R: Rng + ?Sized>(
+    results: &mut Vec<Record>,
+    type_limits: &HashMap<RecordType, usize>,
+    requested: Option<usize>,
+    rng: Option<&mut R>,
+) -> Option<TypeCounts> {
+    if type_limits.is_empty() && requested.is_none() {
+        return None;
+    }
+    let mut order = (0..results.len()).collect::<Vec<_>>();
+    if let Some(rng) = rng {
+        order.shuffle(rng);
+    }
+    let mut counts = BTreeMap::new();
+    let mut keep = vec![false; results.len()];
+    for i in order {
+        let rtype = results[i].rr_type();
+        let limit = match (type_limits.get(&rtype), requested) {
+            (Some(&limit), Some(requested)) => limit.min(requested),
+            (Some(&limit), None) => limit,
+            (None, requested) => requested.unwrap_or(usize::MAX),
+        };
+        let (count, truncated) = counts.entry(rtype).or_insert((0, false));
+        *count += 1;
+        *truncated |= *count > limit;
+        keep[i] = *count <= limit;
+    }
+    let mut keep = keep.into_iter();
+    results.retain(|_| keep.next().unwrap_or(false));
+    Some(counts)
+}
+
+/// `{"TYPE": {"total", "truncated"}}` of `limit_per_type`.
+fn type_counts_json(type_counts: &TypeCounts) -> serde_json::Value {
+    type_counts
+        .iter()
+        .map(|(rtype, (total, truncated))| {
+            let counts = json!({ "total": total, "truncated": truncated });
+            (rdata::type_name(*rtype), counts)
+        })
+        .collect::<serde_json::Map<_, _>>()
+        .into()
+}
+
+/// Locks the shared RNG. A panic while it was held poisons the lock and
+/// may leave the RNG mid-update, so it is reseeded and serving goes on.
+fn lock_rng(rng: &Mutex<SmallRng>) -> MutexGuard<'_, SmallRng> {
+    rng.lock().unwrap_or_else(|poisoned| {
+        tide::log::warn!("rng lock poisoned, reseeding");
+        rng.clear_poison();
+        let mut rng = poisoned.into_inner();
+        *rng = SmallRng::from_entropy();
+        rng
+    })
+}
+
+/// Applies the per-type caps, then `pick`, or `offset`, `n` and the
+/// shuffle, to the answers. Shuffled or picked answers keep a random share
+/// of each type rather than the first ones. Answers the records left for
+/// `offset`, and the counts of `limit_per_type`.
+fn arrange<R: Rng + ?Sized>(
+    results: &mut Vec<Record>,
+    query: &ResolveQuery,
+    type_limits: &HashMap<RecordType, usize>,
+    rng: &mut R,
+) -> (usize, Option<TypeCounts>) {
+    let shuffled = query.pick.is_some() || query.r != 0 || query.stable_shuffle != 0;
+    let requested = query.limit_per_type.map(usize::from);
+    let type_counts = match shuffled {
+        true => limit_per_type(results, type_limits, requested, Some(&mut *rng)),
+        false => limit_per_type(results, type_limits, requested, None::<&mut R>),
+    };
+    let total = results.len();
+    if let Some(k) = query.pick {
+        pick_random(results, k.into(), rng);
+    } else {
+        results.drain(..query.offset.unwrap_or(0).min(results.len()));
+        results.truncate(query.n.unwrap_or(DEFAULT_N).into());
+        if query.r != 0 || query.stable_shuffle != 0 {
+            results.shuffle(rng);
+        }
+    }
+    (total, type_counts)
+}
+
+/// `Link` to the answers from `offset` on, the same request otherwise.
+fn next_page(url: &Url, offset: usize) -> String {
+    let pairs = url
+        .query_pairs()
+        .filter(|(key, _)| key != "offset")
+        .map(|(key, value)| (key.into_owned(), value.into_owned()))
+        .collect::<Vec<_>>();
+    let mut url = url.clone();
+    url.query_pairs_mut()
+        .clear()
+        .extend_pairs(pairs)
+        .append_pair("offset", &offset.to_string());
+    format!(
+        "<{}?{}>; rel=\"next\"",
+        url.path(),
+        url.query().unwrap_or("")
+    )
+}
+
+/// Same for every response served from one resolver cache entry, which
+/// share its expiry, and new when the entry is refilled. A `session`
+/// gets an order of its own.
+fn stable_seed(
+    host: &str,
+    rtype: Option<RecordType>,
+    valid_until: Instant,
+    session: Option<&str>,
+) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    host.to_ascii_lowercase()
+        .trim_end_matches('.')
+        .hash(&mut hasher);
+    rtype.hash(&mut hasher);
+    valid_until.hash(&mut hasher);
+    session.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Keeps `k` elements chosen uniformly at random, in random order
+/// (partial Fisher–Yates).
+fn pick_random<T, R: Rng + ?Sized>(items: &mut Vec<T>, k: usize, rng: &mut R) {
+    let len = items.len();
+    let (picked, _) = items.partial_shuffle(rng, k);
+    let picked_len = picked.len();
+    items.rotate_left(len - picked_len);
+    items.truncate(picked_len);
+}
+
+async fn exists(req: Request<State>) -> tide::Result {
+    let host = match normalize_host(req.param("host")?) {
+        Some(host) => host,
+        None => return Ok(Response::builder(StatusCode::BadRequest).build()),
+    };
+    let host = host.as_str();
+    let query: ExistsQuery = params::query(&req, &[])?;
+    let rtype = match query.t.as_deref().map(rdata::parse_type) {
+        Some(Some(rtype)) => rtype,
+        Some(None) => return Ok(Response::builder(StatusCode::BadRequest).build()),
+        None => RecordType::A,
+    };
+    if !validate_name(host, query.t.is_some()) {
+        return Ok(Response::builder(StatusCode::BadRequest).build());
+    }
+    let state = req.state();
+    if !type_allowed(&state.opts, is_admin(&req), Some(rtype)) {
+        return Ok(type_not_allowed());
+    }
+    let err = match is_exists(state, host, rtype).await {
+        Ok(Some(ttl)) if query.ttl != 0 => return Ok(ttl.to_string().into()),
+        Ok(Some(_)) => return Ok(EXISTS.into()),
+        Ok(None) => {
+            return Ok(Response::builder(StatusCode::NotFound)
+                .body(NOT_FOUND)
+                .build())
+        }
+        Err(err) => err,
+    };
+    // NXDOMAIN, or NOERROR for a name without records of that type.
+    let rcode = match err.kind() {
+        ResolveErrorKind::NoRecordsFound {
+            response_code: code @ (ResponseCode::NXDomain | ResponseCode::NoError),
+            ..
+        } => Some(*code),
+        _ => None,
+    };
+    let mut res = match negative_ttl(&err) {
+        Some(ttl) if query.ttl != 0 => Response::builder(StatusCode::NotFound)
+            .body(ttl.to_string())
+            .build(),
+        _ => lookup_error(state, host, err, Format::Text)?,
+    };
+    if let Some(rcode) = rcode {
+        res.insert_header(cors::X_RCODE, axfr::rcode_name(rcode));
+    }
+    Ok(res)
+}
+
+/// Minimum TTL of the records of `host` of `rtype`, `None` if there are none.
+async fn is_exists(
+    state: &State,
+    host: &str,
+    rtype: RecordType,
+) -> Result<Option<u32>, ResolveError> {
+    let records = lookup_records(state, host, Some(rtype)).await?;
+    Ok(records.iter().map(Record::ttl).min())
+}
+
+/// Negative-cache TTL of an NXDOMAIN or NODATA answer, if the SOA gave one.
+fn negative_ttl(err: &ResolveError) -> Option<u32> {
+    match err.kind() {
+        ResolveErrorKind::NoRecordsFound {
+            response_code: ResponseCode::NXDomain | ResponseCode::NoError,
+            negative_ttl,
+            ..
+        } => *negative_ttl,
+        _ => None,
+    }
+}
+
+/// The `:host` path segment as a name, or comma-separated names, ready
+/// for validation: percent-decoded once, lowercased and converted to
+/// ASCII with IDNA. `None` if anything is still encoded, a control
+/// character shows up or the input is far too long.
+fn normalize_host(raw: &str) -> Option<String> {
+    if raw.len() > MAX_HOST_PARAM * 3 {
+        return None;
+    }
+    let host = percent_decode_str(raw).decode_utf8().ok()?;
+    if host.len() > MAX_HOST_PARAM || host.chars().any(|c| c == '%' || c.is_control()) {
+        return None;
+    }
+    let names = host
+        .split(',')
+        .map(|name| {
+            if name.is_ascii() {
+                Some(name.to_ascii_lowercase())
+            } else {
+                // Hyphens as in `r3---sn` are valid hostnames, not IDNA errors.
+                idna::Config::default()
+                    .check_hyphens(false)
+                    .to_ascii(name)
+                    .ok()
+            }
+        })
+        .collect::<Option<Vec<_>>>()?;
+    Some(names.join(","))
+}
+
+/// Splits a trailing `:port` off `host`, as clients copying `host:443`
+/// from a URL send. `None` when the port is not a number from 1 to 65535.
+fn strip_port(host: &str) -> Option<(&str, Option<u16>)> {
+    let (name, port) = match host.rsplit_once(':') {
+        Some(split) => split,
+        None => return Some((host, None)),
+    };
+    if port.is_empty() || !port.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    match port.parse() {
+        Ok(0) | Err(_) => None,
+        Ok(port) => Some((name, Some(port))),
+    }
+}
+
+fn validate_host(s: &str) -> bool {
+    validate_name(s, false)
+}
+
+/// Like `validate_host`, optionally accepting `_` as in `_443._tcp`.
+fn validate_name(s: &str, underscore: bool) -> bool {
+    if s.len() < 3 || s.len() > 255 {
+        return false;
+    }
+    let mut prev_c = '.';
+    let mut has_dot = false;
+    for c in s.chars() {
+        if c == '.' {
+            if prev_c == '.' || prev_c == '-' {
+                return false;
+            }
+            has_dot = true;
+        } else if c == '-' {
+            if prev_c == '.' {
+                return false;
+            }
+        } else if !(c.is_ascii_alphanumeric() || underscore && c == '_') {
+            return false;
+        }
+        prev_c = c;
+    }
+    if prev_c == '.' || prev_c == '-' || !has_dot {
+        return false;
+    }
+    true
+}
+
+pub fn server(state: State) -> tide::Server<State> {
+    let mut app = tide::with_state(state);
+    let opts = app.state().opts.clone();
+    if opts.cors_enabled {
+        app.with(cors::Cors {
+            allow_private_network: opts.allow_private_network,
+        });
+    }
+    app.with(
+        CompressMiddleware::builder()
+            .threshold(opts.compress_min_bytes)
+            .build(),
+    );
+    app.with(pretty::Pretty);
+    app.with(params::Adjusted);
+    app.with(contract::Contract);
+    if opts.verbose_errors {
+        app.with(attempts::Attempts);
+    }
+    app.at("/ping").get(|_| async { Ok("OK") });
+    app.at("/metrics").get(metrics);
+    app.at("/version").get(version);
+    app.at("/ready").get(ready);
+    app.at("/robots.txt").get(robots_txt);
+    app.at("/admin/upstreams").get(admin_upstreams);
+    app.at("/stats/zones").get(zones::handler);
+    app.at("/openapi.json").get(openapi::handler);
+    if opts.enable_ui {
+        app.at("/").get(ui::handler);
+    }
+    deadline(&mut app.at("/r/:host"), "/r", opts.route_timeout).get(resolve);
+    deadline(&mut app.at("/rr/:host"), "/rr", opts.batch_route_timeout).get(resolve_reverse);
+    deadline(&mut app.at("/x/:host"), "/x", opts.route_timeout).get(exists);
+    deadline(
+        &mut app.at("/probe/:host"),
+        "/probe",
+        opts.batch_route_timeout,
+    )
+    .get(probe::handler);
+    // Streaming, bounded by ws_idle_timeout and the wire read timeout instead.
+    app.at("/axfr/:zone").get(axfr::handler);
+    app.at("/ws").get(ws::handler());
+    app
+}
+
+fn deadline<'r, 'a>(
+    route: &'r mut tide::Route<'a, State>,
+    name: &'static str,
+    after: Option<Duration>,
+) -> &'r mut tide::Route<'a, State> {
+    route.with(deadline::Deadline { route: name, after })
+}
+
+/// Upstream for a `DNS` entry over the transports of `proto`. Racing
+/// keeps a resolver per transport, each with a cache of its own.
+fn upstream(
+    dns: &str,
+    cache_size: usize,
+    shards: usize,
+    proto: Proto,
+) -> Result<Arc<dyn Upstream>, String> {
+    Ok(match proto {
+        Proto::Udp => Arc::new(upstream_resolver(dns, cache_size, shards)?),
+        Proto::Tcp => Arc::new(resolver_over(dns, cache_size, shards, true)?),
+        Proto::Race => Arc::new(upstream::Race::new(
+            Arc::new(upstream_resolver(dns, cache_size, shards)?),
+            Arc::new(resolver_over(dns, cache_size, shards, true)?),
+        )),
+    })
+}
+
+/// Resolver for a `DNS` entry, `ip:port`, its cache of `cache_size`
+/// answers split in `shards`.
+fn upstream_resolver(
+    dns: &str,
+    cache_size: usize,
+    shards: usize,
+) -> Result<upstream::Resolver, String> {
+    resolver_over(dns, cache_size, shards, false)
+}
+
+/// `upstream_resolver`, over TCP only if `tcp`.
+fn resolver_over(
+    dns: &str,
+    cache_size: usize,
+    shards: usize,
+    tcp: bool,
+) -> Result<upstream::Resolver, String> {
+    let addr = match dns.rsplit_once(':') {
+        Some((ip, port)) => match (ip.parse(), port.parse()) {
+            (Ok(ip), Ok(port)) => SocketAddr::new(ip, port),
+            _ => return Err(format!("invalid {}: {}", ENV_DNS, dns)),
+        },
+        None => return Err(format!("invalid {}: {}", ENV_DNS, dns)),
+    };
+    let mut name_servers = NameServerConfigGroup::from_ips_clear(&[addr.ip()], addr.port(), true);
+    if tcp {
+        name_servers.retain(|config| config.protocol == Protocol::Tcp);
+    }
+    let opts = ResolverOpts {
+        // Keeps CNAME records in A/AAAA answers for no_cname.
+        preserve_intermediates: true,
+        ..Default::default()
+    };
+    let shards = shard_cache_sizes(cache_size, cache_shards(shards, cache_size))
+        .into_iter()
+        .map(|cache_size| {
+            BoundResolver::new(
+                ResolverConfig::from_parts(None, vec![], name_servers.clone()),
+                ResolverOpts { cache_size, ..opts },
+                TokioHandle,
+            )
+            .map_err(|err| format!("failed to connect resolver {}: {}", dns, err))
+        })
+        .collect::<Result<_, _>>()?;
+    let resolver = upstream::Resolver::new(dns.into(), addr, shards);
+    Ok(if tcp { resolver.tcp() } else { resolver })
+}
+
+#[cfg(test)]
+pub mod tests {
+    use std::collections::HashSet;
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, Instant};
+
+    use data_encoding::{BASE64, HEXLOWER};
+    use rand::rngs::SmallRng;
+    use rand::SeedableRng;
+    use tide::http::{Method, Request, Response, Url};
+    use trust_dns_resolver::error::{ResolveError, ResolveErrorKind};
+    use trust_dns_resolver::proto::error::ProtoError;
+    use trust_dns_resolver::proto::op::{Message, ResponseCode};
+    use trust_dns_resolver::proto::rr::rdata::{HINFO, MX, NULL, TXT};
+    use trust_dns_resolver::proto::rr::{Name, RData, RecordType};
+
+    use crate::metrics::Metrics;
+    use crate::opts::{
+        parse_aliases, parse_allowlist, parse_type_limits, parse_types, LinkLocal, Opts,
+        ShuffleCache,
+    };
+    use crate::persist::Persisted;
+    use crate::upstream::mock::Mock;
+    use crate::upstream::{Pool, Upstream};
+    use crate::{
+        diagnostics, error_code, normalize_host, pick_random, server, upstream_resolver,
+        validate_host, validate_name, State, EMPTY_RETRY_DELAY, MAX_HOST_PARAM,
+    };
+
+    pub fn state(mock: Mock) -> State {
+        State {
+            resolver: Arc::new(mock),
+            rng: Arc::new(Mutex::new(SmallRng::seed_from_u64(0))),
+            metrics: Arc::new(Metrics::default()),
+            opts: Arc::new(Opts::default()),
+            filters: Default::default(),
+            hooks: Default::default(),
+            zones: None,
+            persisted: None,
+        }
+    }
+
+    pub(crate) async fn get(state: &State, path: &str) -> Response {
+        let url = Url::parse("http://localhost").unwrap().join(path).unwrap();
+        server(state.clone())
+            .respond(Request::new(Method::Get, url))
+            .await
+            .unwrap()
+    }
+
+    /// `get` with `token` as a bearer token.
+    async fn get_as(state: &State, path: &str, token: Option<&str>) -> Response {
+        let url = Url::parse("http://localhost").unwrap().join(path).unwrap();
+        let mut req = Request::new(Method::Get, url);
+        if let Some(token) = token {
+            req.insert_header("Authorization", format!("Bearer {}", token));
+        }
+        server(state.clone()).respond(req).await.unwrap()
+    }
+
+    #[test]
+    fn vhost() {
+        assert!(!validate_host(".o"));
+        assert!(!validate_host("..o"));
+        assert!(!validate_host("-asd.o"));
+        assert!(!validate_host("--asd.o"));
+        assert!(!validate_host("asd."));
+        assert!(!validate_host("asd.o."));
+        assert!(!validate_host("asd-"));
+        assert!(!validate_host("asd.o-"));
+        assert!(!validate_host("asd"));
+        assert!(!validate_host("asd-.o"));
+        assert!(!validate_host("asd.-o"));
+        assert!(!validate_host("asd..o"));
+        assert!(!validate_host("asd--o"));
+        assert!(!validate_host("-.o"));
+        assert!(!validate_host(".-o"));
+        assert!(validate_host("asd--asd.o"));
+        assert!(!validate_host("_smimecert.asd.o"));
+        assert!(validate_name(
+            "c93f1e400f26708f98cb19d936620da35eec8f72e57f9eec01c1afd6._smimecert.asd.o",
+            true
+        ));
+    }
+
+    #[test]
+    fn normalize() {
+        assert_eq!(normalize_host("One%2EExample").unwrap(), "one.example");
+        assert_eq!(
+            normalize_host("b%C3%BCcher.example").unwrap(),
+            "xn--bcher-kva.example"
+        );
+        assert_eq!(
+            normalize_host("r3---sn.example").unwrap(),
+            "r3---sn.example"
+        );
+        assert_eq!(
+            normalize_host("A.example,b%2Cc.example").unwrap(),
+            "a.example,b,c.example"
+        );
+        assert_eq!(normalize_host("one%252Eexample"), None);
+        assert_eq!(normalize_host("one%00.example"), None);
+        assert_eq!(normalize_host("one.example%0A"), None);
+        assert_eq!(normalize_host("one%FF.example"), None);
+        assert_eq!(normalize_host(&"a%2E".repeat(MAX_HOST_PARAM)), None);
+        assert!(!validate_host(&normalize_host("..%2Fmetrics").unwrap()));
+    }
+
+    #[async_std::test]
+    async fn encoded_host() {
+        let state = state(Mock::default().ips("one.example", &["192.0.2.1"]));
+        let mut res = get(&state, "/r/ONE%2eexample").await;
+        assert_eq!(res.status(), 200);
+        assert_eq!(res.body_string().await.unwrap(), "192.0.2.1");
+        assert_eq!(get(&state, "/x/one%2Eexample").await.status(), 200);
+        for path in [
+            "/r/..%2Fmetrics",
+            "/r/one.example%2F..%2F..%2Fmetrics",
+            "/x/..%2F..%2Fping",
+            "/r/one%252Eexample",
+            "/r/one%09.example",
+        ] {
+            assert_eq!(get(&state, path).await.status(), 400, "{}", path);
+        }
+        let long = format!("/r/{}.example", "a".repeat(300));
+        assert_eq!(get(&state, &long).await.status(), 400);
+    }
+
+    #[async_std::test]
+    async fn reachable() {
+        let listener = async_std::net::TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let mut state = state(
+            Mock::default()
+                .ips("one.example", &["127.0.0.2", "127.0.0.1"])
+                .ips("down.example", &["127.0.0.2"]),
+        );
+        let path = format!("/r/one.example?reachable={}", port);
+        let mut res = get(&state, &path).await;
+        assert_eq!(res.status(), 403);
+        assert_eq!(res.body_string().await.unwrap(), "probe_disabled");
+        state.opts = Arc::new(Opts {
+            enable_probe: true,
+            ..Opts::default()
+        });
+        // Nothing listens on 127.0.0.2, the connection is refused.
+        let mut res = get(&state, &path).await;
+        assert_eq!(res.status(), 200);
+        assert_eq!(res.body_string().await.unwrap(), "127.0.0.1");
+        let path = format!("/r/down.example?reachable={}", port);
+        let mut res = get(&state, &path).await;
+        assert_eq!(res.status(), 404);
+        assert_eq!(res.body_string().await.unwrap(), "unreachable");
+        for path in [
+            "/r/one.example?reachable=0",
+            "/r/one.example?reachable=443&t=PTR",
+            "/r/one.example,down.example?reachable=443",
+        ] {
+            assert_eq!(get(&state, path).await.status(), 400, "{}", path);
+        }
+    }
+
+    #[async_std::test]
+    async fn label() {
+        let state = state(
+            Mock::default()
+                .ips("one.example", &["192.0.2.1", "2001:db8::1"])
+                .ips("two.example", &["192.0.2.2"]),
+        );
+        let mut res = get(&state, "/r/one.example?r=0&label=1").await;
+        assert_eq!(
+            res.body_string().await.unwrap(),
+            "A\t192.0.2.1\nAAAA\t2001:db8::1"
+        );
+        let mut res = get(&state, "/r/one.example?r=0").await;
+        assert_eq!(res.body_string().await.unwrap(), "192.0.2.1\n2001:db8::1");
+        let mut res = get(&state, "/r/one.example,two.example?r=0&label=1").await;
+        assert_eq!(
+            res.body_string().await.unwrap(),
+            "A\t192.0.2.1\nAAAA\t2001:db8::1\nA\t192.0.2.2"
+        );
+        let mut res = get(&state, "/r/one.example?label=1&format=json").await;
+        let body: serde_json::Value = res.body_json().await.unwrap();
+        assert_eq!(body["answers"].as_array().unwrap().len(), 2);
+    }
+
+    #[async_std::test]
+    async fn offset() {
+        let ips = [
+            "192.0.2.1",
+            "192.0.2.2",
+            "192.0.2.3",
+            "192.0.2.4",
+            "192.0.2.5",
+        ];
+        let state = state(Mock::default().ips("one.example", &ips));
+        let mut res = get(&state, "/r/one.example?r=0&n=2").await;
+        assert_eq!(res.body_string().await.unwrap(), "192.0.2.1\n192.0.2.2");
+        assert_eq!(
+            res["Link"],
+            "</r/one.example?r=0&n=2&offset=2>; rel=\"next\""
+        );
+        let mut res = get(&state, "/r/one.example?r=0&offset=2&n=2").await;
+        assert_eq!(res.body_string().await.unwrap(), "192.0.2.3\n192.0.2.4");
+        assert_eq!(
+            res["Link"],
+            "</r/one.example?r=0&n=2&offset=4>; rel=\"next\""
+        );
+        let mut res = get(&state, "/r/one.example?r=0&offset=4&n=2").await;
+        assert_eq!(res.body_string().await.unwrap(), "192.0.2.5");
+        assert!(res.header("Link").is_none());
+        assert!(get(&state, "/r/one.example").await.header("Link").is_none());
+        let res = get(&state, "/r/one.example?offset=5").await;
+        assert_eq!(res.status(), 416);
+        let res = get(&state, "/r/one.example?offset=1&pick=1").await;
+        assert_eq!(res.status(), 400);
+        let res = get(&state, "/r/one.example,two.example?offset=1").await;
+        assert_eq!(res.status(), 400);
+    }
+
+    #[async_std::test]
+    async fn admin_upstreams() {
+        let state = state(Mock::default());
+        let mut res = get(&state, "/admin/upstreams").await;
+        assert_eq!(res.status(), 200);
+        assert_eq!(
+            res.body_string().await.unwrap(),
+            r#"{"upstreams":[{"name":"mock"}]}"#
+        );
+
+        let mut state = state;
+        state.resolver = Arc::new(upstream_resolver("127.0.0.1:5353", 16, 1).unwrap());
+        let body: serde_json::Value = get(&state, "/admin/upstreams")
+            .await
+            .body_json()
+            .await
+            .unwrap();
+        let upstream = &body["upstreams"][0];
+        assert_eq!(upstream["name"], "127.0.0.1:5353");
+        // No UPSTREAM_BIND_ADDR nor BIND_INTERFACE in tests.
+        assert_eq!(upstream["bind_addrs"], serde_json::json!([]));
+        assert!(upstream.get("interface").is_none());
+    }
+
+    #[test]
+    fn pick_uniform() {
+        const ITEMS: usize = 10;
+        const K: usize = 3;
+        const ROUNDS: usize = 100_000;
+        let mut rng = SmallRng::seed_from_u64(42);
+        let mut counts = [0usize; ITEMS];
+        for _ in 0..ROUNDS {
+            let mut items = (0..ITEMS).collect::<Vec<_>>();
+            pick_random(&mut items, K, &mut rng);
+            assert_eq!(items.len(), K);
+            let mut sorted = items.clone();
+            sorted.sort_unstable();
+            sorted.dedup();
+            assert_eq!(sorted.len(), K);
+            for v in items {
+                counts[v] += 1;
+            }
+        }
+        let expected = (ROUNDS * K / ITEMS) as f64;
+        for &count in &counts {
+            assert!((count as f64 - expected).abs() / expected < 0.02);
+        }
+        let mut items = vec![1, 2];
+        pick_random(&mut items, 5, &mut rng);
+        assert_eq!(items.len(), 2);
+    }
+
+    #[async_std::test]
+    async fn resolve() {
+        let state = state(Mock::default().ips("one.example", &["192.0.2.1"]));
+        let mut res = get(&state, "/r/one.example").await;
+        assert_eq!(res.status(), 200);
+        assert_eq!(res.body_string().await.unwrap(), "192.0.2.1");
+        let res = get(&state, "/r/one.example?n=1&pick=1").await;
+        assert_eq!(res.status(), 400);
+        let mut res = get(&state, "/r/one.example?trailing_newline=1").await;
+        assert_eq!(res.body_string().await.unwrap(), "192.0.2.1\n");
+        let mut res = get(&state, "/r/one.example?format=json").await;
+        let body: serde_json::Value = res.body_json().await.unwrap();
+        assert_eq!(body["answers"][0]["data"], "192.0.2.1");
+        let res = get(&state, "/r/one.example?t=BOGUS").await;
+        assert_eq!(res.status(), 400);
+    }
+
+    #[async_std::test]
+    async fn family() {
+        let state = state(
+            Mock::default()
+                .ips("dual.example", &["192.0.2.1", "2001:db8::1"])
+                .ips("v4.example", &["192.0.2.2"]),
+        );
+        let mut res = get(&state, "/r/dual.example?prefer=6").await;
+        assert_eq!(res["X-Family"], "6");
+        assert_eq!(res.body_string().await.unwrap(), "2001:db8::1");
+        let mut res = get(&state, "/r/v4.example?prefer=6").await;
+        assert_eq!(res["X-Family"], "4");
+        assert_eq!(res.body_string().await.unwrap(), "192.0.2.2");
+        let res = get(&state, "/r/v4.example?f=6").await;
+        assert_eq!(res.status(), 404);
+        let res = get(&state, "/r/none.example?prefer=4").await;
+        assert_eq!(res.status(), 404);
+        let res = get(&state, "/r/v4.example?f=4&prefer=6").await;
+        assert_eq!(res.status(), 400);
+    }
+
+    #[async_std::test]
+    async fn no_cname() {
+        let state = state(
+            Mock::default()
+                .cname("www.example", "web.example")
+                .ips("web.example", &["192.0.2.1"]),
+        );
+        let mut res = get(&state, "/r/www.example").await;
+        assert_eq!(res.status(), 200);
+        assert_eq!(res.body_string().await.unwrap(), "192.0.2.1");
+        let mut res = get(&state, "/r/www.example?no_cname=1").await;
+        assert_eq!(res.status(), 409);
+        assert_eq!(res.body_string().await.unwrap(), "cname");
+        let res = get(&state, "/r/web.example?no_cname=1").await;
+        assert_eq!(res.status(), 200);
+    }
+
+    #[async_std::test]
+    async fn unicode() {
+        let mut state = state(
+            Mock::default()
+                .cname("xn--mnchen-3ya.example", "xn--caf-dma.example.")
+                .ips("xn--caf-dma.example", &["192.0.2.1"]),
+        );
+        state.opts = Arc::new(Opts {
+            allowed_types: None,
+            ..Opts::default()
+        });
+        let mut res = get(
+            &state,
+            "/r/xn--mnchen-3ya.example?t=CNAME&format=json&unicode=1",
+        )
+        .await;
+        let body: serde_json::Value = res.body_json().await.unwrap();
+        assert_eq!(body["unicode_name"], "münchen.example");
+        assert_eq!(body["answers"][0]["ascii_name"], "xn--caf-dma.example.");
+        assert_eq!(body["answers"][0]["unicode_name"], "café.example.");
+    }
+
+    #[async_std::test]
+    async fn exists() {
+        let state = state(
+            Mock::default()
+                .ips("one.example", &["192.0.2.1"])
+                .negative_ttl(60),
+        );
+        let mut res = get(&state, "/x/one.example").await;
+        assert_eq!(res.status(), 200);
+        assert_eq!(res.body_string().await.unwrap(), "xx");
+        let mut res = get(&state, "/x/one.example?ttl=1").await;
+        assert_eq!(res.body_string().await.unwrap(), "300");
+        let mut res = get(&state, "/x/none.example").await;
+        assert_eq!(res.status(), 404);
+        assert_eq!(res.body_string().await.unwrap(), "nx");
+        let mut res = get(&state, "/x/none.example?ttl=1").await;
+        assert_eq!(res.status(), 404);
+        assert_eq!(res.body_string().await.unwrap(), "60");
+        assert_eq!(res["X-Rcode"], "NXDOMAIN");
+    }
+
+    #[async_std::test]
+    async fn exists_type() {
+        let mx = RData::MX(MX::new(10, Name::from_ascii("mx.example.").unwrap()));
+        let mut state = state(
+            Mock::default()
+                .rdata("mail.example", vec![mx])
+                .ips("v6.example", &["2001:db8::1"]),
+        );
+        assert_eq!(get(&state, "/x/mail.example?t=MX").await.status(), 403);
+        state.opts = Arc::new(Opts {
+            allowed_types: None,
+            ..Opts::default()
+        });
+        let mut res = get(&state, "/x/mail.example?t=MX").await;
+        assert_eq!(res.status(), 200);
+        assert_eq!(res.body_string().await.unwrap(), "xx");
+        // NODATA, told apart from NXDOMAIN by X-Rcode.
+        for path in ["/x/mail.example", "/x/mail.example?t=A"] {
+            let mut res = get(&state, path).await;
+            assert_eq!(res.status(), 404);
+            assert_eq!(res["X-Rcode"], "NOERROR");
+            assert_eq!(res.body_string().await.unwrap(), "nx");
+        }
+        // Without t, only A records count.
+        assert_eq!(get(&state, "/x/v6.example").await.status(), 404);
+        assert_eq!(get(&state, "/x/v6.example?t=AAAA").await.status(), 200);
+        let res = get(&state, "/x/none.example?t=MX").await;
+        assert_eq!(res["X-Rcode"], "NXDOMAIN");
+        assert_eq!(get(&state, "/x/mail.example?t=BOGUS").await.status(), 400);
+        assert_eq!(
+            get(&state, "/x/_dmarc.mail.example?t=TXT").await.status(),
+            404
+        );
+        assert_eq!(get(&state, "/x/_dmarc.mail.example").await.status(), 400);
+    }
+
+    #[async_std::test]
+    async fn link_local() {
+        let mut state = state(
+            Mock::default()
+                .ips("v6.example", &["2001:db8::1", "fd00::1", "fe80::1"])
+                .ips("two.example", &["fe80::2"]),
+        );
+        let mut res = get(&state, "/r/v6.example?r=0").await;
+        assert_eq!(res.body_string().await.unwrap(), "2001:db8::1\nfd00::1");
+        let res = get(&state, "/r/two.example").await;
+        assert_eq!(res.status(), 404);
+
+        state.opts = Arc::new(Opts {
+            link_local: LinkLocal::Keep,
+            ..Opts::default()
+        });
+        let mut res = get(&state, "/r/v6.example?r=0").await;
+        assert_eq!(
+            res.body_string().await.unwrap(),
+            "2001:db8::1\nfd00::1\nfe80::1"
+        );
+        let mut res = get(&state, "/r/v6.example?r=0&format=json").await;
+        let body: serde_json::Value = res.body_json().await.unwrap();
+        assert!(body["answers"][2].get("scope").is_none());
+
+        state.opts = Arc::new(Opts {
+            link_local: LinkLocal::Annotate,
+            ..Opts::default()
+        });
+        let mut res = get(&state, "/r/v6.example?r=0&label=1").await;
+        assert_eq!(
+            res.body_string().await.unwrap(),
+            "AAAA\t2001:db8::1\nAAAA\tfd00::1\nAAAA\tfe80::1\tlink-local"
+        );
+        let mut res = get(&state, "/r/v6.example?r=0&format=json").await;
+        let body: serde_json::Value = res.body_json().await.unwrap();
+        let scopes = body["answers"].as_array().unwrap().iter();
+        let scopes = scopes.map(|answer| answer.get("scope").cloned());
+        assert_eq!(
+            scopes.collect::<Vec<_>>(),
+            [None, None, Some("link-local".into())]
+        );
+        let mut res = get(&state, "/r/v6.example,two.example").await;
+        assert_eq!(
+            res.body_string().await.unwrap(),
+            "2001:db8::1\nfd00::1\nfe80::1\tlink-local\nfe80::2\tlink-local"
+        );
+        let mut res = get(&state, "/r/v6.example,two.example?format=json&keyed=1").await;
+        let body: serde_json::Value = res.body_json().await.unwrap();
+        assert!(body["v6.example"][0].get("scope").is_none());
+        assert_eq!(body["two.example"][0]["scope"], "link-local");
+    }
+
+    #[async_std::test]
+    async fn cache_status() {
+        let fresh = Mock::default().ips("one.example", &["192.0.2.1"]);
+        let state = state(fresh);
+        let res = get(&state, "/r/one.example").await;
+        assert_eq!(res["X-Cache"], "MISS");
+        assert!(get(&state, "/r/none.example")
+            .await
+            .header("X-Cache")
+            .is_none());
+
+        let cached = Mock::default()
+            .ips("one.example", &["192.0.2.1"])
+            .valid_until(Instant::now() + Duration::from_secs(100));
+        let state = super::tests::state(cached);
+        let res = get(&state, "/r/one.example?t=A").await;
+        assert_eq!(res["X-Cache"], "HIT");
+
+        let path = std::env::temp_dir().join(format!("bdns-stale-{}.json", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let saved = Persisted::load(
+            Arc::new(Mock::default().ips("one.example", &["192.0.2.1"])),
+            &path,
+        );
+        saved.lookup("one.example", RecordType::A).await.unwrap();
+        saved.save().unwrap();
+        let persisted = Arc::new(Persisted::load(
+            Arc::new(Mock::default().ips("two.example", &["192.0.2.2"])),
+            &path,
+        ));
+        let mut state = super::tests::state(Mock::default());
+        state.resolver = persisted.clone();
+        state.persisted = Some(persisted);
+        let res = get(&state, "/r/one.example?t=A").await;
+        assert_eq!(res["X-Cache"], "STALE");
+        let res = get(&state, "/r/one.example").await;
+        assert_eq!(res["X-Cache"], "STALE");
+        let res = get(&state, "/r/two.example?t=A").await;
+        assert_eq!(res["X-Cache"], "MISS");
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[async_std::test]
+    async fn reverse() {
+        let state = state(
+            Mock::default()
+                .ips("two.example", &["192.0.2.1", "192.0.2.2"])
+                .ptr("192.0.2.1", &["one.example."]),
+        );
+        let mut res = get(&state, "/rr/two.example?r=0").await;
+        assert_eq!(res.status(), 200);
+        let body: serde_json::Value = res.body_json().await.unwrap();
+        assert_eq!(
+            body,
+            serde_json::json!([
+                {"ip": "192.0.2.1", "ptr": ["one.example."]},
+                {"ip": "192.0.2.2", "ptr": []},
+            ]),
+        );
+        let res = get(&state, "/rr/none.example").await;
+        assert_eq!(res.status(), 404);
+    }
+
+    #[async_std::test]
+    async fn name_filter() {
+        let mut state = state(
+            Mock::default()
+                .ips("two.example", &["192.0.2.1", "192.0.2.2"])
+                .ptr("192.0.2.1", &["web.example.com.", "mail.example.net."])
+                .ptr("192.0.2.2", &["db.example.org."]),
+        );
+        state.opts = Arc::new(Opts {
+            allowed_types: None,
+            ..Opts::default()
+        });
+        let ptr = "/r/1.2.0.192.in-addr.arpa?t=PTR&r=0";
+        let mut res = get(&state, &format!("{}&filter=%5C.com%24", ptr)).await;
+        assert_eq!(res.status(), 200);
+        assert_eq!(res.body_string().await.unwrap(), "web.example.com.");
+        let mut res = get(&state, &format!("{}&filter=EXAMPLE&n=1", ptr)).await;
+        assert_eq!(res.body_string().await.unwrap(), "web.example.com.");
+        let mut res = get(&state, &format!("{}&filter=%5C.io%24", ptr)).await;
+        assert_eq!(res.status(), 404);
+        assert_eq!(res.body_string().await.unwrap(), "nx");
+        let mut res = get(&state, &format!("{}&filter=(", ptr)).await;
+        assert_eq!(res.status(), 400);
+        assert_eq!(res.body_string().await.unwrap(), "bad_filter");
+        let res = get(&state, "/r/two.example?filter=example").await;
+        assert_eq!(res.status(), 400);
+        let res = get(&state, "/r/two.example,one.example?t=PTR&filter=a").await;
+        assert_eq!(res.status(), 400);
+
+        let mut res = get(&state, "/rr/two.example?r=0&filter=%5C.org%24").await;
+        let body: serde_json::Value = res.body_json().await.unwrap();
+        assert_eq!(
+            body,
+            serde_json::json!([
+                {"ip": "192.0.2.1", "ptr": []},
+                {"ip": "192.0.2.2", "ptr": ["db.example.org."]},
+            ]),
+        );
+        let res = get(&state, "/rr/two.example?filter=%5C.io%24").await;
+        assert_eq!(res.status(), 404);
+        let res = get(&state, "/rr/two.example?filter=(").await;
+        assert_eq!(res.status(), 400);
+    }
+
+    #[async_std::test]
+    async fn allowed_types() {
+        let mut state = state(Mock::default().ips("one.example", &["192.0.2.1"]));
+        let mut res = get(&state, "/r/one.example?t=TXT").await;
+        assert_eq!(res.status(), 403);
+        assert_eq!(res.body_string().await.unwrap(), "type_not_allowed");
+        assert!(!state.metrics.render().contains("bdns_lookups_total{"));
+        let mut res = get(&state, "/version").await;
+        let body: serde_json::Value = res.body_json().await.unwrap();
+        assert_eq!(
+            body["allowed_types"],
+            serde_json::json!(["A", "AAAA", "PTR"])
+        );
+        state.opts = Arc::new(Opts {
+            allowed_types: parse_types("A"),
+            ..Opts::default()
+        });
+        assert_eq!(get(&state, "/r/one.example?f=4").await.status(), 200);
+        assert_eq!(get(&state, "/r/one.example").await.status(), 403);
+        assert_eq!(get(&state, "/x/one.example").await.status(), 200);
+        assert_eq!(get(&state, "/x/one.example?t=AAAA").await.status(), 403);
+
+        state.opts = Arc::new(Opts {
+            allowed_types: parse_types("A"),
+            auth_token: Some("secret".into()),
+            admin_bypass_types: true,
+            ..Opts::default()
+        });
+        for token in [None, Some("secreT")] {
+            let res = get_as(&state, "/r/one.example", token).await;
+            assert_eq!(res.status(), 403);
+        }
+        assert_eq!(
+            get_as(&state, "/r/one.example", Some("secret"))
+                .await
+                .status(),
+            200
+        );
+        assert_eq!(
+            get_as(&state, "/x/one.example?t=AAAA", Some("secret"))
+                .await
+                .status(),
+            404
+        );
+        state.opts = Arc::new(Opts {
+            allowed_types: parse_types("A"),
+            auth_token: Some("secret".into()),
+            ..Opts::default()
+        });
+        assert_eq!(
+            get_as(&state, "/r/one.example", Some("secret"))
+                .await
+                .status(),
+            403
+        );
+        assert_eq!(parse_types(" * "), None);
+    }
+
+    #[async_std::test]
+    async fn compress() {
+        let mut state = state(Mock::default());
+        state.opts = Arc::new(Opts {
+            compress_min_bytes: 16,
+            ..Opts::default()
+        });
+        let app = server(state);
+        let request = |path: &str| {
+            let url = Url::parse("http://localhost").unwrap().join(path).unwrap();
+            let mut req = Request::new(Method::Get, url);
+            req.insert_header("Accept-Encoding", "gzip");
+            app.respond::<_, Response>(req)
+        };
+        let res = request("/version").await.unwrap();
+        assert_eq!(res["Content-Encoding"], "gzip");
+        let res = request("/ping").await.unwrap();
+        assert!(res.header("Content-Encoding").is_none());
+    }
+
+    #[async_std::test]
+    async fn any() {
+        let mx = MX::new(10, Name::from_ascii("mail.example.").unwrap());
+        let hinfo = HINFO::new("RFC8482".into(), "".into());
+        let mut state = state(
+            Mock::default()
+                .rdata(
+                    "one.example",
+                    vec![RData::A("192.0.2.1".parse().unwrap()), RData::MX(mx)],
+                )
+                .rdata("min.example", vec![RData::HINFO(hinfo)]),
+        );
+        assert_eq!(get(&state, "/r/one.example?t=any").await.status(), 403);
+        state.opts = Arc::new(Opts {
+            enable_any: true,
+            ..Opts::default()
+        });
+        let mut res = get(&state, "/r/one.example?t=any&r=0").await;
+        assert_eq!(res.status(), 200);
+        assert_eq!(
+            res.body_string().await.unwrap(),
+            "A 192.0.2.1\nMX 10 mail.example."
+        );
+        let res = get(&state, "/r/min.example?t=ANY").await;
+        assert_eq!(res.status(), 404);
+        assert_eq!(res["X-Any-Minimal"], "1");
+        let mut res = get(&state, "/r/one.example?t=any&r=0&format=csv").await;
+        assert_eq!(res.status(), 200);
+        assert_eq!(res["Content-Type"], "text/csv");
+        assert_eq!(
+            res.body_string().await.unwrap(),
+            "host,type,class,ttl,value\r\n\
+             one.example,A,IN,300,192.0.2.1\r\n\
+             one.example,MX,IN,300,10 mail.example.\r\n"
+        );
+    }
+
+    #[async_std::test]
+    async fn type_limits() {
+        let txt = (0..6).map(|i| RData::TXT(TXT::new(vec![format!("t{}", i)])));
+        let mut rdata = vec![
+            RData::A("192.0.2.1".parse().unwrap()),
+            RData::A("192.0.2.2".parse().unwrap()),
+        ];
+        rdata.extend(txt);
+        let type_limits = parse_type_limits("txt=3, A=16");
+        assert_eq!(type_limits.len(), 2);
+        assert_eq!(type_limits[&RecordType::TXT], 3);
+        let mut state = state(Mock::default().rdata("many.example", rdata));
+        state.opts = Arc::new(Opts {
+            enable_any: true,
+            type_limits,
+            ..Opts::default()
+        });
+        let mut res = get(&state, "/r/many.example?t=ANY&r=0&format=json&n=255").await;
+        let body: serde_json::Value = res.body_json().await.unwrap();
+        assert_eq!(body["answers"].as_array().unwrap().len(), 5);
+        assert_eq!(
+            body["types"],
+            serde_json::json!({
+                "A": { "total": 2, "truncated": false },
+                "TXT": { "total": 6, "truncated": true },
+            })
+        );
+        // The request may ask fewer than the server allows, not more.
+        let mut res = get(&state, "/r/many.example?t=ANY&r=0&limit_per_type=1&n=255").await;
+        assert_eq!(res.body_string().await.unwrap(), "A 192.0.2.1\nTXT \"t0\"");
+        let mut res = get(&state, "/r/many.example?t=ANY&r=0&limit_per_type=5&n=255").await;
+        let body = res.body_string().await.unwrap();
+        assert_eq!(body.lines().count(), 5);
+        // `n` still caps the total.
+        let mut res = get(&state, "/r/many.example?t=ANY&r=0&n=2").await;
+        assert_eq!(res.body_string().await.unwrap(), "A 192.0.2.1\nA 192.0.2.2");
+        // Shuffled or picked, any records of a type may be kept.
+        let mut kept = HashSet::new();
+        for _ in 0..20 {
+            let mut res = get(&state, "/r/many.example?t=ANY&limit_per_type=1&n=255").await;
+            let body = res.body_string().await.unwrap();
+            let txt = body
+                .lines()
+                .filter(|line| line.starts_with("TXT"))
+                .collect::<Vec<_>>();
+            assert_eq!(body.lines().count(), 2);
+            assert_eq!(txt.len(), 1);
+            kept.insert(txt[0].to_owned());
+        }
+        assert!(kept.len() > 1, "{:?}", kept);
+        let mut res = get(&state, "/r/many.example?t=ANY&pick=2&limit_per_type=1").await;
+        let body = res.body_string().await.unwrap();
+        assert!(body.lines().any(|line| line.starts_with("A ")), "{}", body);
+        assert!(
+            body.lines().any(|line| line.starts_with("TXT ")),
+            "{}",
+            body
+        );
+        let res = get(&state, "/r/many.example,one.example?limit_per_type=1").await;
+        assert_eq!(res.status(), 400);
+        state.opts = Arc::new(Opts {
+            enable_any: true,
+            ..Opts::default()
+        });
+        let mut res = get(&state, "/r/many.example?t=ANY&format=json").await;
+        let body: serde_json::Value = res.body_json().await.unwrap();
+        assert!(body.get("types").is_none());
+    }
+
+    #[async_std::test]
+    async fn batch() {
+        let state = state(
+            Mock::default()
+                .ips("one.example", &["192.0.2.1"])
+                .ips("two.example", &["192.0.2.2"]),
+        );
+        let mut res = get(&state, "/r/one.example,two.example").await;
+        assert_eq!(res.body_string().await.unwrap(), "192.0.2.1\n192.0.2.2");
+        let res = get(&state, "/r/one.example,-bad.example").await;
+        assert_eq!(res.status(), 400);
+        let mut res = get(
+            &state,
+            "/r/one.example,-bad.example,none.example?format=json&keyed=1",
+        )
+        .await;
+        let body: serde_json::Value = res.body_json().await.unwrap();
+        assert_eq!(body["one.example"][0]["data"], "192.0.2.1");
+        assert_eq!(
+            body["-bad.example"],
+            serde_json::json!({ "error": "bad_host" })
+        );
+        assert_eq!(body["none.example"], serde_json::json!({ "error": "nx" }));
+    }
+
+    #[async_std::test]
+    async fn dnssec_ok() {
+        let sig = [
+            0, 1, 13, 2, 0, 0, 1, 44, 0, 0, 0, 2, 0, 0, 0, 1, 0, 1, 3, b'o', b'n', b'e', 7, b'e',
+            b'x', b'a', b'm', b'p', b'l', b'e', 0, 0xab,
+        ];
+        let rrsig = RData::Unknown {
+            code: 46,
+            rdata: NULL::with(sig.to_vec()),
+        };
+        let a = RData::A("192.0.2.1".parse().unwrap());
+        let mut state = state(Mock::default().rdata("one.example", vec![a, rrsig]));
+        let res = get(&state, "/r/one.example?do=1").await;
+        assert_eq!(res.status(), 403);
+        state.opts = Arc::new(Opts {
+            enable_debug: true,
+            ..Opts::default()
+        });
+        let mut res = get(&state, "/r/one.example?do=1&t=A").await;
+        assert_eq!(res.status(), 200);
+        let body: serde_json::Value = res.body_json().await.unwrap();
+        assert_eq!(body["answers"][0]["data"], "192.0.2.1");
+        assert_eq!(body["answers"].as_array().unwrap().len(), 1);
+        assert_eq!(body["meta"]["rrsig"][0]["data"]["type_covered"], "A");
+        assert_eq!(get(&state, "/r/none.example?do=1").await.status(), 404);
+    }
+
+    #[async_std::test]
+    async fn debug_wire() {
+        let mut state = state(Mock::default().ips("one.example", &["192.0.2.1", "2001:db8::1"]));
+        let res = get_as(&state, "/r/one.example?debug=wire", Some("secret")).await;
+        assert_eq!(res.status(), 403);
+        state.opts = Arc::new(Opts {
+            debug_wire: true,
+            auth_token: Some("secret".into()),
+            ..Opts::default()
+        });
+        for token in [None, Some("secreT"), Some("secret2")] {
+            let res = get_as(&state, "/r/one.example?debug=wire", token).await;
+            assert_eq!(res.status(), 401);
+        }
+
+        let mut res = get_as(&state, "/r/one.example?debug=wire", Some("secret")).await;
+        let body = res.body_string().await.unwrap();
+        let message = Message::from_vec(&HEXLOWER.decode(body.as_bytes()).unwrap()).unwrap();
+        let answers = message.answers();
+        assert_eq!(answers.len(), 1);
+        assert_eq!(
+            answers[0].rdata().to_ip_addr().unwrap().to_string(),
+            "192.0.2.1"
+        );
+        let path = "/r/one.example?debug=wire&f=6&encoding=base64";
+        let mut res = get_as(&state, path, Some("secret")).await;
+        let body = res.body_string().await.unwrap();
+        let message = Message::from_vec(&BASE64.decode(body.as_bytes()).unwrap()).unwrap();
+        assert_eq!(message.answers()[0].rr_type(), RecordType::AAAA);
+        // Not formatted: NXDOMAIN is in the message.
+        let mut res = get_as(&state, "/r/none.example?debug=wire", Some("secret")).await;
+        assert_eq!(res.status(), 200);
+        let body = res.body_string().await.unwrap();
+        let message = Message::from_vec(&HEXLOWER.decode(body.as_bytes()).unwrap()).unwrap();
+        assert_eq!(message.response_code(), ResponseCode::NXDomain);
+
+        for path in [
+            "/r/one.example?debug=raw",
+            "/r/one.example?debug=wire&encoding=base32",
+            "/r/one.example?debug=wire&fast=1",
+            "/r/one.example,two.example?debug=wire",
+        ] {
+            let res = get_as(&state, path, Some("secret")).await;
+            assert_eq!(res.status(), 400, "{}", path);
+        }
+    }
+
+    #[async_std::test]
+    async fn stable_shuffle() {
+        let ips = [
+            "192.0.2.1",
+            "192.0.2.2",
+            "192.0.2.3",
+            "192.0.2.4",
+            "192.0.2.5",
+        ];
+        let order = |valid_until| async move {
+            let state = state(
+                Mock::default()
+                    .ips("many.example", &ips)
+                    .valid_until(valid_until),
+            );
+            let mut orders = Vec::new();
+            for _ in 0..5 {
+                let mut res = get(&state, "/r/many.example?stable_shuffle=1").await;
+                orders.push(res.body_string().await.unwrap());
+            }
+            orders
+        };
+        let now = Instant::now();
+        let mut fills = Vec::new();
+        for i in 0..4 {
+            let orders = order(now + Duration::from_secs(300 * i)).await;
+            assert!(orders.iter().all(|v| *v == orders[0]));
+            fills.push(orders[0].clone());
+        }
+        // Each refill reshuffles, four equal orders are 1 in 120^3.
+        assert!(fills.iter().any(|v| *v != fills[0]));
+    }
+
+    #[async_std::test]
+    async fn aliases() {
+        let aliases = parse_aliases(" DB=db1.internal.example., cache=db ,a=b,b=a");
+        assert_eq!(aliases["db"], "db1.internal.example");
+        let mut state = state(Mock::default().ips("db1.internal.example", &["192.0.2.1"]));
+        state.opts = Arc::new(Opts {
+            aliases,
+            ..Opts::default()
+        });
+        let mut res = get(&state, "/r/cache").await;
+        assert_eq!(res.status(), 200);
+        assert_eq!(res.body_string().await.unwrap(), "192.0.2.1");
+        let mut res = get(&state, "/r/a").await;
+        assert_eq!(res.status(), 508);
+        assert_eq!(res.body_string().await.unwrap(), "alias_loop");
+    }
+
+    #[async_std::test]
+    async fn dual_stack() {
+        let mock = Mock::default()
+            .ips("dual.example", &["2001:db8::1", "192.0.2.1"])
+            .ips("v6.example", &["2001:db8::2"])
+            .cname("alias.example", "dual.example")
+            .delay(Duration::from_millis(100));
+        let state = state(mock);
+        let start = Instant::now();
+        let mut res = get(&state, "/r/dual.example?r=0").await;
+        assert!(start.elapsed() < Duration::from_millis(180));
+        assert_eq!(res.body_string().await.unwrap(), "192.0.2.1\n2001:db8::1");
+        let mut res = get(&state, "/r/v6.example").await;
+        assert_eq!(res.body_string().await.unwrap(), "2001:db8::2");
+        let mut res = get(&state, "/r/alias.example?r=0").await;
+        assert_eq!(res.body_string().await.unwrap(), "192.0.2.1\n2001:db8::1");
+        assert_eq!(
+            get(&state, "/r/alias.example?no_cname=1").await.status(),
+            409
+        );
+        assert_eq!(get(&state, "/r/none.example").await.status(), 404);
+    }
+
+    /// Latency of the default path against A then AAAA one after the other,
+    /// with 20ms upstreams:
+    /// `cargo test --release dual_stack_latency -- --ignored --nocapture`
+    #[async_std::test]
+    #[ignore]
+    async fn dual_stack_latency() {
+        const LOOKUPS: u32 = 50;
+        let mock = Mock::default()
+            .ips("dual.example", &["192.0.2.1", "2001:db8::1"])
+            .delay(Duration::from_millis(20));
+        let state = state(mock);
+        let start = Instant::now();
+        for _ in 0..LOOKUPS {
+            for rtype in [RecordType::A, RecordType::AAAA] {
+                state.resolver.lookup("dual.example", rtype).await.unwrap();
+            }
+        }
+        println!("sequential: {:?}/lookup", start.elapsed() / LOOKUPS);
+        let start = Instant::now();
+        for _ in 0..LOOKUPS {
+            super::query_upstream(&state, "dual.example", None)
+                .await
+                .unwrap();
+        }
+        println!("concurrent: {:?}/lookup", start.elapsed() / LOOKUPS);
+    }
+
+    #[async_std::test]
+    async fn fast() {
+        let mock = Mock::default()
+            .ips("dual.example", &["192.0.2.1", "2001:db8::1"])
+            .ips("v6.example", &["2001:db8::2"])
+            .type_delay(RecordType::AAAA, Duration::from_millis(300));
+        let state = state(mock);
+        let start = Instant::now();
+        let mut res = get(&state, "/r/dual.example?fast=1&format=json").await;
+        assert!(start.elapsed() < Duration::from_millis(200));
+        assert_eq!(res["X-Family"], "4");
+        let body: serde_json::Value = res.body_json().await.unwrap();
+        assert_eq!(body["families"], serde_json::json!([4]));
+        assert_eq!(body["answers"].as_array().unwrap().len(), 1);
+        // The AAAA lookup left behind still completes.
+        async_std::task::sleep(Duration::from_millis(400)).await;
+        let metrics = state.metrics.render();
+        assert!(metrics.contains("bdns_lookups_total{type=\"AAAA\",outcome=\"hit\"} 1\n"));
+
+        let mut res = get(&state, "/r/v6.example?fast=1").await;
+        assert_eq!(res["X-Family"], "6");
+        assert_eq!(res.body_string().await.unwrap(), "2001:db8::2");
+        assert_eq!(get(&state, "/r/none.example?fast=1").await.status(), 404);
+        for path in [
+            "/r/dual.example?fast=1&t=A",
+            "/r/dual.example?fast=1&prefer=6",
+            "/r/dual.example,v6.example?fast=1",
+        ] {
+            assert_eq!(get(&state, path).await.status(), 400, "{}", path);
+        }
+
+        let state =
+            super::tests::state(Mock::default().ips("dual.example", &["2001:db8::1", "192.0.2.1"]));
+        let mut res = get(&state, "/r/dual.example?fast=1&r=0").await;
+        assert_eq!(res["X-Family"], "4,6");
+        assert_eq!(res.body_string().await.unwrap(), "192.0.2.1\n2001:db8::1");
+    }
+
+    #[async_std::test]
+    async fn strip_port() {
+        let mut state = state(Mock::default().ips("example.com", &["192.0.2.1"]));
+        assert_eq!(get(&state, "/r/example.com:8080").await.status(), 400);
+        state.opts = Arc::new(Opts {
+            strip_port: true,
+            ..Opts::default()
+        });
+        let mut res = get(&state, "/r/example.com:8080").await;
+        assert_eq!(res.status(), 200);
+        assert_eq!(res.body_string().await.unwrap(), "192.0.2.1");
+        let mut res = get(&state, "/r/example.com:443?format=json").await;
+        let body: serde_json::Value = res.body_json().await.unwrap();
+        assert_eq!(body["host"], "example.com");
+        assert_eq!(body["port"], 443);
+        let mut res = get(&state, "/r/example.com?format=json").await;
+        let body: serde_json::Value = res.body_json().await.unwrap();
+        assert!(body.get("port").is_none());
+        for path in [
+            "/r/example.com:notaport",
+            "/r/example.com:",
+            "/r/example.com:0",
+            "/r/example.com:65536",
+            "/r/example.com:+80",
+            "/r/example.com:80:80",
+        ] {
+            assert_eq!(get(&state, path).await.status(), 400, "{}", path);
+        }
+    }
+
+    #[async_std::test]
+    async fn allowlist() {
+        assert_eq!(parse_allowlist("", "example.com"), None);
+        let allowlist = parse_allowlist("allowlist", " Example.com., cdn.example.net");
+        assert_eq!(
+            allowlist.as_deref(),
+            Some(&["example.com".to_string(), "cdn.example.net".into()][..])
+        );
+        let mut state = state(
+            Mock::default()
+                .ips("example.com", &["192.0.2.1"])
+                .ips("www.example.com", &["192.0.2.2"])
+                .ips("notexample.com", &["192.0.2.3"])
+                .ips("example.net", &["192.0.2.4"]),
+        );
+        state.opts = Arc::new(Opts {
+            allowlist,
+            ..Opts::default()
+        });
+        assert_eq!(get(&state, "/r/example.com").await.status(), 200);
+        assert_eq!(get(&state, "/x/www.example.com").await.status(), 200);
+        for path in ["/r/notexample.com", "/r/example.net", "/x/example.net"] {
+            let mut res = get(&state, path).await;
+            assert_eq!(res.status(), 403, "{}", path);
+            assert_eq!(res.body_string().await.unwrap(), "host_not_allowed");
+        }
+        let mut res = get(&state, "/r/example.com,example.net?format=json&keyed=1").await;
+        let body: serde_json::Value = res.body_json().await.unwrap();
+        assert_eq!(body["example.net"]["error"], "host_not_allowed");
+        let metrics = state.metrics.render();
+        assert!(metrics.contains("bdns_allowlist_denied_total 4\n"));
+        // Refused before the upstream, so not counted as lookups.
+        assert!(metrics.contains("bdns_lookups_total{type=\"IP\",outcome=\"error\"} 0\n"));
+    }
+
+    #[async_std::test]
+    async fn shuffle_cache() {
+        let ips = [
+            "192.0.2.1",
+            "192.0.2.2",
+            "192.0.2.3",
+            "192.0.2.4",
+            "192.0.2.5",
+        ];
+        // Records of 300s, cached 180s ago.
+        let valid_until = Instant::now() + Duration::from_secs(121);
+        let mut state = state(
+            Mock::default()
+                .ips("many.example", &ips)
+                .valid_until(valid_until),
+        );
+        let res = get(&state, "/r/many.example?r=0").await;
+        assert!(res.header("Cache-Control").is_none());
+
+        state.opts = Arc::new(Opts {
+            cache_headers: true,
+            ..Opts::default()
+        });
+        let res = get(&state, "/r/many.example?r=0").await;
+        assert_eq!(res["Cache-Control"], "public, max-age=120");
+        assert!(res.header("Vary").is_none());
+        assert!(res.header("ETag").is_none());
+        let res = get(&state, "/r/many.example?stable_shuffle=1").await;
+        assert_eq!(res["Cache-Control"], "public, max-age=120");
+        for path in ["/r/many.example", "/r/many.example?r=0&pick=2"] {
+            let res = get(&state, path).await;
+            assert_eq!(res["Cache-Control"], "private, no-store");
+            assert!(res.header("Vary").is_none());
+        }
+        // Private mode ignores sessions.
+        let res = get_session(&state, "/r/many.example", "s1").await;
+        assert_eq!(res["Cache-Control"], "private, no-store");
+
+        state.opts = Arc::new(Opts {
+            cache_headers: true,
+            shuffle_cache: ShuffleCache::Edge,
+            ..Opts::default()
+        });
+        let res = get(&state, "/r/many.example").await;
+        assert_eq!(res["Cache-Control"], "private, no-store");
+        assert!(res.header("Vary").is_none());
+        assert!(res.header("ETag").is_none());
+        let mut first = get_session(&state, "/r/many.example", "s1").await;
+        assert_eq!(first["Cache-Control"], "public, max-age=120");
+        assert_eq!(first["Vary"], "X-Session");
+        let mut again = get_session(&state, "/r/many.example", "s1").await;
+        assert_eq!(first["ETag"].as_str(), again["ETag"].as_str());
+        assert_eq!(
+            first.body_string().await.unwrap(),
+            again.body_string().await.unwrap()
+        );
+        let other = get_session(&state, "/r/many.example", "s2").await;
+        assert_ne!(first["ETag"].as_str(), other["ETag"].as_str());
+    }
+
+    async fn get_session(state: &State, path: &str, session: &str) -> Response {
+        let url = Url::parse("http://localhost").unwrap().join(path).unwrap();
+        let mut req = Request::new(Method::Get, url);
+        req.insert_header("X-Session", session);
+        server(state.clone()).respond(req).await.unwrap()
+    }
+
+    #[async_std::test]
+    async fn robots_txt() {
+        let mut state = state(Mock::default());
+        let mut res = get(&state, "/robots.txt").await;
+        assert_eq!(res.status(), 200);
+        assert_eq!(
+            res.body_string().await.unwrap(),
+            "User-agent: *\nDisallow: /\n"
+        );
+        state.opts = Arc::new(Opts {
+            robots_txt: "User-agent: *\nAllow: /ping\n".into(),
+            ..Opts::default()
+        });
+        let mut res = get(&state, "/robots.txt").await;
+        assert_eq!(
+            res.body_string().await.unwrap(),
+            "User-agent: *\nAllow: /ping\n"
+        );
+    }
+
+    #[async_std::test]
+    async fn rng_poisoned() {
+        let state = state(Mock::default().ips("two.example", &["192.0.2.1", "192.0.2.2"]));
+        let rng = state.rng.clone();
+        let poisoner = std::thread::spawn(move || {
+            let _guard = rng.lock().unwrap();
+            panic!("poisoning the rng lock");
+        });
+        assert!(poisoner.join().is_err());
+        assert!(state.rng.is_poisoned());
+        for _ in 0..2 {
+            let res = get(&state, "/r/two.example").await;
+            assert_eq!(res.status(), 200);
+        }
+        assert!(!state.rng.is_poisoned());
+    }
+
+    #[async_std::test]
+    async fn max_response_bytes() {
+        let txt = |i: usize| RData::TXT(TXT::new(vec![format!("{}{}", i, "x".repeat(999))]));
+        let mut state = state(
+            Mock::default()
+                .rdata("big.example", (0..8).map(txt).collect())
+                .rdata("big2.example", (0..8).map(txt).collect()),
+        );
+        state.opts = Arc::new(Opts {
+            allowed_types: None,
+            max_response_bytes: 2500,
+            ..Opts::default()
+        });
+        let mut res = get(&state, "/r/big.example?t=TXT&r=0").await;
+        assert_eq!(res.status(), 200);
+        assert_eq!(res["X-Truncated"], "true");
+        assert_eq!(res["X-Answer-Count"], "2");
+        assert!(res.body_string().await.unwrap().len() <= 2500);
+        let mut res = get(&state, "/r/big.example?t=TXT&r=0&format=json").await;
+        let body: serde_json::Value = res.body_json().await.unwrap();
+        assert_eq!(body["truncated_reason"], "size");
+        assert_eq!(body["answers"].as_array().unwrap().len(), 2);
+        // The budget is shared by the whole batch.
+        let res = get(&state, "/r/big.example,big2.example?t=TXT&n=1").await;
+        assert_eq!(res["X-Answer-Count"], "2");
+        assert!(res.header("X-Truncated").is_none());
+        let mut res = get(&state, "/r/big.example,big2.example?t=TXT&n=2").await;
+        assert_eq!(res["X-Truncated"], "true");
+        assert_eq!(res["X-Answer-Count"], "2");
+        assert!(res.body_string().await.unwrap().starts_with("\"0x"));
+        let res = get(&state, "/r/big.example?t=TXT&n=2").await;
+        assert!(res.header("X-Truncated").is_none());
+    }
+
+    #[async_std::test]
+    async fn max_resolve_depth() {
+        let mut state = state(
+            Mock::default()
+                .cname("www.example", "edge.example.")
+                .cname("edge.example", "node.example.")
+                .ips("node.example", &["192.0.2.1"]),
+        );
+        state.opts = Arc::new(Opts {
+            aliases: parse_aliases(
+                "site.alias=www.example,a.loop=b.loop,b.loop=c.loop,c.loop=a.loop",
+            ),
+            max_resolve_depth: 3,
+            ..Opts::default()
+        });
+        // One alias and two CNAME hops.
+        let mut res = get(&state, "/r/site.alias").await;
+        assert_eq!(res.status(), 200);
+        assert_eq!(res.body_string().await.unwrap(), "192.0.2.1");
+        let res = get(&state, "/r/a.loop").await;
+        assert_eq!(res.status(), 508);
+        let mut res = get(&state, "/r/a.loop,site.alias?format=json&keyed=1").await;
+        let body: serde_json::Value = res.body_json().await.unwrap();
+        assert_eq!(body["a.loop"]["error"], "alias_loop");
+        assert_eq!(body["site.alias"][0]["data"], "192.0.2.1");
+
+        state.opts = Arc::new(Opts {
+            aliases: parse_aliases("db=node.example,bad=-bad.example"),
+            ..Opts::default()
+        });
+        // Dot-less keys are expanded before the names are validated.
+        let mut res = get(&state, "/r/db,www.example").await;
+        assert_eq!(res.status(), 200);
+        assert_eq!(res.body_string().await.unwrap(), "192.0.2.1\n192.0.2.1");
+        let mut res = get(&state, "/r/db,bad?format=json&keyed=1").await;
+        let body: serde_json::Value = res.body_json().await.unwrap();
+        assert_eq!(body["db"][0]["data"], "192.0.2.1");
+        assert_eq!(body["bad"]["error"], "bad_host");
+        assert_eq!(get(&state, "/r/db,bad").await.status(), 400);
+
+        state.opts = Arc::new(Opts {
+            aliases: parse_aliases("site.alias=www.example"),
+            max_resolve_depth: 2,
+            ..Opts::default()
+        });
+        let mut res = get(&state, "/r/site.alias").await;
+        assert_eq!(res.status(), 508);
+        assert_eq!(res.body_string().await.unwrap(), "alias_loop");
+        assert_eq!(get(&state, "/r/www.example").await.status(), 200);
+    }
+
+    #[async_std::test]
+    async fn test_hosts() {
+        let mut state = state(Mock::default().ips("nodata.example", &["192.0.2.1"]));
+        state.opts = Arc::new(Opts {
+            test_hosts: vec![
+                ("nodata.example".into(), ResponseCode::NoError),
+                ("servfail.example".into(), ResponseCode::ServFail),
+            ],
+            ..Default::default()
+        });
+        let res = get(&state, "/r/nodata.example").await;
+        assert_eq!(res.status(), 404);
+        let res = get(&state, "/x/NODATA.example").await;
+        assert_eq!(res.status(), 404);
+        let res = get(&state, "/r/servfail.example").await;
+        assert_eq!(res.status(), 502);
+        assert!(!state.metrics.render().contains("bdns_lookups_total{"));
+        assert!(res.header("Cache-Control").is_none());
+
+        state.opts = Arc::new(Opts {
+            test_hosts: vec![("nodata.example".into(), ResponseCode::NoError)],
+            cache_headers: true,
+            default_record_ttl: 30,
+            ..Default::default()
+        });
+        let res = get(&state, "/r/nodata.example").await;
+        assert_eq!(res.status(), 404);
+        assert_eq!(res["Cache-Control"], "public, max-age=30");
+        let mut res = get(&state, "/x/nodata.example?ttl=1").await;
+        assert_eq!(res.body_string().await.unwrap(), "30");
+    }
+
+    #[async_std::test]
+    async fn servfail() {
+        let state = state(Mock::default().rcode("broken.example", ResponseCode::ServFail));
+        let mut res = get(&state, "/r/broken.example").await;
+        assert_eq!(res.status(), 502);
+        assert_eq!(res.body_string().await.unwrap(), "servfail");
+        let metrics = state.metrics.render();
+        assert!(metrics.contains("bdns_servfail_total 1\n"));
+        assert!(metrics.contains("bdns_lookups_total{type=\"IP\",outcome=\"error\"} 1\n"));
+    }
+
+    #[async_std::test]
+    async fn empty_retry() {
+        // A and AAAA both empty once, then answered.
+        let mock = || {
+            Mock::default()
+                .ips("flaky.example", &["192.0.2.1"])
+                .empty("flaky.example", 2)
+        };
+        let mut state = state(mock());
+        state.opts = Arc::new(Opts {
+            empty_retry: true,
+            ..Default::default()
+        });
+        let mut res = get(&state, "/r/flaky.example").await;
+        assert_eq!(res.status(), 200);
+        assert_eq!(res.body_string().await.unwrap(), "192.0.2.1");
+        // Authoritative NXDOMAIN is not asked again.
+        let start = Instant::now();
+        assert_eq!(get(&state, "/r/none.example").await.status(), 404);
+        assert!(start.elapsed() < EMPTY_RETRY_DELAY);
+        let metrics = state.metrics.render();
+        assert!(metrics.contains("bdns_empty_retries_total 1\n"));
+        assert!(metrics.contains("bdns_lookups_total{type=\"IP\",outcome=\"hit\"} 1\n"));
+
+        let mut state = super::tests::state(mock().empty("flaky.example", 4));
+        state.opts = Arc::new(Opts {
+            empty_retry: true,
+            ..Default::default()
+        });
+        assert_eq!(get(&state, "/r/flaky.example").await.status(), 404);
+
+        let state = super::tests::state(mock());
+        assert_eq!(get(&state, "/r/flaky.example").await.status(), 404);
+        assert!(state
+            .metrics
+            .render()
+            .contains("bdns_empty_retries_total 0\n"));
+    }
+
+    #[async_std::test]
+    async fn verbose_errors() {
+        let broken = Mock::default().rcode("broken.example", ResponseCode::ServFail);
+        let mut state = state(Mock::default());
+        // An answer from the first upstream leaves the second one unasked.
+        state.resolver = Arc::new(Pool::new(
+            vec![Arc::new(broken), Arc::new(Mock::default().down())],
+            None,
+            Default::default(),
+        ));
+        state.opts = Arc::new(Opts {
+            verbose_errors: true,
+            ..Default::default()
+        });
+        let mut res = get(&state, "/r/broken.example?format=json&f=4").await;
+        assert_eq!(res.status(), 502);
+        let body: serde_json::Value = res.body_json().await.unwrap();
+        assert_eq!(body["error"], "servfail");
+        assert_eq!(body["kind"], "no_records");
+        assert_eq!(body["rcode"], "SERVFAIL");
+        assert_eq!(body["upstreams"], serde_json::json!(["mock"]));
+        assert_eq!(body["tries"], 1);
+        let mut res = get(&state, "/r/none.example?format=json").await;
+        assert_eq!(res.status(), 404);
+        let body: serde_json::Value = res.body_json().await.unwrap();
+        assert_eq!(
+            (&body["error"], &body["rcode"]),
+            (&"nx".into(), &"NXDOMAIN".into())
+        );
+        // Text answers keep the plain codes.
+        let mut res = get(&state, "/r/broken.example").await;
+        assert_eq!(res.body_string().await.unwrap(), "servfail");
+
+        let mut down = super::tests::state(Mock::default());
+        down.resolver = Arc::new(Pool::new(
+            vec![
+                Arc::new(Mock::default().down()),
+                Arc::new(Mock::default().down()),
+            ],
+            None,
+            Default::default(),
+        ));
+        down.opts = state.opts.clone();
+        let mut res = get(&down, "/r/one.example?format=json&f=4").await;
+        assert_eq!(res.status(), 500);
+        let body: serde_json::Value = res.body_json().await.unwrap();
+        assert_eq!(
+            (&body["error"], &body["kind"]),
+            (&"timeout".into(), &"timeout".into())
+        );
+        assert!(body["rcode"].is_null());
+        assert_eq!(body["upstreams"], serde_json::json!(["mock", "mock"]));
+        assert_eq!(body["tries"], 2);
+        let mut res = get(&down, "/rr/one.example").await;
+        assert_eq!(res.status(), 500);
+        let body: serde_json::Value = res.body_json().await.unwrap();
+        assert_eq!(body["kind"], "timeout");
+
+        let io = std::io::Error::new(std::io::ErrorKind::ConnectionReset, "reset");
+        let proto = ProtoError::from("bad label");
+        for (err, kind) in [
+            (ResolveError::from(io), "io"),
+            (ResolveError::from(proto), "protocol"),
+            (
+                ResolveErrorKind::Msg("no connections".into()).into(),
+                "message",
+            ),
+        ] {
+            let body = diagnostics(error_code(&state, "one.example", &err), &err);
+            assert_eq!(
+                (&body["error"], &body["kind"]),
+                (&"error".into(), &kind.into())
+            );
+            assert!(body["message"].is_string());
+        }
+
+        // Off by default, failures stay opaque.
+        let down = super::tests::state(Mock::default().down());
+        let res = get(&down, "/r/one.example?format=json").await;
+        assert_eq!(res.status(), 500);
+    }
+
+    #[async_std::test]
+    async fn ready() {
+        let mut state = state(
+            Mock::default()
+                .ips("one.example", &["192.0.2.1"])
+                .rcode("broken.example", ResponseCode::ServFail),
+        );
+        state.opts = Arc::new(Opts {
+            degraded_min_lookups: 4,
+            ..Default::default()
+        });
+        let mut res = get(&state, "/ready").await;
+        assert_eq!(res.status(), 200);
+        assert_eq!(res.body_string().await.unwrap(), "OK");
+        for path in ["/r/one.example", "/r/broken.example", "/r/broken.example"] {
+            get(&state, path).await;
+        }
+        // Too few lookups to judge.
+        assert_eq!(get(&state, "/ready").await.status(), 200);
+        get(&state, "/r/broken.example").await;
+        let mut res = get(&state, "/ready").await;
+        assert_eq!(res.status(), 503);
+        assert_eq!(res.body_string().await.unwrap(), "degraded");
+
+        let state = super::tests::state(Mock::default().down());
+        let mut res = get(&state, "/ready").await;
+        assert_eq!(res.status(), 503);
+        assert_eq!(res.body_string().await.unwrap(), "upstream_down");
+    }
+}
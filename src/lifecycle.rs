@@ -0,0 +1,401 @@
+use std::env;
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use futures_util::future::{BoxFuture, FutureExt};
+use rand::rngs::SmallRng;
+use rand::SeedableRng;
+use tide::listener::{Listener, ToListener};
+use tide::{Server, StatusCode};
+use tide_rustls::TlsListener;
+
+use crate::metrics::Metrics;
+use crate::opts::{
+    env_flag, get_opts, ARG_CHECK_CONFIG, ARG_PROBE, ENV_SELF_TEST, ENV_SELF_TEST_PROBE,
+};
+use crate::upstream::Upstream;
+use crate::{
+    bind, check, egress, filter, persist, server, shadow, upstream, upstream_resolver, zones,
+    AnswerFilter, State,
+};
+
+/// Shutdown hooks still running by then are abandoned.
+pub const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+
+type Hook<T> = Arc<dyn Fn(T) -> BoxFuture<'static, ()> + Send + Sync>;
+
+/// Callbacks of embedders around serving, each run in the order registered.
+#[derive(Clone, Default)]
+pub struct Hooks {
+    on_ready: Vec<Hook<Vec<SocketAddr>>>,
+    on_shutdown: Vec<Hook<()>>,
+}
+
+impl Hooks {
+    pub fn on_ready<F, Fut>(&mut self, hook: F)
+    where
+        F: Fn(Vec<SocketAddr>) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        self.on_ready
+            .push(Arc::new(move |addrs| hook(addrs).boxed()));
+    }
+
+    pub fn on_shutdown<F, Fut>(&mut self, hook: F)
+    where
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        self.on_shutdown.push(Arc::new(move |()| hook().boxed()));
+    }
+
+    pub fn has_shutdown(&self) -> bool {
+        !self.on_shutdown.is_empty()
+    }
+
+    async fn ready(&self, addrs: Vec<SocketAddr>) {
+        for hook in &self.on_ready {
+            hook(addrs.clone()).await;
+        }
+    }
+
+    /// Runs the shutdown hooks, for at most `SHUTDOWN_TIMEOUT` in total.
+    pub async fn shutdown(&self) {
+        let hooks = async {
+            for hook in &self.on_shutdown {
+                hook(()).await;
+            }
+        };
+        if async_std::future::timeout(SHUTDOWN_TIMEOUT, hooks)
+            .await
+            .is_err()
+        {
+            eprintln!("shutdown hooks timed out");
+        }
+    }
+}
+
+/// `State` from the environment, with the answer filters and lifecycle
+/// hooks of an embedder.
+#[derive(Default)]
+pub struct Builder {
+    filters: Vec<Arc<dyn AnswerFilter>>,
+    hooks: Hooks,
+}
+
+impl Builder {
+    /// Adds `filter` after the `ANSWER_FILTERS` and those already added,
+    /// so it gets their output.
+    pub fn filter(mut self, filter: Arc<dyn AnswerFilter>) -> Self {
+        self.filters.push(filter);
+        self
+    }
+
+    /// Runs `hook` with the addresses `serve` bound, port 0 resolved,
+    /// before serving, after those already added.
+    pub fn on_ready<F, Fut>(mut self, hook: F) -> Self
+    where
+        F: Fn(Vec<SocketAddr>) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        self.hooks.on_ready(hook);
+        self
+    }
+
+    /// Runs `hook` on SIGTERM or SIGINT, before persisting the cache and
+    /// exiting, after those already added.
+    pub fn on_shutdown<F, Fut>(mut self, hook: F) -> Self
+    where
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        self.hooks.on_shutdown(hook);
+        self
+    }
+
+    /// Reads the options from the environment and sets up the upstreams.
+    /// Upstream socket options are process-wide, so a process builds once.
+    pub fn build(self) -> tide::Result<State> {
+        let opts = get_opts();
+        if let Err(err) = bind::set(opts.upstream_bind_addrs.clone()) {
+            let addrs = format!("{:?}", opts.upstream_bind_addrs);
+            tide::log::error!("cannot bind upstream queries", { addrs: addrs, error: err.to_string() });
+            return Err(tide::Error::from_str(
+                StatusCode::InternalServerError,
+                format!("cannot bind {}: {}", addrs, err),
+            ));
+        }
+        if !opts.upstream_bind_addrs.is_empty() {
+            let addrs = format!("{:?}", opts.upstream_bind_addrs);
+            tide::log::info!("upstream queries bound", { addrs: addrs });
+        }
+        if let Some(name) = opts.bind_interface.clone() {
+            if let Err(err) = bind::set_interface(name.clone()) {
+                tide::log::error!("cannot bind upstream queries", { interface: name, error: err.to_string() });
+                return Err(tide::Error::from_str(
+                    StatusCode::InternalServerError,
+                    format!("cannot bind to interface {}: {}", name, err),
+                ));
+            }
+            tide::log::info!("upstream queries bound", { interface: name });
+        }
+        if let Some(ports) = opts.source_ports.clone() {
+            tide::log::info!("upstream UDP source ports", { ports: format!("{:?}", ports) });
+            bind::set_ports(ports)?;
+        }
+        if let Some(qps) = opts.upstream_qps {
+            tide::log::info!("upstream queries limited", { per_second: qps });
+            egress::set(egress::Throttle::new(qps, opts.upstream_qps_wait))?;
+        }
+        let mut upstreams: Vec<Arc<dyn Upstream>> = Vec::new();
+        for dns in &opts.dns {
+            if !dns.contains(':') {
+                continue;
+            }
+            let resolver = upstream(dns, opts.cache_size, opts.cache_shards, opts.proto)
+                .map_err(|err| tide::Error::from_str(StatusCode::InternalServerError, err))?;
+            upstreams.push(resolver);
+        }
+        if upstreams.is_empty() {
+            return Err(tide::Error::from_str(
+                StatusCode::InternalServerError,
+                "no upstreams",
+            ));
+        }
+        let metrics = Arc::new(Metrics::default());
+        let hedge = opts.hedge_after.map(|after| upstream::Hedge {
+            after,
+            max_ratio: opts.hedge_max_ratio,
+        });
+        let pool = Arc::new(upstream::Pool::new(
+            upstreams,
+            hedge,
+            metrics.hedges.clone(),
+        ));
+        let mut pool: Arc<dyn Upstream> = pool;
+        let rng = Arc::new(Mutex::new(SmallRng::from_entropy()));
+        if let (Some(dns), Some(sample)) = (&opts.shadow_dns, opts.shadow_sample) {
+            let shadow = upstream_resolver(dns, opts.cache_size, 1)
+                .map_err(|err| tide::Error::from_str(StatusCode::InternalServerError, err))?;
+            eprintln!("copying {} of lookups to shadow upstream {}", sample, dns);
+            pool = Arc::new(shadow::Shadowed::new(
+                pool,
+                Arc::new(shadow),
+                sample,
+                rng.clone(),
+                metrics.clone(),
+            ));
+        }
+        let mut persisted = None;
+        if let Some(path) = &opts.cache_file {
+            let loaded = Arc::new(persist::Persisted::load(pool, path));
+            eprintln!("loaded {} answers from {}", loaded.len(), path.display());
+            pool = loaded.clone();
+            persisted = Some(loaded);
+        }
+        if let Some(every) = opts.capability_probe {
+            let pool = pool.clone();
+            async_std::task::spawn(async move {
+                loop {
+                    pool.probe().await;
+                    async_std::task::sleep(every).await;
+                }
+            });
+        }
+        let zones = if opts.zone_stats {
+            let suffixes = match &opts.psl_file {
+                Some(path) => zones::Suffixes::load(path).map_err(|err| {
+                    tide::Error::from_str(
+                        StatusCode::InternalServerError,
+                        format!("cannot load {}: {}", path.display(), err),
+                    )
+                })?,
+                None => zones::Suffixes::Builtin,
+            };
+            Some(Arc::new(zones::ZoneStats::new(suffixes)))
+        } else {
+            None
+        };
+        if let (Some(zones), Some(path)) = (zones.clone(), opts.zone_stats_file.clone()) {
+            let every = opts.zone_stats_dump;
+            async_std::task::spawn(async move {
+                loop {
+                    async_std::task::sleep(every).await;
+                    if let Err(err) = zones.dump(&path) {
+                        eprintln!("cannot save {}: {}", path.display(), err);
+                    }
+                }
+            });
+        }
+        let filters = opts
+            .answer_filters
+            .iter()
+            .filter_map(|name| filter::builtin(name))
+            .chain(self.filters)
+            .collect::<Vec<_>>();
+        Ok(State {
+            resolver: pool,
+            rng,
+            metrics,
+            opts: Arc::new(opts),
+            filters: Arc::new(filters),
+            hooks: Arc::new(self.hooks),
+            zones,
+            persisted,
+        })
+    }
+}
+
+/// Serves `state` on `ADDR`, over TLS with `CERT_FILE` and `KEY_FILE`,
+/// until SIGTERM or SIGINT.
+pub async fn serve(state: State) -> tide::Result<()> {
+    let addr = state.opts.addr.clone();
+    let tls = state
+        .opts
+        .cert_file
+        .clone()
+        .zip(state.opts.key_file.clone());
+    let hooks = state.hooks.clone();
+    let persisted = state.persisted.clone();
+    let path = state.opts.cache_file.clone();
+    if persisted.is_some() || hooks.has_shutdown() {
+        tokio::spawn(async move {
+            shutdown().await;
+            hooks.shutdown().await;
+            if let (Some(persisted), Some(path)) = (persisted, path) {
+                match persisted.save() {
+                    Ok(saved) => eprintln!("saved {} answers to {}", saved, path.display()),
+                    Err(err) => eprintln!("cannot save {}: {}", path.display(), err),
+                }
+            }
+            std::process::exit(0);
+        });
+    }
+    let app = server(state);
+    if let Some((cert_file, key_file)) = tls {
+        let listener = TlsListener::build()
+            .addrs(addr)
+            .cert(cert_file)
+            .key(key_file);
+        listen(app, listener).await?;
+    } else {
+        listen(app, addr).await?;
+    }
+    Ok(())
+}
+
+/// The binary: checks the configuration with `--check-config`, serves
+/// otherwise.
+pub async fn run() -> tide::Result<()> {
+    if env::args().any(|arg| arg == ARG_CHECK_CONFIG) || env_flag(ENV_SELF_TEST) {
+        let probe = env::args().any(|arg| arg == ARG_PROBE) || env_flag(ENV_SELF_TEST_PROBE);
+        std::process::exit(if check::run(probe).await { 0 } else { 1 });
+    }
+    // Startup is logged at info; per-request lines are not.
+    tide::log::with_level(tide::log::LevelFilter::Info);
+    let state = State::builder().build()?;
+    log::set_max_level(tide::log::LevelFilter::Warn);
+    serve(state).await
+}
+
+/// Binds `app` to `listener`, runs the ready hooks with the addresses
+/// actually bound, port 0 resolved, then serves.
+pub async fn listen<L: ToListener<State>>(app: Server<State>, listener: L) -> io::Result<()> {
+    let hooks = app.state().hooks.clone();
+    let mut listener = app.bind(listener).await?;
+    let addrs = listener
+        .info()
+        .iter()
+        .filter_map(|info| bound_addr(info.connection()))
+        .collect();
+    hooks.ready(addrs).await;
+    listener.accept().await
+}
+
+/// Address of a listener connection string such as `http://127.0.0.1:8000`.
+fn bound_addr(connection: &str) -> Option<SocketAddr> {
+    connection.split_once("://")?.1.parse().ok()
+}
+
+/// Resolves on SIGINT or SIGTERM.
+async fn shutdown() {
+    use tokio::signal::unix::{signal, SignalKind};
+    match signal(SignalKind::terminate()) {
+        Ok(mut term) => {
+            futures_util::future::select(Box::pin(tokio::signal::ctrl_c()), Box::pin(term.recv()))
+                .await;
+        }
+        Err(_) => {
+            let _ = tokio::signal::ctrl_c().await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use async_std::channel;
+    use async_std::io::{ReadExt, WriteExt};
+    use async_std::net::TcpStream;
+
+    use super::{bound_addr, listen, Hooks};
+    use crate::server;
+    use crate::tests::state;
+    use crate::upstream::mock::Mock;
+
+    #[async_std::test]
+    async fn ready_and_shutdown() {
+        let (tx, rx) = channel::bounded(1);
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let (first, second) = (events.clone(), events.clone());
+        let mut hooks = Hooks::default();
+        hooks.on_ready(move |addrs| {
+            let tx = tx.clone();
+            async move { tx.send(addrs).await.unwrap() }
+        });
+        hooks.on_shutdown(move || {
+            let events = first.clone();
+            async move { events.lock().unwrap().push("first") }
+        });
+        hooks.on_shutdown(move || {
+            let events = second.clone();
+            async move { events.lock().unwrap().push("second") }
+        });
+        let hooks = Arc::new(hooks);
+        let mut state = state(Mock::default());
+        state.hooks = hooks.clone();
+        async_std::task::spawn(listen(server(state), "127.0.0.1:0"));
+        let addrs = rx.recv().await.unwrap();
+        assert_eq!(addrs.len(), 1);
+        assert_ne!(addrs[0].port(), 0);
+
+        let mut conn = TcpStream::connect(addrs[0]).await.unwrap();
+        conn.write_all(b"GET /ping HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut res = String::new();
+        conn.read_to_string(&mut res).await.unwrap();
+        assert!(res.starts_with("HTTP/1.1 200"));
+        assert!(res.ends_with("OK"));
+
+        hooks.shutdown().await;
+        assert_eq!(*events.lock().unwrap(), ["first", "second"]);
+    }
+
+    #[test]
+    fn addrs() {
+        assert_eq!(
+            bound_addr("http://127.0.0.1:8000"),
+            Some("127.0.0.1:8000".parse().unwrap())
+        );
+        assert_eq!(
+            bound_addr("https://[::1]:443"),
+            Some("[::1]:443".parse().unwrap())
+        );
+        assert_eq!(bound_addr("127.0.0.1:8000, 127.0.0.2:8000"), None);
+    }
+}
@@ -0,0 +1,371 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+pub const HIT: &str = "hit";
+pub const NXDOMAIN: &str = "nxdomain";
+pub const NODATA: &str = "nodata";
+pub const ERROR: &str = "error";
+
+const OUTCOMES: [&str; 4] = [HIT, NXDOMAIN, NODATA, ERROR];
+
+pub const SHADOW_AGREE: &str = "agree";
+pub const SHADOW_DISAGREE: &str = "disagree";
+pub const SHADOW_ERROR: &str = "error";
+pub const SHADOW_SKIPPED: &str = "skipped";
+
+const SHADOW_OUTCOMES: [&str; 4] = [SHADOW_AGREE, SHADOW_DISAGREE, SHADOW_ERROR, SHADOW_SKIPPED];
+
+/// Samples for the error rate are at least this far apart.
+const SAMPLE_EVERY: Duration = Duration::from_secs(1);
+
+/// Upstream queries counted by the second, for the current rate.
+#[derive(Default)]
+pub struct Rate {
+    second: Option<Instant>,
+    count: u64,
+    /// Queries of the last full second.
+    last: u64,
+}
+
+impl Rate {
+    pub const fn new() -> Self {
+        Self {
+            second: None,
+            count: 0,
+            last: 0,
+        }
+    }
+
+    /// Counts a query sent at `now`.
+    pub fn add(&mut self, now: Instant) {
+        self.roll(now);
+        self.count += 1;
+    }
+
+    fn roll(&mut self, now: Instant) {
+        let second = *self.second.get_or_insert(now);
+        let elapsed = now.saturating_duration_since(second);
+        if elapsed >= SAMPLE_EVERY * 2 {
+            self.last = 0;
+            self.count = 0;
+            self.second = Some(now);
+        } else if elapsed >= SAMPLE_EVERY {
+            self.last = self.count;
+            self.count = 0;
+            self.second = Some(second + SAMPLE_EVERY);
+        }
+    }
+}
+
+/// Lookup totals at a point in time.
+#[derive(Clone, Copy)]
+struct Sample {
+    at: Instant,
+    lookups: u64,
+    errors: u64,
+}
+
+#[derive(Default)]
+pub struct Metrics {
+    /// Upstream lookups by record type, one counter per outcome.
+    lookups: RwLock<BTreeMap<String, Arc<[AtomicU64; 4]>>>,
+    /// Handlers cancelled by their route deadline.
+    handler_timeouts: RwLock<BTreeMap<&'static str, AtomicU64>>,
+    pub servfail: AtomicU64,
+    pub hedges: Arc<AtomicU64>,
+    /// Requests with query parameters clamped into range.
+    pub params_adjusted: AtomicU64,
+    /// Lookups refused for hosts outside the allowlist.
+    pub allowlist_denied: AtomicU64,
+    /// Address lookups asked again after an empty answer.
+    pub empty_retries: AtomicU64,
+    /// Lookups copied to the shadow upstream, one counter per outcome.
+    shadow: [AtomicU64; 4],
+    /// Time the compared lookups took at the upstream and at the shadow.
+    shadow_micros: [AtomicU64; 2],
+    /// Totals taken by `error_rate`, oldest first.
+    samples: Mutex<VecDeque<Sample>>,
+}
+
+impl Metrics {
+    pub fn inc(counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn lookup(&self, rtype: &str, outcome: &str) {
+        let index = OUTCOMES.iter().position(|v| *v == outcome).unwrap();
+        let counters = self.lookups.read().unwrap().get(rtype).cloned();
+        let counters = match counters {
+            Some(counters) => counters,
+            None => self
+                .lookups
+                .write()
+                .unwrap()
+                .entry(rtype.into())
+                .or_default()
+                .clone(),
+        };
+        Self::inc(&counters[index]);
+    }
+
+    pub fn handler_timeout(&self, route: &'static str) {
+        if let Some(counter) = self.handler_timeouts.read().unwrap().get(route) {
+            return Self::inc(counter);
+        }
+        Self::inc(
+            self.handler_timeouts
+                .write()
+                .unwrap()
+                .entry(route)
+                .or_default(),
+        );
+    }
+
+    /// Lookups and errors of all types.
+    fn totals(&self) -> (u64, u64) {
+        let lookups = self.lookups.read().unwrap();
+        let error = OUTCOMES.iter().position(|v| *v == ERROR).unwrap();
+        let mut totals = (0, 0);
+        for counters in lookups.values() {
+            totals.0 += counters
+                .iter()
+                .map(|c| c.load(Ordering::Relaxed))
+                .sum::<u64>();
+            totals.1 += counters[error].load(Ordering::Relaxed);
+        }
+        totals
+    }
+
+    /// Share of lookups that failed over about the last `window`, or since
+    /// startup until the first sample is that old. `None` below
+    /// `min_lookups`, where a few errors say little.
+    pub fn error_rate(&self, window: Duration, min_lookups: u64) -> Option<f64> {
+        let (lookups, errors) = self.totals();
+        let now = Instant::now();
+        let mut samples = self.samples.lock().unwrap();
+        // Keeps the newest sample at least `window` old as the baseline.
+        while samples.len() > 1 && now.duration_since(samples[1].at) >= window {
+            samples.pop_front();
+        }
+        let base = samples.front().copied().unwrap_or(Sample {
+            at: now,
+            lookups: 0,
+            errors: 0,
+        });
+        if samples
+            .back()
+            .is_none_or(|last| now.duration_since(last.at) >= SAMPLE_EVERY)
+        {
+            samples.push_back(Sample {
+                at: now,
+                lookups,
+                errors,
+            });
+        }
+        let lookups = lookups - base.lookups;
+        if lookups == 0 || lookups < min_lookups {
+            return None;
+        }
+        Some((errors - base.errors) as f64 / lookups as f64)
+    }
+
+    pub fn shadow(&self, outcome: &str) {
+        let index = SHADOW_OUTCOMES.iter().position(|v| *v == outcome).unwrap();
+        Self::inc(&self.shadow[index]);
+    }
+
+    pub fn shadow_latency(&self, upstream: Duration, shadow: Duration) {
+        for (counter, elapsed) in self.shadow_micros.iter().zip([upstream, shadow]) {
+            counter.fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+        }
+    }
+
+    /// Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let mut s = String::new();
+        let name = "bdns_lookups_total";
+        let _ = writeln!(
+            s,
+            "# HELP {} Upstream lookups by record type and outcome.",
+            name
+        );
+        let _ = writeln!(s, "# TYPE {} counter", name);
+        for (rtype, counters) in self.lookups.read().unwrap().iter() {
+            for (outcome, counter) in OUTCOMES.iter().zip(counters.iter()) {
+                let _ = writeln!(
+                    s,
+                    "{}{{type=\"{}\",outcome=\"{}\"}} {}",
+                    name,
+                    escape(rtype),
+                    outcome,
+                    counter.load(Ordering::Relaxed),
+                );
+            }
+        }
+        let name = "bdns_handler_timeouts_total";
+        let _ = writeln!(
+            s,
+            "# HELP {} Handlers cancelled by the route deadline.",
+            name
+        );
+        let _ = writeln!(s, "# TYPE {} counter", name);
+        for (route, counter) in self.handler_timeouts.read().unwrap().iter() {
+            let _ = writeln!(
+                s,
+                "{}{{route=\"{}\"}} {}",
+                name,
+                escape(route),
+                counter.load(Ordering::Relaxed),
+            );
+        }
+        for (name, help, counter) in [
+            (
+                "bdns_servfail_total",
+                "Lookups answered with SERVFAIL.",
+                &self.servfail,
+            ),
+            (
+                "bdns_hedges_total",
+                "Queries repeated at a second upstream.",
+                &self.hedges,
+            ),
+            (
+                "bdns_params_adjusted_total",
+                "Requests with query parameters clamped into range.",
+                &self.params_adjusted,
+            ),
+            (
+                "bdns_allowlist_denied_total",
+                "Lookups refused for hosts outside the allowlist.",
+                &self.allowlist_denied,
+            ),
+            (
+                "bdns_empty_retries_total",
+                "Address lookups asked again after an empty answer.",
+                &self.empty_retries,
+            ),
+            (
+                "bdns_upstream_queries_total",
+                "Queries sent to the upstreams.",
+                &crate::egress::QUERIES,
+            ),
+            (
+                "bdns_upstream_throttled_total",
+                "Queries refused for the upstream query budget.",
+                &crate::egress::THROTTLED,
+            ),
+            (
+                "bdns_upstream_tcp_connects_total",
+                "TCP connections opened to upstreams and masters.",
+                &crate::bind::TCP_CONNECTS,
+            ),
+        ] {
+            let _ = writeln!(s, "# HELP {} {}", name, help);
+            let _ = writeln!(s, "# TYPE {} counter", name);
+            let _ = writeln!(s, "{} {}", name, counter.load(Ordering::Relaxed));
+        }
+        let name = "bdns_upstream_qps";
+        let _ = writeln!(
+            s,
+            "# HELP {} Queries sent to the upstreams in the last full second.",
+            name
+        );
+        let _ = writeln!(s, "# TYPE {} gauge", name);
+        let mut rate = crate::egress::RATE.lock().unwrap();
+        rate.roll(Instant::now());
+        let _ = writeln!(s, "{} {}", name, rate.last);
+        drop(rate);
+        let name = "bdns_shadow_total";
+        let _ = writeln!(
+            s,
+            "# HELP {} Lookups copied to the shadow upstream by result.",
+            name
+        );
+        let _ = writeln!(s, "# TYPE {} counter", name);
+        for (result, counter) in SHADOW_OUTCOMES.iter().zip(self.shadow.iter()) {
+            let _ = writeln!(
+                s,
+                "{}{{result=\"{}\"}} {}",
+                name,
+                result,
+                counter.load(Ordering::Relaxed)
+            );
+        }
+        let name = "bdns_shadow_seconds_total";
+        let _ = writeln!(
+            s,
+            "# HELP {} Time the compared lookups took, by upstream.",
+            name
+        );
+        let _ = writeln!(s, "# TYPE {} counter", name);
+        for (upstream, micros) in ["primary", "shadow"].iter().zip(self.shadow_micros.iter()) {
+            let seconds = micros.load(Ordering::Relaxed) as f64 / 1e6;
+            let _ = writeln!(s, "{}{{upstream=\"{}\"}} {}", name, upstream, seconds);
+        }
+        s
+    }
+}
+
+/// Escapes a label value.
+fn escape(s: &str) -> String {
+    s.replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::{escape, Metrics, Rate, ERROR, HIT, NXDOMAIN};
+
+    #[test]
+    fn lookups() {
+        let metrics = Metrics::default();
+        metrics.lookup("A", HIT);
+        metrics.lookup("A", HIT);
+        metrics.lookup("TYPE65534", NXDOMAIN);
+        let s = metrics.render();
+        assert!(s.contains("bdns_lookups_total{type=\"A\",outcome=\"hit\"} 2\n"));
+        assert!(s.contains("bdns_lookups_total{type=\"A\",outcome=\"error\"} 0\n"));
+        assert!(s.contains("bdns_lookups_total{type=\"TYPE65534\",outcome=\"nxdomain\"} 1\n"));
+        assert_eq!(escape("a\"b\\c\nd"), "a\\\"b\\\\c\\nd");
+    }
+
+    #[test]
+    fn error_rate() {
+        let metrics = Metrics::default();
+        let window = Duration::from_secs(60);
+        assert_eq!(metrics.error_rate(window, 1), None);
+        for _ in 0..3 {
+            metrics.lookup("A", HIT);
+        }
+        metrics.lookup("AAAA", ERROR);
+        assert_eq!(metrics.error_rate(window, 1), Some(0.25));
+        assert_eq!(metrics.error_rate(window, 5), None);
+        // Samples older than the window stop counting.
+        let metrics = Metrics::default();
+        metrics.lookup("A", ERROR);
+        assert_eq!(metrics.error_rate(Duration::ZERO, 1), Some(1.0));
+        metrics.lookup("A", HIT);
+        assert_eq!(metrics.error_rate(Duration::ZERO, 1), Some(0.0));
+    }
+
+    #[test]
+    fn upstream_rate() {
+        let start = Instant::now();
+        let mut rate = Rate::default();
+        rate.roll(start);
+        rate.count = 5;
+        rate.roll(start + Duration::from_millis(1500));
+        assert_eq!((rate.last, rate.count), (5, 0));
+        rate.count = 2;
+        rate.roll(start + Duration::from_millis(2100));
+        assert_eq!(rate.last, 2);
+        rate.roll(start + Duration::from_secs(5));
+        assert_eq!(rate.last, 0);
+    }
+}
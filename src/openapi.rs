@@ -0,0 +1,66 @@
+use tide::{Request, StatusCode};
+
+use crate::State;
+
+/// OpenAPI description of the routes, edited along with them.
+const SPEC: &str = include_str!("openapi.json");
+
+/// `/openapi.json`: the embedded description, with the running version.
+pub async fn handler(_req: Request<State>) -> tide::Result {
+    let mut spec: serde_json::Value = serde_json::from_str(SPEC)
+        .map_err(|err| tide::Error::new(StatusCode::InternalServerError, err))?;
+    spec["info"]["version"] = env!("CARGO_PKG_VERSION").into();
+    Ok(spec.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use tide::http::{Method, Request, Response, Url};
+
+    use crate::server;
+    use crate::tests::state;
+    use crate::upstream::mock::Mock;
+
+    #[async_std::test]
+    async fn spec() {
+        let url = Url::parse("http://localhost/openapi.json").unwrap();
+        let mut res: Response = server(state(Mock::default()))
+            .respond(Request::new(Method::Get, url))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), 200);
+        let spec: serde_json::Value = res.body_json().await.unwrap();
+        assert_eq!(spec["info"]["version"], env!("CARGO_PKG_VERSION"));
+        let paths = spec["paths"].as_object().unwrap();
+        for path in [
+            "/r/{host}",
+            "/rr/{host}",
+            "/x/{host}",
+            "/probe/{host}",
+            "/axfr/{zone}",
+            "/ws",
+            "/",
+            "/ping",
+            "/ready",
+            "/version",
+            "/metrics",
+            "/admin/upstreams",
+            "/stats/zones",
+            "/robots.txt",
+            "/openapi.json",
+        ] {
+            assert!(paths.contains_key(path), "{}", path);
+        }
+        assert_eq!(paths.len(), 15);
+        // Every reference points at a component.
+        let text = spec.to_string();
+        for reference in text.split("\"$ref\":\"#/").skip(1) {
+            let pointer = reference.split('"').next().unwrap();
+            assert!(
+                spec.pointer(&format!("/{}", pointer)).is_some(),
+                "{}",
+                pointer
+            );
+        }
+    }
+}
@@ -0,0 +1,717 @@
+use std::collections::HashMap;
+use std::env;
+use std::net::{IpAddr, SocketAddr};
+use std::ops::RangeInclusive;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+use trust_dns_resolver::proto::op::ResponseCode;
+use trust_dns_resolver::proto::rr::RecordType;
+
+use crate::{filter, rdata};
+
+pub const ENV_DNS: &str = "DNS";
+pub const ENV_SELF_TEST: &str = "SELF_TEST";
+pub const ENV_SELF_TEST_PROBE: &str = "SELF_TEST_PROBE";
+const ENV_ADDR: &str = "ADDR";
+pub const ENV_CERT_FILE: &str = "CERT_FILE";
+pub const ENV_KEY_FILE: &str = "KEY_FILE";
+const ENV_WS_CONCURRENCY: &str = "WS_CONCURRENCY";
+const ENV_WS_IDLE_TIMEOUT: &str = "WS_IDLE_TIMEOUT";
+const ENV_TRAILING_NEWLINE: &str = "TRAILING_NEWLINE";
+const ENV_TEXT_CONTRACT: &str = "TEXT_CONTRACT";
+const ENV_CONCURRENCY: &str = "CONCURRENCY";
+const ENV_HEDGE_AFTER_MS: &str = "HEDGE_AFTER_MS";
+const ENV_HEDGE_MAX_RATIO: &str = "HEDGE_MAX_RATIO";
+pub const ENV_SHADOW_DNS: &str = "SHADOW_DNS";
+const ENV_SHADOW_SAMPLE: &str = "SHADOW_SAMPLE";
+const ENV_CORS_ENABLED: &str = "CORS_ENABLED";
+const ENV_ALLOW_PRIVATE_NETWORK: &str = "ALLOW_PRIVATE_NETWORK";
+const ENV_AXFR_ALLOWED_ZONES: &str = "AXFR_ALLOWED_ZONES";
+const ENV_AXFR_MASTERS: &str = "AXFR_MASTERS";
+const ENV_AXFR_MAX_RECORDS: &str = "AXFR_MAX_RECORDS";
+const ENV_AXFR_MAX_BYTES: &str = "AXFR_MAX_BYTES";
+pub const ENV_UPSTREAM_BIND_ADDR: &str = "UPSTREAM_BIND_ADDR";
+pub const ENV_SOURCE_PORT_RANGE: &str = "SOURCE_PORT_RANGE";
+pub const ENV_BIND_INTERFACE: &str = "BIND_INTERFACE";
+const ENV_COMPRESS_MIN_BYTES: &str = "COMPRESS_MIN_BYTES";
+const ENV_ENABLE_DEBUG: &str = "ENABLE_DEBUG";
+const ENV_DEBUG_WIRE: &str = "DEBUG_WIRE";
+const ENV_AUTH_TOKEN: &str = "AUTH_TOKEN";
+const ENV_ADMIN_BYPASS_TYPES: &str = "ADMIN_BYPASS_TYPES";
+const ENV_ENABLE_ANY: &str = "ENABLE_ANY";
+const ENV_ENABLE_PROBE: &str = "ENABLE_PROBE";
+const ENV_ENABLE_UI: &str = "ENABLE_UI";
+const ENV_PROBE_MAX_PORTS: &str = "PROBE_MAX_PORTS";
+const ENV_STRICT_PARAMS: &str = "STRICT_PARAMS";
+const ENV_ANSWER_FILTERS: &str = "ANSWER_FILTERS";
+const ENV_ALLOWED_TYPES: &str = "ALLOWED_TYPES";
+const ENV_TYPE_LIMITS: &str = "TYPE_LIMITS";
+const ENV_ALIASES: &str = "ALIASES";
+const ENV_STRIP_PORT: &str = "STRIP_PORT";
+const ENV_MODE: &str = "MODE";
+const ENV_ALLOWLIST: &str = "ALLOWLIST";
+const ENV_VERBOSE_ERRORS: &str = "VERBOSE_ERRORS";
+const ENV_EMPTY_RETRY: &str = "EMPTY_RETRY";
+const ENV_LINK_LOCAL: &str = "LINK_LOCAL";
+const ENV_MAX_RESOLVE_DEPTH: &str = "MAX_RESOLVE_DEPTH";
+const ENV_CACHE_HEADERS: &str = "CACHE_HEADERS";
+const ENV_ROBOTS_TXT: &str = "ROBOTS_TXT";
+const ENV_MAX_RESPONSE_BYTES: &str = "MAX_RESPONSE_BYTES";
+const ENV_SHUFFLE_CACHE: &str = "SHUFFLE_CACHE";
+const ENV_ROUTE_TIMEOUT_MS: &str = "ROUTE_TIMEOUT_MS";
+const ENV_BATCH_ROUTE_TIMEOUT_MS: &str = "BATCH_ROUTE_TIMEOUT_MS";
+const ENV_DEADLINE_HEADER: &str = "DEADLINE_HEADER";
+const ENV_DEGRADED_ERROR_RATE: &str = "DEGRADED_ERROR_RATE";
+const ENV_DEGRADED_WINDOW_SECS: &str = "DEGRADED_WINDOW_SECS";
+const ENV_DEGRADED_MIN_LOOKUPS: &str = "DEGRADED_MIN_LOOKUPS";
+const ENV_CAPABILITY_PROBE_SECS: &str = "CAPABILITY_PROBE_SECS";
+const ENV_CACHE_SIZE: &str = "CACHE_SIZE";
+const ENV_CACHE_SHARDS: &str = "CACHE_SHARDS";
+const ENV_PROTO: &str = "PROTO";
+const ENV_UPSTREAM_QPS: &str = "UPSTREAM_QPS";
+const ENV_UPSTREAM_QPS_WAIT: &str = "UPSTREAM_QPS_WAIT";
+const ENV_PERSIST_CACHE: &str = "PERSIST_CACHE";
+const ENV_CACHE_FILE: &str = "CACHE_FILE";
+const ENV_ZONE_STATS: &str = "ZONE_STATS";
+pub const ENV_PSL_FILE: &str = "PSL_FILE";
+const ENV_ZONE_STATS_FILE: &str = "ZONE_STATS_FILE";
+const ENV_ZONE_STATS_DUMP_SECS: &str = "ZONE_STATS_DUMP_SECS";
+const ENV_TEST_NODATA_HOST: &str = "TEST_NODATA_HOST";
+const ENV_TEST_NXDOMAIN_HOST: &str = "TEST_NXDOMAIN_HOST";
+const ENV_TEST_SERVFAIL_HOST: &str = "TEST_SERVFAIL_HOST";
+const ENV_DEFAULT_RECORD_TTL: &str = "DEFAULT_RECORD_TTL";
+
+pub const ARG_CHECK_CONFIG: &str = "--check-config";
+pub const ARG_PROBE: &str = "--probe";
+
+pub const DEFAULT_DNS: &str = "127.0.0.1:5353";
+const DEFAULT_ADDR: &str = "127.0.0.1:8000";
+const DEFAULT_WS_CONCURRENCY: usize = 16;
+const DEFAULT_WS_IDLE_TIMEOUT: u64 = 60;
+const DEFAULT_CONCURRENCY: usize = 8;
+const DEFAULT_HEDGE_MAX_RATIO: f64 = 0.05;
+const DEFAULT_SHADOW_SAMPLE: f64 = 0.05;
+const DEFAULT_AXFR_MAX_RECORDS: usize = 10_000;
+const DEFAULT_AXFR_MAX_BYTES: usize = 4 << 20;
+const DEFAULT_COMPRESS_MIN_BYTES: usize = 1024;
+const DEFAULT_ALLOWED_TYPES: &str = "A,AAAA,PTR";
+const DEFAULT_ROUTE_TIMEOUT_MS: u64 = 10_000;
+const DEFAULT_BATCH_ROUTE_TIMEOUT_MS: u64 = 30_000;
+const DEFAULT_DEGRADED_ERROR_RATE: f64 = 0.5;
+const DEFAULT_DEGRADED_WINDOW_SECS: u64 = 60;
+const DEFAULT_DEGRADED_MIN_LOOKUPS: u64 = 20;
+const DEFAULT_CAPABILITY_PROBE_SECS: u64 = 300;
+pub const DEFAULT_CACHE_SIZE: usize = 1024;
+const DEFAULT_CACHE_FILE: &str = "bdns-cache.json";
+const DEFAULT_ZONE_STATS_DUMP_SECS: u64 = 300;
+const DEFAULT_PROBE_MAX_PORTS: usize = 8;
+const DEFAULT_MAX_RESOLVE_DEPTH: usize = 8;
+const DEFAULT_MAX_RESPONSE_BYTES: usize = 256 << 10;
+const DEFAULT_ROBOTS_TXT: &str = "User-agent: *\nDisallow: /\n";
+const DEFAULT_RECORD_TTL: u32 = 60;
+
+/// Caching of shuffled `/r` answers when `CACHE_HEADERS` is on.
+#[derive(Clone, Copy, PartialEq)]
+pub enum ShuffleCache {
+    /// `Cache-Control: private, no-store`, nothing shares an order.
+    Private,
+    /// Clients send `X-Session`, each session is cached with its order.
+    Edge,
+}
+
+impl FromStr for ShuffleCache {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "private" => Ok(Self::Private),
+            "edge" => Ok(Self::Edge),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Transports lookups go over.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Proto {
+    /// UDP, retrying over TCP when the answer is truncated.
+    Udp,
+    Tcp,
+    /// UDP and TCP at once, the first answer wins.
+    Race,
+}
+
+impl FromStr for Proto {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "udp" => Ok(Self::Udp),
+            "tcp" => Ok(Self::Tcp),
+            "race" => Ok(Self::Race),
+            _ => Err(()),
+        }
+    }
+}
+
+/// What becomes of IPv6 link-local (fe80::/10) answers, unusable without
+/// the interface of the zone they came from.
+#[derive(Clone, Copy, PartialEq)]
+pub enum LinkLocal {
+    /// Dropped before the answer filters.
+    Drop,
+    Keep,
+    /// Kept, with `"scope": "link-local"` in JSON and a `link-local` field
+    /// ending text lines.
+    Annotate,
+}
+
+impl FromStr for LinkLocal {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "drop" => Ok(Self::Drop),
+            "keep" => Ok(Self::Keep),
+            "annotate" => Ok(Self::Annotate),
+            _ => Err(()),
+        }
+    }
+}
+
+pub(crate) struct Opts {
+    pub dns: Vec<String>,
+    pub addr: String,
+    pub cert_file: Option<String>,
+    pub key_file: Option<String>,
+    pub ws_concurrency: usize,
+    pub concurrency: usize,
+    pub ws_idle_timeout: Duration,
+    pub trailing_newline: bool,
+    /// Plain-text bodies follow `contract::TEXT_V1`.
+    pub text_contract: bool,
+    pub hedge_after: Option<Duration>,
+    pub hedge_max_ratio: f64,
+    /// Upstream a sample of lookups is repeated at for comparison, `ip:port`.
+    pub shadow_dns: Option<String>,
+    /// Share of lookups repeated at `shadow_dns`, `None` repeats none.
+    pub shadow_sample: Option<f64>,
+    /// Off when a proxy in front sets the CORS headers.
+    pub cors_enabled: bool,
+    pub allow_private_network: bool,
+    /// Deadline of a single-lookup handler, `None` disables it.
+    pub route_timeout: Option<Duration>,
+    /// Deadline of handlers doing several lookups.
+    pub batch_route_timeout: Option<Duration>,
+    /// Request header with the time the client has left, shortening the
+    /// route deadlines.
+    pub deadline_header: Option<String>,
+    /// Zones `/axfr` may transfer, each also needs a master.
+    pub axfr_allowed_zones: Vec<String>,
+    /// Server to transfer each zone from.
+    pub axfr_masters: HashMap<String, SocketAddr>,
+    pub axfr_max_records: usize,
+    /// Cap on the rendered body of a transfer.
+    pub axfr_max_bytes: usize,
+    /// `/ready` fails above this share of failed lookups, `None` disables it.
+    pub degraded_error_rate: Option<f64>,
+    /// Span the error rate is measured over.
+    pub degraded_window: Duration,
+    /// Fewer lookups in the window never count as degraded.
+    pub degraded_min_lookups: u64,
+    /// Interval of the upstream capability probe, `None` disables it.
+    pub capability_probe: Option<Duration>,
+    /// Answers the resolver cache of each upstream holds, over all shards.
+    pub cache_size: usize,
+    /// Locks the resolver cache of each upstream is split over, a power of
+    /// two and at most `cache_size`.
+    pub cache_shards: usize,
+    pub proto: Proto,
+    /// Queries sent to the upstreams per second at most, `None` for any.
+    pub upstream_qps: Option<u32>,
+    /// Queries over `upstream_qps` wait up to `egress::MAX_WAIT` rather
+    /// than fail at once.
+    pub upstream_qps_wait: bool,
+    /// Answers are saved there on shutdown and served after a restart.
+    pub cache_file: Option<PathBuf>,
+    /// Lookups are counted by registrable domain for `/stats/zones`.
+    pub zone_stats: bool,
+    /// Public suffix list used instead of the one compiled in.
+    pub psl_file: Option<PathBuf>,
+    /// Zone counts are written there as CSV every `zone_stats_dump`.
+    pub zone_stats_file: Option<PathBuf>,
+    pub zone_stats_dump: Duration,
+    /// Source addresses of upstream queries, one IPv4 and one IPv6 at most.
+    pub upstream_bind_addrs: Vec<IpAddr>,
+    /// Source ports of upstream UDP queries, ephemeral when `None`.
+    pub source_ports: Option<RangeInclusive<u16>>,
+    /// Network interface upstream queries leave from, Linux only.
+    pub bind_interface: Option<String>,
+    /// Smallest body compressed for clients sending `Accept-Encoding`.
+    /// Measured on the body as sent, after any truncation.
+    pub compress_min_bytes: usize,
+    /// Allows debugging options such as `do=1`.
+    pub enable_debug: bool,
+    /// Allows `debug=wire` to holders of `auth_token`.
+    pub debug_wire: bool,
+    /// Bearer token of authenticated options such as `debug=wire`.
+    pub auth_token: Option<String>,
+    /// Allows `t=ANY`, regardless of `allowed_types`.
+    pub enable_any: bool,
+    /// Allows `reachable=PORT` and `/probe`, connecting to the resolved
+    /// addresses.
+    pub enable_probe: bool,
+    /// Ports a single `/probe` request may list.
+    pub probe_max_ports: usize,
+    /// Serves the lookup page at `/`.
+    pub enable_ui: bool,
+    /// Numeric parameters out of range fail with 400 instead of being clamped.
+    pub strict_params: bool,
+    /// Built-in answer filters, in the order they apply.
+    pub answer_filters: Vec<String>,
+    /// Record types that may be queried, `None` allows all.
+    pub allowed_types: Option<Vec<RecordType>>,
+    /// Holders of `auth_token` may query any type, past `allowed_types`.
+    pub admin_bypass_types: bool,
+    /// Answers kept of each record type on `/r`, as a cap on
+    /// `limit_per_type`.
+    pub type_limits: HashMap<RecordType, usize>,
+    /// Short names resolved as their target by `/r`.
+    pub aliases: HashMap<String, String>,
+    /// `/r` resolves `host:port` as `host`.
+    pub strip_port: bool,
+    /// With `MODE=allowlist`, the only domains resolved, with their
+    /// subdomains. Lowercased, without the trailing dot.
+    pub allowlist: Option<Vec<String>>,
+    /// Failed lookups answered in JSON carry the error kind, rcode and
+    /// upstreams.
+    pub verbose_errors: bool,
+    /// Address lookups with an empty answer that is not authoritative are
+    /// asked once more.
+    pub empty_retry: bool,
+    pub link_local: LinkLocal,
+    /// Alias and CNAME hops a request may take in total.
+    pub max_resolve_depth: usize,
+    /// `Cache-Control` on `/r` answers, `max-age` being what is left of the
+    /// cache entry, or the negative TTL of NXDOMAIN and NODATA.
+    pub cache_headers: bool,
+    pub shuffle_cache: ShuffleCache,
+    /// Body of `/robots.txt`.
+    pub robots_txt: String,
+    /// Answers beyond this much output are dropped, batches included.
+    pub max_response_bytes: usize,
+    /// Hosts always failing with the given code, for client testing.
+    pub test_hosts: Vec<(String, ResponseCode)>,
+    /// TTL of answers made up here, such as those of `test_hosts`.
+    pub default_record_ttl: u32,
+}
+
+impl Default for Opts {
+    fn default() -> Self {
+        Self {
+            dns: vec![DEFAULT_DNS.into()],
+            addr: DEFAULT_ADDR.into(),
+            cert_file: None,
+            key_file: None,
+            ws_concurrency: DEFAULT_WS_CONCURRENCY,
+            concurrency: DEFAULT_CONCURRENCY,
+            ws_idle_timeout: Duration::from_secs(DEFAULT_WS_IDLE_TIMEOUT),
+            trailing_newline: false,
+            text_contract: false,
+            hedge_after: None,
+            hedge_max_ratio: DEFAULT_HEDGE_MAX_RATIO,
+            shadow_dns: None,
+            shadow_sample: Some(DEFAULT_SHADOW_SAMPLE),
+            cors_enabled: true,
+            allow_private_network: false,
+            route_timeout: Some(Duration::from_millis(DEFAULT_ROUTE_TIMEOUT_MS)),
+            batch_route_timeout: Some(Duration::from_millis(DEFAULT_BATCH_ROUTE_TIMEOUT_MS)),
+            deadline_header: None,
+            axfr_allowed_zones: Vec::new(),
+            axfr_masters: HashMap::new(),
+            axfr_max_records: DEFAULT_AXFR_MAX_RECORDS,
+            axfr_max_bytes: DEFAULT_AXFR_MAX_BYTES,
+            degraded_error_rate: Some(DEFAULT_DEGRADED_ERROR_RATE),
+            degraded_window: Duration::from_secs(DEFAULT_DEGRADED_WINDOW_SECS),
+            degraded_min_lookups: DEFAULT_DEGRADED_MIN_LOOKUPS,
+            capability_probe: Some(Duration::from_secs(DEFAULT_CAPABILITY_PROBE_SECS)),
+            cache_size: DEFAULT_CACHE_SIZE,
+            cache_shards: default_cache_shards(),
+            proto: Proto::Udp,
+            upstream_qps: None,
+            upstream_qps_wait: false,
+            cache_file: None,
+            zone_stats: false,
+            psl_file: None,
+            zone_stats_file: None,
+            zone_stats_dump: Duration::from_secs(DEFAULT_ZONE_STATS_DUMP_SECS),
+            upstream_bind_addrs: Vec::new(),
+            source_ports: None,
+            bind_interface: None,
+            compress_min_bytes: DEFAULT_COMPRESS_MIN_BYTES,
+            enable_debug: false,
+            debug_wire: false,
+            auth_token: None,
+            enable_any: false,
+            enable_probe: false,
+            probe_max_ports: DEFAULT_PROBE_MAX_PORTS,
+            enable_ui: false,
+            strict_params: false,
+            answer_filters: Vec::new(),
+            allowed_types: parse_types(DEFAULT_ALLOWED_TYPES),
+            admin_bypass_types: false,
+            type_limits: HashMap::new(),
+            aliases: HashMap::new(),
+            strip_port: false,
+            allowlist: None,
+            verbose_errors: false,
+            empty_retry: false,
+            link_local: LinkLocal::Drop,
+            max_resolve_depth: DEFAULT_MAX_RESOLVE_DEPTH,
+            cache_headers: false,
+            shuffle_cache: ShuffleCache::Private,
+            robots_txt: DEFAULT_ROBOTS_TXT.into(),
+            max_response_bytes: DEFAULT_MAX_RESPONSE_BYTES,
+            test_hosts: Vec::new(),
+            default_record_ttl: DEFAULT_RECORD_TTL,
+        }
+    }
+}
+
+/// Comma-separated names of built-in answer filters.
+fn parse_filters(v: &str) -> Vec<String> {
+    v.split(',')
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+        .map(|name| match filter::builtin(name) {
+            Some(_) => name.into(),
+            None => panic!("invalid {}: {}", ENV_ANSWER_FILTERS, name),
+        })
+        .collect()
+}
+
+/// One cache shard per CPU, rounded up to a power of two.
+fn default_cache_shards() -> usize {
+    std::thread::available_parallelism()
+        .map_or(1, usize::from)
+        .next_power_of_two()
+}
+
+/// `shards` rounded up to a power of two, lowered until each of them holds
+/// an answer of `cache_size`.
+pub fn cache_shards(shards: usize, cache_size: usize) -> usize {
+    let mut shards = shards.max(1).next_power_of_two();
+    while shards > cache_size.max(1) {
+        shards /= 2;
+    }
+    shards
+}
+
+/// Capacity of each of `shards` resolver caches, `cache_size` in all.
+pub fn shard_cache_sizes(cache_size: usize, shards: usize) -> Vec<usize> {
+    (0..shards)
+        .map(|i| cache_size / shards + usize::from(i < cache_size % shards))
+        .collect()
+}
+
+/// Parses an env variable, unset or empty gives the default.
+fn env_or<T: FromStr>(key: &str, default: T) -> T {
+    match env::var(key) {
+        Ok(v) if !v.is_empty() => v
+            .parse()
+            .unwrap_or_else(|_| panic!("invalid {}: {}", key, v)),
+        _ => default,
+    }
+}
+
+/// Boolean env variable, `1` or `true` enables it.
+pub fn env_flag(key: &str) -> bool {
+    matches!(env::var(key).as_deref(), Ok("1") | Ok("true"))
+}
+
+/// Milliseconds env variable, `0` gives `None`.
+fn env_millis(key: &str, default: u64) -> Option<Duration> {
+    match env_or(key, default) {
+        0 => None,
+        ms => Some(Duration::from_millis(ms)),
+    }
+}
+
+/// Fraction env variable, `0` gives `None`.
+fn parse_rate(key: &str, default: f64) -> Option<f64> {
+    match env_or(key, default) {
+        0.0 => None,
+        rate if rate > 0.0 && rate <= 1.0 => Some(rate),
+        rate => panic!("invalid {}: {}", key, rate),
+    }
+}
+
+/// Parses `zone=ip:port,...`.
+fn parse_masters(s: &str) -> HashMap<String, SocketAddr> {
+    s.split(',')
+        .map(str::trim)
+        .filter(|v| !v.is_empty())
+        .map(
+            |v| match v.split_once('=').map(|(zone, addr)| (zone, addr.parse())) {
+                Some((zone, Ok(addr))) if !zone.is_empty() => {
+                    (zone.trim_end_matches('.').to_ascii_lowercase(), addr)
+                }
+                _ => panic!("invalid {}: {}", ENV_AXFR_MASTERS, v),
+            },
+        )
+        .collect()
+}
+
+/// Parses `ip[,ip]`, an IPv4 and an IPv6 address in either order.
+pub fn parse_bind_addrs(s: &str) -> Vec<IpAddr> {
+    let addrs = s
+        .split(',')
+        .map(str::trim)
+        .filter(|v| !v.is_empty())
+        .map(|v| {
+            v.parse::<IpAddr>()
+                .unwrap_or_else(|_| panic!("invalid {}: {}", ENV_UPSTREAM_BIND_ADDR, v))
+        })
+        .collect::<Vec<_>>();
+    if addrs.len() > 2 || addrs.len() == 2 && addrs[0].is_ipv4() == addrs[1].is_ipv4() {
+        panic!("invalid {}: one address per family", ENV_UPSTREAM_BIND_ADDR);
+    }
+    addrs
+}
+
+/// Parses `port` or `first-last`, empty gives `None`.
+pub fn parse_port_range(s: &str) -> Option<RangeInclusive<u16>> {
+    let s = s.trim();
+    if s.is_empty() {
+        return None;
+    }
+    let (first, last) = s.split_once('-').unwrap_or((s, s));
+    match (first.trim().parse::<u16>(), last.trim().parse::<u16>()) {
+        (Ok(first), Ok(last)) if first > 0 && first <= last => Some(first..=last),
+        _ => panic!("invalid {}: {}", ENV_SOURCE_PORT_RANGE, s),
+    }
+}
+
+/// Parses a list of record types, `*` allows all of them.
+pub fn parse_types(s: &str) -> Option<Vec<RecordType>> {
+    if s.trim() == "*" {
+        return None;
+    }
+    let types = s
+        .split(',')
+        .map(str::trim)
+        .filter(|v| !v.is_empty())
+        .map(|v| {
+            rdata::parse_type(v).unwrap_or_else(|| panic!("invalid {}: {}", ENV_ALLOWED_TYPES, v))
+        })
+        .collect();
+    Some(types)
+}
+
+/// Parses `TYPE=limit,...` such as `TXT=5,A=16`.
+pub fn parse_type_limits(s: &str) -> HashMap<RecordType, usize> {
+    s.split(',')
+        .map(str::trim)
+        .filter(|v| !v.is_empty())
+        .map(|v| {
+            let limit = v.split_once('=').and_then(|(rtype, limit)| {
+                let limit = limit.trim().parse().ok().filter(|limit| *limit > 0)?;
+                Some((rdata::parse_type(rtype.trim())?, limit))
+            });
+            limit.unwrap_or_else(|| panic!("invalid {}: {}", ENV_TYPE_LIMITS, v))
+        })
+        .collect()
+}
+
+/// Parses `alias=target,...`, keys are case-insensitive.
+pub fn parse_aliases(s: &str) -> HashMap<String, String> {
+    s.split(',')
+        .map(str::trim)
+        .filter(|v| !v.is_empty())
+        .map(|v| match v.split_once('=') {
+            Some((alias, target)) if !alias.is_empty() && !target.is_empty() => (
+                alias.trim().to_ascii_lowercase(),
+                target.trim().trim_end_matches('.').to_string(),
+            ),
+            _ => panic!("invalid {}: {}", ENV_ALIASES, v),
+        })
+        .collect()
+}
+
+/// Domains of `ALLOWLIST` when `MODE` is `allowlist`, an empty list
+/// resolving nothing. Unset `MODE` resolves everything.
+pub fn parse_allowlist(mode: &str, list: &str) -> Option<Vec<String>> {
+    match mode.trim() {
+        "" => None,
+        "allowlist" => Some(
+            list.split(',')
+                .map(|v| v.trim().trim_end_matches('.').to_ascii_lowercase())
+                .filter(|v| !v.is_empty())
+                .collect(),
+        ),
+        _ => panic!("invalid {}: {}", ENV_MODE, mode),
+    }
+}
+
+/// Boolean env variable with a default, `0` or `false` disables it.
+fn env_flag_or(key: &str, default: bool) -> bool {
+    match env::var(key).as_deref() {
+        Ok("1") | Ok("true") => true,
+        Ok("0") | Ok("false") => false,
+        Ok(v) if !v.is_empty() => panic!("invalid {}: {}", key, v),
+        _ => default,
+    }
+}
+
+/// Parses `ip:port,...`, checked when the resolvers are built.
+pub fn parse_dns(s: &str) -> Vec<String> {
+    s.split(',').map(ToString::to_string).collect()
+}
+
+pub fn get_opts() -> Opts {
+    let cache_size = env_or(ENV_CACHE_SIZE, DEFAULT_CACHE_SIZE).max(1);
+    Opts {
+        dns: parse_dns(&env::var(ENV_DNS).unwrap_or_else(|_| DEFAULT_DNS.into())),
+        addr: env::var(ENV_ADDR).unwrap_or_else(|_| DEFAULT_ADDR.into()),
+        cert_file: env::var(ENV_CERT_FILE).ok(),
+        key_file: env::var(ENV_KEY_FILE).ok(),
+        ws_concurrency: env_or(ENV_WS_CONCURRENCY, DEFAULT_WS_CONCURRENCY).max(1),
+        concurrency: env_or(ENV_CONCURRENCY, DEFAULT_CONCURRENCY).max(1),
+        ws_idle_timeout: Duration::from_secs(env_or(ENV_WS_IDLE_TIMEOUT, DEFAULT_WS_IDLE_TIMEOUT)),
+        trailing_newline: env_flag(ENV_TRAILING_NEWLINE),
+        text_contract: env_flag(ENV_TEXT_CONTRACT),
+        hedge_after: env_millis(ENV_HEDGE_AFTER_MS, 0),
+        hedge_max_ratio: env_or(ENV_HEDGE_MAX_RATIO, DEFAULT_HEDGE_MAX_RATIO),
+        shadow_dns: env::var(ENV_SHADOW_DNS).ok().filter(|v| !v.is_empty()),
+        shadow_sample: parse_rate(ENV_SHADOW_SAMPLE, DEFAULT_SHADOW_SAMPLE),
+        cors_enabled: env_flag_or(ENV_CORS_ENABLED, true),
+        allow_private_network: env_flag(ENV_ALLOW_PRIVATE_NETWORK),
+        route_timeout: env_millis(ENV_ROUTE_TIMEOUT_MS, DEFAULT_ROUTE_TIMEOUT_MS),
+        batch_route_timeout: env_millis(ENV_BATCH_ROUTE_TIMEOUT_MS, DEFAULT_BATCH_ROUTE_TIMEOUT_MS),
+        deadline_header: env::var(ENV_DEADLINE_HEADER).ok().filter(|v| !v.is_empty()),
+        axfr_allowed_zones: env::var(ENV_AXFR_ALLOWED_ZONES)
+            .unwrap_or_default()
+            .split(',')
+            .map(|v| v.trim().trim_end_matches('.').to_ascii_lowercase())
+            .filter(|v| !v.is_empty())
+            .collect(),
+        axfr_masters: parse_masters(&env::var(ENV_AXFR_MASTERS).unwrap_or_default()),
+        axfr_max_records: env_or(ENV_AXFR_MAX_RECORDS, DEFAULT_AXFR_MAX_RECORDS),
+        axfr_max_bytes: env_or(ENV_AXFR_MAX_BYTES, DEFAULT_AXFR_MAX_BYTES),
+        degraded_error_rate: parse_rate(ENV_DEGRADED_ERROR_RATE, DEFAULT_DEGRADED_ERROR_RATE),
+        degraded_window: Duration::from_secs(
+            env_or(ENV_DEGRADED_WINDOW_SECS, DEFAULT_DEGRADED_WINDOW_SECS).max(1),
+        ),
+        degraded_min_lookups: env_or(ENV_DEGRADED_MIN_LOOKUPS, DEFAULT_DEGRADED_MIN_LOOKUPS),
+        capability_probe: match env_or(ENV_CAPABILITY_PROBE_SECS, DEFAULT_CAPABILITY_PROBE_SECS) {
+            0 => None,
+            secs => Some(Duration::from_secs(secs)),
+        },
+        cache_size,
+        cache_shards: cache_shards(env_or(ENV_CACHE_SHARDS, default_cache_shards()), cache_size),
+        proto: env_or(ENV_PROTO, Proto::Udp),
+        upstream_qps: match env_or(ENV_UPSTREAM_QPS, 0) {
+            0 => None,
+            qps => Some(qps),
+        },
+        upstream_qps_wait: env_flag(ENV_UPSTREAM_QPS_WAIT),
+        cache_file: Some(env_or(ENV_CACHE_FILE, PathBuf::from(DEFAULT_CACHE_FILE)))
+            .filter(|_| env_flag(ENV_PERSIST_CACHE)),
+        zone_stats: env_flag(ENV_ZONE_STATS),
+        psl_file: env::var(ENV_PSL_FILE)
+            .ok()
+            .filter(|v| !v.is_empty())
+            .map(PathBuf::from),
+        zone_stats_file: env::var(ENV_ZONE_STATS_FILE)
+            .ok()
+            .filter(|v| !v.is_empty())
+            .map(PathBuf::from),
+        zone_stats_dump: Duration::from_secs(
+            env_or(ENV_ZONE_STATS_DUMP_SECS, DEFAULT_ZONE_STATS_DUMP_SECS).max(1),
+        ),
+        upstream_bind_addrs: parse_bind_addrs(
+            &env::var(ENV_UPSTREAM_BIND_ADDR).unwrap_or_default(),
+        ),
+        source_ports: parse_port_range(&env::var(ENV_SOURCE_PORT_RANGE).unwrap_or_default()),
+        bind_interface: env::var(ENV_BIND_INTERFACE).ok().filter(|v| !v.is_empty()),
+        compress_min_bytes: env_or(ENV_COMPRESS_MIN_BYTES, DEFAULT_COMPRESS_MIN_BYTES),
+        enable_debug: env_flag(ENV_ENABLE_DEBUG),
+        debug_wire: env_flag(ENV_DEBUG_WIRE),
+        auth_token: env::var(ENV_AUTH_TOKEN).ok().filter(|v| !v.is_empty()),
+        enable_any: env_flag(ENV_ENABLE_ANY),
+        enable_probe: env_flag(ENV_ENABLE_PROBE),
+        probe_max_ports: env_or(ENV_PROBE_MAX_PORTS, DEFAULT_PROBE_MAX_PORTS),
+        enable_ui: env_flag(ENV_ENABLE_UI),
+        strict_params: env_flag(ENV_STRICT_PARAMS),
+        answer_filters: parse_filters(&env::var(ENV_ANSWER_FILTERS).unwrap_or_default()),
+        allowed_types: parse_types(
+            &env::var(ENV_ALLOWED_TYPES).unwrap_or_else(|_| DEFAULT_ALLOWED_TYPES.into()),
+        ),
+        admin_bypass_types: env_flag(ENV_ADMIN_BYPASS_TYPES),
+        type_limits: parse_type_limits(&env::var(ENV_TYPE_LIMITS).unwrap_or_default()),
+        aliases: parse_aliases(&env::var(ENV_ALIASES).unwrap_or_default()),
+        strip_port: env_flag(ENV_STRIP_PORT),
+        allowlist: parse_allowlist(
+            &env::var(ENV_MODE).unwrap_or_default(),
+            &env::var(ENV_ALLOWLIST).unwrap_or_default(),
+        ),
+        verbose_errors: env_flag(ENV_VERBOSE_ERRORS),
+        empty_retry: env_flag(ENV_EMPTY_RETRY),
+        link_local: env_or(ENV_LINK_LOCAL, LinkLocal::Drop),
+        max_resolve_depth: env_or(ENV_MAX_RESOLVE_DEPTH, DEFAULT_MAX_RESOLVE_DEPTH),
+        cache_headers: env_flag(ENV_CACHE_HEADERS),
+        shuffle_cache: env_or(ENV_SHUFFLE_CACHE, ShuffleCache::Private),
+        // Env files hold one line, `\n` stands for a line break.
+        robots_txt: match env::var(ENV_ROBOTS_TXT) {
+            Ok(v) if !v.is_empty() => v.replace("\\n", "\n"),
+            _ => DEFAULT_ROBOTS_TXT.into(),
+        },
+        max_response_bytes: env_or(ENV_MAX_RESPONSE_BYTES, DEFAULT_MAX_RESPONSE_BYTES),
+        test_hosts: [
+            (ENV_TEST_NODATA_HOST, ResponseCode::NoError),
+            (ENV_TEST_NXDOMAIN_HOST, ResponseCode::NXDomain),
+            (ENV_TEST_SERVFAIL_HOST, ResponseCode::ServFail),
+        ]
+        .iter()
+        .filter_map(|(key, code)| match env::var(key) {
+            Ok(host) if !host.is_empty() => Some((host.trim_end_matches('.').into(), *code)),
+            _ => None,
+        })
+        .collect(),
+        default_record_ttl: env_or(ENV_DEFAULT_RECORD_TTL, DEFAULT_RECORD_TTL),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{cache_shards, parse_port_range, shard_cache_sizes};
+
+    #[test]
+    fn port_range() {
+        assert_eq!(parse_port_range(""), None);
+        assert_eq!(parse_port_range("5300"), Some(5300..=5300));
+        assert_eq!(parse_port_range(" 40000 - 40999 "), Some(40000..=40999));
+        for bad in ["0-10", "20-10", "1-70000", "x"] {
+            assert!(std::panic::catch_unwind(|| parse_port_range(bad)).is_err());
+        }
+    }
+
+    #[test]
+    fn cache_size() {
+        for (cache_size, shards) in [(1024, 8), (1024, 1), (1000, 16), (32, 64), (1, 4)] {
+            let shards = cache_shards(shards, cache_size);
+            assert!(shards.is_power_of_two());
+            let sizes = shard_cache_sizes(cache_size, shards);
+            assert_eq!(sizes.len(), shards);
+            assert_eq!(sizes.iter().sum::<usize>(), cache_size);
+            assert!(sizes
+                .iter()
+                .all(|&size| size >= cache_size / shards && size > 0));
+        }
+        assert_eq!(cache_shards(6, 1024), 8);
+        assert_eq!(cache_shards(64, 32), 32);
+        assert_eq!(cache_shards(0, 32), 1);
+    }
+}
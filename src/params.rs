@@ -0,0 +1,116 @@
+use std::sync::{Arc, Mutex};
+
+use serde::de::DeserializeOwned;
+use tide::{Middleware, Next, Request, StatusCode};
+
+use crate::cors::X_PARAM_ADJUSTED;
+use crate::metrics::Metrics;
+use crate::State;
+
+/// Range a numeric query parameter is clamped to.
+pub struct Limit {
+    pub key: &'static str,
+    pub min: i128,
+    pub max: i128,
+}
+
+/// Adjustments made to the query of the current request.
+#[derive(Clone, Default)]
+struct Adjustments(Arc<Mutex<Vec<String>>>);
+
+/// Reports the adjustments made by `query` in `X-Param-Adjusted`.
+pub struct Adjusted;
+
+#[tide::utils::async_trait]
+impl Middleware<State> for Adjusted {
+    async fn handle(&self, mut req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        let adjustments = Adjustments::default();
+        req.set_ext(adjustments.clone());
+        let mut res = next.run(req).await;
+        let adjusted = adjustments.0.lock().unwrap();
+        if !adjusted.is_empty() {
+            res.insert_header(X_PARAM_ADJUSTED, adjusted.join(", "));
+        }
+        Ok(res)
+    }
+}
+
+/// Parses the query of `req` like `Request::query`, after clamping the
+/// numbers outside their `limits`, as `n=300->255`. Values that do not
+/// parse are left for the 400, and so are clamped ones with
+/// `strict_params`.
+pub fn query<T: DeserializeOwned>(req: &Request<State>, limits: &[Limit]) -> tide::Result<T> {
+    let state = req.state();
+    let mut adjusted = Vec::new();
+    let query = req
+        .url()
+        .query()
+        .unwrap_or("")
+        .split('&')
+        .map(|pair| {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            let limit = limits.iter().find(|limit| limit.key == key);
+            let (limit, parsed) = match (limit, value.parse::<i128>()) {
+                (Some(limit), Ok(parsed)) => (limit, parsed),
+                _ => return Ok(pair.to_string()),
+            };
+            let clamped = parsed.clamp(limit.min, limit.max);
+            if clamped == parsed {
+                return Ok(pair.to_string());
+            }
+            if state.opts.strict_params {
+                return Err(tide::Error::from_str(
+                    StatusCode::BadRequest,
+                    format!("{} out of range: {}", key, value),
+                ));
+            }
+            adjusted.push(format!("{}={}->{}", key, value, clamped));
+            Ok(format!("{}={}", key, clamped))
+        })
+        .collect::<tide::Result<Vec<_>>>()?
+        .join("&");
+    let parsed = serde_qs::from_str(&query)
+        .map_err(|err| tide::Error::from_str(StatusCode::BadRequest, err.to_string()))?;
+    if !adjusted.is_empty() {
+        Metrics::inc(&state.metrics.params_adjusted);
+        if let Some(adjustments) = req.ext::<Adjustments>() {
+            adjustments.0.lock().unwrap().extend(adjusted);
+        }
+    }
+    Ok(parsed)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::Ordering;
+    use std::sync::Arc;
+
+    use crate::opts::Opts;
+    use crate::tests::{get, state};
+    use crate::upstream::mock::Mock;
+
+    #[async_std::test]
+    async fn adjusted() {
+        let ips = ["192.0.2.1", "192.0.2.2"];
+        let mut state = state(Mock::default().ips("one.example", &ips));
+        let mut res = get(&state, "/r/one.example?r=0&n=300").await;
+        assert_eq!(res.status(), 200);
+        assert_eq!(res["X-Param-Adjusted"], "n=300->255");
+        assert_eq!(res.body_string().await.unwrap(), "192.0.2.1\n192.0.2.2");
+        let res = get(&state, "/r/one.example?n=-1&pick=1000").await;
+        assert_eq!(res.status(), 400);
+        let res = get(&state, "/r/one.example?pick=1000").await;
+        assert_eq!(res["X-Param-Adjusted"], "pick=1000->255");
+        let res = get(&state, "/r/one.example?n=2").await;
+        assert!(res.header("X-Param-Adjusted").is_none());
+        assert_eq!(get(&state, "/r/one.example?n=abc").await.status(), 400);
+        assert_eq!(state.metrics.params_adjusted.load(Ordering::Relaxed), 3);
+        state.opts = Arc::new(Opts {
+            strict_params: true,
+            ..Opts::default()
+        });
+        let res = get(&state, "/r/one.example?n=300").await;
+        assert_eq!(res.status(), 400);
+        assert!(res.header("X-Param-Adjusted").is_none());
+    }
+}
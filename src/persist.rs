@@ -0,0 +1,307 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use async_trait::async_trait;
+use data_encoding::BASE64;
+use serde::{Deserialize, Serialize};
+use trust_dns_resolver::error::ResolveError;
+use trust_dns_resolver::lookup::Lookup;
+use trust_dns_resolver::proto::op::{Message, Query};
+use trust_dns_resolver::proto::rr::{Name, Record, RecordType};
+use trust_dns_resolver::proto::serialize::binary::{BinDecodable, BinEncodable};
+
+use crate::upstream::Upstream;
+
+/// Answers kept for the next start, new ones are not recorded past this.
+const MAX_ENTRIES: usize = 10_000;
+
+/// Lowercased host without the trailing dot, and the queried type.
+type Key = (String, RecordType);
+
+struct Cached {
+    records: Vec<Record>,
+    expires: SystemTime,
+}
+
+/// An answer as stored in the file.
+#[derive(Serialize, Deserialize)]
+struct Entry {
+    host: String,
+    /// `None` in files of versions asking A and AAAA together, skipped.
+    #[serde(rename = "type")]
+    rtype: Option<u16>,
+    /// Unix time the answer expires at.
+    expires: u64,
+    /// Records in wire format, base64.
+    records: Vec<String>,
+}
+
+/// Answers of `upstream` kept across restarts: recorded as they are
+/// resolved, written to `path` on shutdown and served after the next start
+/// until they expire. Only positive answers are kept.
+pub struct Persisted {
+    upstream: Arc<dyn Upstream>,
+    path: PathBuf,
+    loaded: HashMap<Key, Cached>,
+    recent: Mutex<HashMap<Key, Cached>>,
+}
+
+impl Persisted {
+    /// Loads the answers saved at `path`. A missing or corrupt file starts
+    /// empty, expired answers are discarded.
+    pub fn load(upstream: Arc<dyn Upstream>, path: &Path) -> Self {
+        let loaded = match fs::read(path) {
+            Ok(buf) => decode(&buf).unwrap_or_else(|err| {
+                tide::log::warn!("discarding cache file", {
+                    path: path.display().to_string(),
+                    error: err,
+                });
+                HashMap::new()
+            }),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => HashMap::new(),
+            Err(err) => {
+                tide::log::warn!("cannot read cache file", {
+                    path: path.display().to_string(),
+                    error: err.to_string(),
+                });
+                HashMap::new()
+            }
+        };
+        Self {
+            upstream,
+            path: path.into(),
+            loaded,
+            recent: Default::default(),
+        }
+    }
+
+    /// Answers loaded at startup.
+    pub fn len(&self) -> usize {
+        self.loaded.len()
+    }
+
+    /// Whether the answer of `host` to `rtype` is served from the file,
+    /// saved before the last restart.
+    pub fn serves_loaded(&self, host: &str, rtype: RecordType) -> bool {
+        self.loaded
+            .get(&key(host, rtype))
+            .is_some_and(|cached| cached.expires > SystemTime::now())
+    }
+
+    /// Writes the unexpired answers, through a temporary file so a crash
+    /// cannot leave half of one. Returns how many were written.
+    pub fn save(&self) -> io::Result<usize> {
+        let now = SystemTime::now();
+        let recent = self.recent.lock().unwrap();
+        let loaded = self
+            .loaded
+            .iter()
+            .filter(|(key, _)| !recent.contains_key(*key));
+        let entries = loaded
+            .chain(recent.iter())
+            .filter(|(_, cached)| cached.expires > now)
+            .map(|(key, cached)| encode(key, cached))
+            .collect::<Result<Vec<_>, _>>()?;
+        let tmp = self.path.with_extension("tmp");
+        fs::write(&tmp, serde_json::to_vec(&entries)?)?;
+        fs::rename(&tmp, &self.path)?;
+        Ok(entries.len())
+    }
+
+    /// Loaded answer of `key`, while it has not expired.
+    fn cached(&self, key: &Key) -> Result<Option<Lookup>, ResolveError> {
+        let cached = match self.loaded.get(key) {
+            Some(cached) => cached,
+            None => return Ok(None),
+        };
+        let ttl = match cached.expires.duration_since(SystemTime::now()) {
+            Ok(ttl) => ttl,
+            Err(_) => return Ok(None),
+        };
+        let query = Query::query(Name::from_str(&key.0)?, key.1);
+        Ok(Some(Lookup::new_with_deadline(
+            query,
+            Arc::from(cached.records.clone()),
+            Instant::now() + ttl,
+        )))
+    }
+
+    fn record(&self, key: Key, lookup: &Lookup) {
+        let records = lookup.record_iter().cloned().collect::<Vec<_>>();
+        if records.is_empty() {
+            return;
+        }
+        let now = SystemTime::now();
+        let expires = now
+            + lookup
+                .valid_until()
+                .saturating_duration_since(Instant::now());
+        let mut recent = self.recent.lock().unwrap();
+        if recent.len() >= MAX_ENTRIES && !recent.contains_key(&key) {
+            recent.retain(|_, cached| cached.expires > now);
+            if recent.len() >= MAX_ENTRIES {
+                return;
+            }
+        }
+        recent.insert(key, Cached { records, expires });
+    }
+}
+
+fn key(host: &str, rtype: RecordType) -> Key {
+    (host.trim_end_matches('.').to_ascii_lowercase(), rtype)
+}
+
+fn encode(key: &Key, cached: &Cached) -> io::Result<Entry> {
+    let records = cached
+        .records
+        .iter()
+        .map(|record| record.to_bytes().map(|buf| BASE64.encode(&buf)))
+        .collect::<Result<_, _>>()
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+    Ok(Entry {
+        host: key.0.clone(),
+        rtype: Some(key.1.into()),
+        expires: cached
+            .expires
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs(),
+        records,
+    })
+}
+
+fn decode(buf: &[u8]) -> Result<HashMap<Key, Cached>, String> {
+    let entries: Vec<Entry> = serde_json::from_slice(buf).map_err(|err| err.to_string())?;
+    let now = SystemTime::now();
+    let mut loaded = HashMap::new();
+    for entry in entries {
+        let expires = UNIX_EPOCH + Duration::from_secs(entry.expires);
+        let rtype = match entry.rtype {
+            Some(rtype) if expires > now => RecordType::from(rtype),
+            _ => continue,
+        };
+        let records = entry
+            .records
+            .iter()
+            .map(|record| {
+                let buf = BASE64
+                    .decode(record.as_bytes())
+                    .map_err(|err| err.to_string())?;
+                Record::from_bytes(&buf).map_err(|err| err.to_string())
+            })
+            .collect::<Result<_, _>>()?;
+        let key = key(&entry.host, rtype);
+        loaded.insert(key, Cached { records, expires });
+    }
+    Ok(loaded)
+}
+
+#[async_trait]
+impl Upstream for Persisted {
+    fn name(&self) -> &str {
+        self.upstream.name()
+    }
+
+    async fn lookup(&self, host: &str, rtype: RecordType) -> Result<Lookup, ResolveError> {
+        let key = key(host, rtype);
+        if let Some(lookup) = self.cached(&key)? {
+            return Ok(lookup);
+        }
+        let lookup = self.upstream.lookup(host, rtype).await?;
+        self.record(key, &lookup);
+        Ok(lookup)
+    }
+
+    async fn query(
+        &self,
+        host: &str,
+        rtype: RecordType,
+        dnssec_ok: bool,
+    ) -> Result<Message, ResolveError> {
+        self.upstream.query(host, rtype, dnssec_ok).await
+    }
+
+    async fn query_wire(
+        &self,
+        host: &str,
+        rtype: RecordType,
+        dnssec_ok: bool,
+    ) -> Result<Vec<u8>, ResolveError> {
+        self.upstream.query_wire(host, rtype, dnssec_ok).await
+    }
+
+    async fn probe(&self) {
+        self.upstream.probe().await
+    }
+
+    fn status(&self) -> Vec<serde_json::Value> {
+        self.upstream.status()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::sync::Arc;
+
+    use trust_dns_resolver::proto::rr::RecordType;
+
+    use super::Persisted;
+    use crate::upstream::mock::Mock;
+    use crate::upstream::Upstream;
+
+    #[async_std::test]
+    async fn persisted() {
+        let path = std::env::temp_dir().join(format!("bdns-cache-{}.json", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let mock = Mock::default()
+            .ips("one.example", &["192.0.2.1"])
+            .ips("two.example", &["2001:db8::2"]);
+        let persisted = Persisted::load(Arc::new(mock), &path);
+        assert_eq!(persisted.len(), 0);
+        persisted
+            .lookup("one.example", RecordType::A)
+            .await
+            .unwrap();
+        persisted
+            .lookup("two.example.", RecordType::AAAA)
+            .await
+            .unwrap();
+        assert!(persisted
+            .lookup("none.example", RecordType::A)
+            .await
+            .is_err());
+        assert_eq!(persisted.save().unwrap(), 2);
+
+        // The restarted upstream knows nothing, answers come from the file.
+        let persisted = Persisted::load(Arc::new(Mock::default()), &path);
+        assert_eq!(persisted.len(), 2);
+        let lookup = persisted.lookup("ONE.example.", RecordType::A).await;
+        assert_eq!(
+            lookup.unwrap().iter().next().unwrap().to_string(),
+            "192.0.2.1"
+        );
+        let lookup = persisted.lookup("Two.Example", RecordType::AAAA).await;
+        assert_eq!(
+            lookup.unwrap().iter().next().unwrap().to_string(),
+            "2001:db8::2"
+        );
+        assert!(persisted
+            .lookup("one.example", RecordType::MX)
+            .await
+            .is_err());
+
+        let json = fs::read_to_string(&path).unwrap();
+        let expired = json.replacen("\"expires\":", "\"expires\":1,\"x\":", 1);
+        fs::write(&path, expired).unwrap();
+        assert_eq!(Persisted::load(Arc::new(Mock::default()), &path).len(), 1);
+        fs::write(&path, "{not json").unwrap();
+        assert_eq!(Persisted::load(Arc::new(Mock::default()), &path).len(), 0);
+        let _ = fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,77 @@
+use tide::http::mime;
+use tide::{Middleware, Next, Request};
+
+/// Re-serializes JSON responses with indentation for requests with
+/// `pretty=1`, for reading with curl. Compact stays the default.
+pub struct Pretty;
+
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for Pretty {
+    async fn handle(&self, req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        let pretty = req
+            .url()
+            .query_pairs()
+            .any(|(key, value)| key == "pretty" && value == "1");
+        let mut res = next.run(req).await;
+        let is_json = res
+            .content_type()
+            .is_some_and(|mime| mime.essence() == mime::JSON.essence());
+        if !pretty || !is_json {
+            return Ok(res);
+        }
+        let body = res.take_body().into_bytes().await?;
+        match serde_json::from_slice::<serde_json::Value>(&body) {
+            Ok(value) => {
+                let mut body = serde_json::to_string_pretty(&value)?;
+                body.push('\n');
+                res.set_body(body);
+                res.set_content_type(mime::JSON);
+            }
+            Err(_) => res.set_body(body),
+        }
+        Ok(res)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tide::http::{Method, Request, Response, Url};
+
+    use crate::server;
+    use crate::tests::state;
+    use crate::upstream::mock::Mock;
+
+    async fn get(path: &str) -> Response {
+        let state = state(Mock::default().ips("one.example", &["192.0.2.1"]));
+        let url = Url::parse("http://localhost").unwrap().join(path).unwrap();
+        server(state)
+            .respond(Request::new(Method::Get, url))
+            .await
+            .unwrap()
+    }
+
+    #[async_std::test]
+    async fn pretty() {
+        let mut res = get("/r/one.example?format=json").await;
+        let compact = res.body_string().await.unwrap();
+        assert!(!compact.contains('\n'));
+        let mut res = get("/r/one.example?format=json&pretty=1").await;
+        assert_eq!(res["Content-Type"], "application/json");
+        let body = res.body_string().await.unwrap();
+        assert!(body.starts_with("{\n  \"answers\": [\n"));
+        let value: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(
+            value,
+            serde_json::from_str::<serde_json::Value>(&compact).unwrap()
+        );
+        let mut res = get("/version?pretty=1").await;
+        assert!(res
+            .body_string()
+            .await
+            .unwrap()
+            .contains("\n  \"version\": "));
+        // Text answers are left alone.
+        let mut res = get("/r/one.example?pretty=1").await;
+        assert_eq!(res.body_string().await.unwrap(), "192.0.2.1");
+    }
+}
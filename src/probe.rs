@@ -0,0 +1,211 @@
+use std::io;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use futures_util::stream::{self, StreamExt};
+use serde::Deserialize;
+use serde_json::json;
+use tide::{Request, Response, StatusCode};
+use trust_dns_resolver::proto::rr::Record;
+
+use crate::{
+    is_admin, lookup_addrs, lookup_error, normalize_host, params, type_allowed, type_not_allowed,
+    validate_host, Format, State, DEFAULT_N, NOT_FOUND, PROBE_DISABLED, RESOLVE_LIMITS,
+};
+
+/// Wait for a single connection attempt.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// All connection attempts of a request end by then.
+const PROBE_DEADLINE: Duration = Duration::from_secs(3);
+
+pub const REFUSED: &str = "refused";
+pub const TIMEOUT: &str = "timeout";
+pub const UNREACHABLE: &str = "unreachable";
+pub const ERROR: &str = "error";
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct ProbeQuery {
+    /// Comma-separated ports to connect to.
+    ports: Option<String>,
+    n: Option<u8>,
+}
+
+/// `/probe/:host?ports=80,443`: connects to each address of `host` on
+/// each port and answers the connect time in milliseconds or why it
+/// failed, per address and port. `refused` means the host is up, a
+/// `timeout` usually means a firewall drops the packets.
+pub async fn handler(req: Request<State>) -> tide::Result {
+    let host = match normalize_host(req.param("host")?) {
+        Some(host) if validate_host(&host) => host,
+        _ => return Ok(Response::builder(StatusCode::BadRequest).build()),
+    };
+    let query: ProbeQuery = params::query(&req, RESOLVE_LIMITS)?;
+    let state = req.state();
+    if !state.opts.enable_probe {
+        return Ok(Response::builder(StatusCode::Forbidden)
+            .body(PROBE_DISABLED)
+            .build());
+    }
+    if !type_allowed(&state.opts, is_admin(&req), None) {
+        return Ok(type_not_allowed());
+    }
+    let ports = match query.ports.as_deref().map(parse_ports) {
+        Some(Some(ports)) if ports.len() <= state.opts.probe_max_ports => ports,
+        _ => return Ok(Response::builder(StatusCode::BadRequest).build()),
+    };
+    let mut addrs = match lookup_addrs(state, &host, None).await {
+        Ok(addrs) => addrs,
+        Err(err) => return lookup_error(state, &host, err, Format::Json),
+    };
+    addrs.truncate(query.n.unwrap_or(DEFAULT_N).into());
+    if addrs.is_empty() {
+        return Ok(Response::builder(StatusCode::NotFound)
+            .body(NOT_FOUND)
+            .build());
+    }
+    let deadline = Instant::now() + PROBE_DEADLINE;
+    let pairs = addrs
+        .iter()
+        .flat_map(|ip| ports.iter().map(move |port| SocketAddr::new(*ip, *port)))
+        .collect::<Vec<_>>();
+    let outcomes = stream::iter(pairs)
+        .map(|addr| async move { connect(addr, deadline).await })
+        .buffered(state.opts.concurrency)
+        .collect::<Vec<_>>()
+        .await;
+    let results = addrs
+        .iter()
+        .zip(outcomes.chunks(ports.len()))
+        .map(|(ip, outcomes)| {
+            let ports = ports
+                .iter()
+                .zip(outcomes)
+                .map(|(port, outcome)| match outcome {
+                    Ok(elapsed) => json!({ "port": port, "ms": millis(*elapsed) }),
+                    Err(kind) => json!({ "port": port, "error": kind }),
+                })
+                .collect::<Vec<_>>();
+            json!({ "ip": ip, "ports": ports })
+        })
+        .collect::<Vec<_>>();
+    Ok(json!({ "host": host, "results": results }).into())
+}
+
+/// Address records accepting a TCP connection on `port`, in their order,
+/// for `reachable=PORT`.
+pub async fn reachable(state: &State, records: Vec<Record>, port: u16) -> Vec<Record> {
+    let deadline = Instant::now() + PROBE_DEADLINE;
+    stream::iter(records)
+        .map(|record| async move {
+            let addr = SocketAddr::new(record.rdata().to_ip_addr()?, port);
+            connect(addr, deadline).await.ok().map(|_| record)
+        })
+        .buffered(state.opts.concurrency)
+        .filter_map(|record| async move { record })
+        .collect()
+        .await
+}
+
+/// Time to connect to `addr`, giving up after `PROBE_TIMEOUT` or at
+/// `deadline`, or the kind of failure.
+async fn connect(addr: SocketAddr, deadline: Instant) -> Result<Duration, &'static str> {
+    let left = deadline.saturating_duration_since(Instant::now());
+    let start = Instant::now();
+    let connect = async_std::net::TcpStream::connect(addr);
+    match async_std::io::timeout(left.min(PROBE_TIMEOUT), connect).await {
+        Ok(_) => Ok(start.elapsed()),
+        Err(err) => Err(error_kind(&err)),
+    }
+}
+
+fn error_kind(err: &io::Error) -> &'static str {
+    match err.kind() {
+        io::ErrorKind::ConnectionRefused => REFUSED,
+        io::ErrorKind::TimedOut => TIMEOUT,
+        io::ErrorKind::HostUnreachable | io::ErrorKind::NetworkUnreachable => UNREACHABLE,
+        _ => ERROR,
+    }
+}
+
+/// Comma-separated non-zero ports, `None` if any is not one.
+fn parse_ports(v: &str) -> Option<Vec<u16>> {
+    v.split(',')
+        .map(|port| port.trim().parse().ok().filter(|port| *port != 0))
+        .collect::<Option<Vec<u16>>>()
+        .filter(|ports| !ports.is_empty())
+}
+
+/// Milliseconds with microsecond precision.
+fn millis(elapsed: Duration) -> f64 {
+    elapsed.as_micros() as f64 / 1000.0
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use std::sync::Arc;
+
+    use async_std::net::TcpListener;
+
+    use super::{error_kind, parse_ports};
+    use crate::opts::Opts;
+    use crate::tests::{get, state};
+    use crate::upstream::mock::Mock;
+
+    #[async_std::test]
+    async fn probe() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let open = listener.local_addr().unwrap().port();
+        let closed = {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            listener.local_addr().unwrap().port()
+        };
+        let mut state = state(Mock::default().ips("one.example", &["127.0.0.1", "127.0.0.2"]));
+        let path = format!("/probe/one.example?ports={},{}", open, closed);
+        assert_eq!(get(&state, &path).await.status(), 403);
+        state.opts = Arc::new(Opts {
+            enable_probe: true,
+            probe_max_ports: 2,
+            ..Opts::default()
+        });
+        let mut res = get(&state, &path).await;
+        assert_eq!(res.status(), 200);
+        let body: serde_json::Value = res.body_json().await.unwrap();
+        let results = &body["results"];
+        assert_eq!(results[0]["ip"], "127.0.0.1");
+        assert_eq!(results[0]["ports"][0]["port"], open);
+        assert!(results[0]["ports"][0]["ms"].is_f64());
+        assert_eq!(results[0]["ports"][1]["error"], "refused");
+        assert_eq!(results[1]["ip"], "127.0.0.2");
+        assert_eq!(results[1]["ports"][0]["error"], "refused");
+        for path in [
+            "/probe/one.example",
+            "/probe/one.example?ports=0",
+            "/probe/one.example?ports=80,x",
+            "/probe/one.example?ports=80,443,8443",
+        ] {
+            assert_eq!(get(&state, path).await.status(), 400, "{}", path);
+        }
+        assert_eq!(
+            get(&state, "/probe/none.example?ports=80").await.status(),
+            404
+        );
+    }
+
+    #[test]
+    fn kinds() {
+        assert_eq!(parse_ports(" 80, 443"), Some(vec![80, 443]));
+        assert_eq!(parse_ports(""), None);
+        for (kind, name) in [
+            (io::ErrorKind::ConnectionRefused, "refused"),
+            (io::ErrorKind::TimedOut, "timeout"),
+            (io::ErrorKind::HostUnreachable, "unreachable"),
+            (io::ErrorKind::NetworkUnreachable, "unreachable"),
+            (io::ErrorKind::PermissionDenied, "error"),
+        ] {
+            assert_eq!(error_kind(&io::Error::from(kind)), name);
+        }
+    }
+}
@@ -0,0 +1,421 @@
+use data_encoding::{BASE64, HEXUPPER};
+use serde_json::{json, Map, Value};
+use trust_dns_resolver::proto::rr::rdata::caa;
+use trust_dns_resolver::proto::rr::{Name, RData, Record, RecordType};
+use trust_dns_resolver::proto::serialize::binary::{BinDecodable, BinDecoder};
+
+/// Not known to trust-dns, decoded here from the raw rdata.
+pub const RRSIG: u16 = 46;
+const SMIMEA: u16 = 53;
+const URI: u16 = 256;
+
+/// Record types accepted by `t=`.
+const TYPES: &[(&str, RecordType)] = &[
+    ("A", RecordType::A),
+    ("AAAA", RecordType::AAAA),
+    ("ANY", RecordType::ANY),
+    ("CAA", RecordType::CAA),
+    ("CNAME", RecordType::CNAME),
+    ("MX", RecordType::MX),
+    ("NS", RecordType::NS),
+    ("OPENPGPKEY", RecordType::OPENPGPKEY),
+    ("PTR", RecordType::PTR),
+    ("RRSIG", RecordType::Unknown(RRSIG)),
+    ("SMIMEA", RecordType::Unknown(SMIMEA)),
+    ("SOA", RecordType::SOA),
+    ("SRV", RecordType::SRV),
+    ("SSHFP", RecordType::SSHFP),
+    ("TLSA", RecordType::TLSA),
+    ("TXT", RecordType::TXT),
+    ("URI", RecordType::Unknown(URI)),
+];
+
+/// Parses a mnemonic or the RFC 3597 `TYPE<n>` form.
+pub fn parse_type(s: &str) -> Option<RecordType> {
+    if let Some((_, rtype)) = TYPES.iter().find(|(name, _)| name.eq_ignore_ascii_case(s)) {
+        return Some(*rtype);
+    }
+    let code = s.get(..4)?;
+    if !code.eq_ignore_ascii_case("TYPE") {
+        return None;
+    }
+    s[4..].parse::<u16>().ok().map(RecordType::from)
+}
+
+pub fn type_name(rtype: RecordType) -> String {
+    match TYPES.iter().find(|(_, t)| *t == rtype) {
+        Some((name, _)) => (*name).into(),
+        None => match rtype {
+            RecordType::Unknown(code) => format!("TYPE{}", code),
+            _ => rtype.to_string(),
+        },
+    }
+}
+
+/// Presentation format of the rdata.
+pub fn text(rdata: &RData) -> String {
+    match rdata {
+        RData::Unknown { code, rdata } => {
+            let raw = rdata.anything().unwrap_or_default();
+            match *code {
+                SMIMEA => smimea(raw).map(|(usage, selector, matching, data)| {
+                    format!(
+                        "{} {} {} {}",
+                        usage,
+                        selector,
+                        matching,
+                        HEXUPPER.encode(data)
+                    )
+                }),
+                URI => uri(raw).map(|(priority, weight, target)| {
+                    format!("{} {} \"{}\"", priority, weight, target)
+                }),
+                RRSIG => rrsig(raw).map(|sig| {
+                    format!(
+                        "{} {} {} {} {} {} {} {} {}",
+                        type_name(sig.type_covered),
+                        sig.algorithm,
+                        sig.labels,
+                        sig.original_ttl,
+                        sig.expiration,
+                        sig.inception,
+                        sig.key_tag,
+                        sig.signer,
+                        BASE64.encode(sig.signature)
+                    )
+                }),
+                _ => None,
+            }
+            .unwrap_or_else(|| generic(raw))
+        }
+        RData::TXT(txt) => txt
+            .txt_data()
+            .iter()
+            .map(|s| character_string(s))
+            .collect::<Vec<_>>()
+            .join(" "),
+        RData::CAA(caa) => format!(
+            "{} {} {}",
+            if caa.issuer_critical() { 128 } else { 0 },
+            caa.tag().as_str(),
+            character_string(&caa_value(caa.value()))
+        ),
+        _ => rdata.to_string(),
+    }
+}
+
+/// RFC 1035 quoted character-string, escaping quotes, backslashes and
+/// bytes outside printable ASCII as `\DDD`.
+fn character_string(raw: &[u8]) -> String {
+    let mut s = String::with_capacity(raw.len() + 2);
+    s.push('"');
+    for &b in raw {
+        match b {
+            b'"' | b'\\' => {
+                s.push('\\');
+                s.push(b.into());
+            }
+            b' '..=b'~' => s.push(b.into()),
+            _ => s.push_str(&format!("\\{:03}", b)),
+        }
+    }
+    s.push('"');
+    s
+}
+
+/// CAA value as sent, the issuer and its `key=value` parameters joined
+/// with `; `.
+fn caa_value(value: &caa::Value) -> Vec<u8> {
+    match value {
+        caa::Value::Issuer(name, params) => {
+            let mut parts = vec![name.as_ref().map(Name::to_ascii).unwrap_or_default()];
+            parts.extend(
+                params
+                    .iter()
+                    .map(|param| format!("{}={}", param.key(), param.value())),
+            );
+            parts.join("; ").into_bytes()
+        }
+        caa::Value::Url(url) => url.to_string().into_bytes(),
+        caa::Value::Unknown(raw) => raw.clone(),
+    }
+}
+
+/// JSON answer, `data` is an object for types with several fields.
+pub fn json(record: &Record) -> Value {
+    let rdata = record.rdata();
+    let data = match rdata {
+        RData::MX(mx) => json!({
+            "preference": mx.preference(),
+            "exchange": mx.exchange().to_string(),
+        }),
+        RData::SRV(srv) => json!({
+            "priority": srv.priority(),
+            "weight": srv.weight(),
+            "port": srv.port(),
+            "target": srv.target().to_string(),
+        }),
+        RData::TLSA(tlsa) => json!({
+            "usage": u8::from(tlsa.cert_usage()),
+            "selector": u8::from(tlsa.selector()),
+            "matching": u8::from(tlsa.matching()),
+            "cert_data": HEXUPPER.encode(tlsa.cert_data()),
+        }),
+        RData::SSHFP(sshfp) => json!({
+            "algorithm": u8::from(sshfp.algorithm()),
+            "fptype": u8::from(sshfp.fingerprint_type()),
+            "fingerprint": HEXUPPER.encode(sshfp.fingerprint()),
+        }),
+        RData::OPENPGPKEY(key) => json!({ "public_key": BASE64.encode(key.public_key()) }),
+        RData::Unknown { code, rdata } => {
+            let raw = rdata.anything().unwrap_or_default();
+            match *code {
+                SMIMEA => smimea(raw).map(|(usage, selector, matching, data)| {
+                    json!({
+                        "usage": usage,
+                        "selector": selector,
+                        "matching": matching,
+                        "cert_data": HEXUPPER.encode(data),
+                    })
+                }),
+                URI => uri(raw).map(|(priority, weight, target)| {
+                    json!({ "priority": priority, "weight": weight, "target": target })
+                }),
+                RRSIG => rrsig(raw).map(|sig| {
+                    json!({
+                        "type_covered": type_name(sig.type_covered),
+                        "algorithm": sig.algorithm,
+                        "labels": sig.labels,
+                        "original_ttl": sig.original_ttl,
+                        "expiration": sig.expiration,
+                        "inception": sig.inception,
+                        "key_tag": sig.key_tag,
+                        "signer": sig.signer.to_string(),
+                        "signature": BASE64.encode(sig.signature),
+                    })
+                }),
+                _ => None,
+            }
+            .unwrap_or_else(|| generic(raw).into())
+        }
+        _ => text(rdata).into(),
+    };
+    json!({
+        "name": record.name().to_string(),
+        "type": type_name(record.rr_type()),
+        "ttl": record.ttl(),
+        "data": data,
+    })
+}
+
+/// Columns of `csv`, the same for every record type.
+pub const CSV_HEADER: &str = "host,type,class,ttl,value";
+
+/// RFC 4180 row of a record, without the line break.
+pub fn csv(record: &Record) -> String {
+    [
+        record.name().to_ascii(),
+        type_name(record.rr_type()),
+        record.dns_class().to_string(),
+        record.ttl().to_string(),
+        text(record.rdata()),
+    ]
+    .iter()
+    .map(|field| csv_field(field))
+    .collect::<Vec<_>>()
+    .join(",")
+}
+
+/// Quotes fields holding commas, quotes or line breaks, doubling quotes.
+fn csv_field(field: &str) -> String {
+    if field.contains(&[',', '"', '\r', '\n'][..]) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.into()
+    }
+}
+
+/// `ascii_name` and UTS-46 `unicode_name` of a name, the latter falls back
+/// to the ASCII form with `unicode_error` set when it does not decode.
+pub fn unicode_names(name: &str) -> Map<String, Value> {
+    let ascii = name.to_ascii_lowercase();
+    let (unicode, result) = idna::domain_to_unicode(&ascii);
+    let mut names = Map::new();
+    names.insert("ascii_name".into(), ascii.clone().into());
+    if result.is_ok() {
+        names.insert("unicode_name".into(), unicode.into());
+    } else {
+        names.insert("unicode_name".into(), ascii.into());
+        names.insert("unicode_error".into(), true.into());
+    }
+    names
+}
+
+/// Adds `unicode_names` of the target of CNAME and PTR answers.
+pub fn add_unicode_names(answer: &mut Value, record: &Record) {
+    let target = match record.rdata() {
+        RData::CNAME(name) | RData::PTR(name) => name,
+        _ => return,
+    };
+    if let Value::Object(answer) = answer {
+        answer.extend(unicode_names(&target.to_ascii()));
+    }
+}
+
+/// RFC 3597 generic rdata.
+fn generic(raw: &[u8]) -> String {
+    if raw.is_empty() {
+        return "\\# 0".into();
+    }
+    format!("\\# {} {}", raw.len(), HEXUPPER.encode(raw))
+}
+
+/// Same layout as TLSA: usage, selector, matching type, data.
+fn smimea(raw: &[u8]) -> Option<(u8, u8, u8, &[u8])> {
+    match raw {
+        [usage, selector, matching, data @ ..] => Some((*usage, *selector, *matching, data)),
+        _ => None,
+    }
+}
+
+/// Priority, weight and the target URI.
+fn uri(raw: &[u8]) -> Option<(u16, u16, &str)> {
+    match raw {
+        [p1, p2, w1, w2, target @ ..] => Some((
+            u16::from_be_bytes([*p1, *p2]),
+            u16::from_be_bytes([*w1, *w2]),
+            std::str::from_utf8(target).ok()?,
+        )),
+        _ => None,
+    }
+}
+
+/// RFC 4034 RRSIG fields, times in seconds since the epoch.
+struct Rrsig<'a> {
+    type_covered: RecordType,
+    algorithm: u8,
+    labels: u8,
+    original_ttl: u32,
+    expiration: u32,
+    inception: u32,
+    key_tag: u16,
+    signer: Name,
+    signature: &'a [u8],
+}
+
+fn rrsig(raw: &[u8]) -> Option<Rrsig<'_>> {
+    let fixed = raw.get(..18)?;
+    let u32_at =
+        |i: usize| u32::from_be_bytes([fixed[i], fixed[i + 1], fixed[i + 2], fixed[i + 3]]);
+    // The signer name is never compressed, so it decodes on its own.
+    let mut decoder = BinDecoder::new(&raw[18..]);
+    let signer = Name::read(&mut decoder).ok()?;
+    let signature = &raw[18 + decoder.index()..];
+    Some(Rrsig {
+        type_covered: RecordType::from(u16::from_be_bytes([fixed[0], fixed[1]])),
+        algorithm: fixed[2],
+        labels: fixed[3],
+        original_ttl: u32_at(4),
+        expiration: u32_at(8),
+        inception: u32_at(12),
+        key_tag: u16::from_be_bytes([fixed[16], fixed[17]]),
+        signer,
+        signature,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use trust_dns_resolver::proto::rr::rdata::caa::{KeyValue, CAA};
+    use trust_dns_resolver::proto::rr::rdata::{NULL, TXT};
+    use trust_dns_resolver::proto::rr::{Name, RData, Record, RecordType};
+
+    use super::{csv, json as answer, parse_type, text, unicode_names};
+
+    /// RRSIG over A, algorithm 13, signed by x.example.
+    const RRSIG_A: [u8; 31] = [
+        0, 1, 13, 2, 0, 0, 1, 44, 0x65, 0x53, 0xf1, 0x00, 0x64, 0xbb, 0x5a, 0x80, 0x12, 0x34, 1,
+        b'x', 7, b'e', b'x', b'a', b'm', b'p', b'l', b'e', 0, 0xab, 0xcd,
+    ];
+
+    fn unknown(code: u16, raw: &[u8]) -> Record {
+        let rdata = RData::Unknown {
+            code,
+            rdata: NULL::with(raw.to_vec()),
+        };
+        Record::from_rdata(Name::from_ascii("x.example.").unwrap(), 60, rdata)
+    }
+
+    #[test]
+    fn types() {
+        assert_eq!(parse_type("smimea"), Some(RecordType::Unknown(53)));
+        assert_eq!(parse_type("SSHFP"), Some(RecordType::SSHFP));
+        assert_eq!(parse_type("TYPE65"), Some(RecordType::HTTPS));
+        assert_eq!(parse_type("TYPE65534"), Some(RecordType::Unknown(65534)));
+        assert_eq!(parse_type("BOGUS"), None);
+    }
+
+    #[test]
+    fn decode() {
+        let smimea = unknown(53, &[3, 1, 1, 0xab, 0xcd]);
+        assert_eq!(text(smimea.rdata()), "3 1 1 ABCD");
+        assert_eq!(
+            answer(&smimea)["data"],
+            json!({"usage": 3, "selector": 1, "matching": 1, "cert_data": "ABCD"}),
+        );
+        let uri = unknown(256, b"\x00\x0a\x00\x01https://example.com/");
+        assert_eq!(
+            answer(&uri)["data"],
+            json!({"priority": 10, "weight": 1, "target": "https://example.com/"}),
+        );
+        let other = unknown(65534, &[0x0a, 0x00]);
+        assert_eq!(answer(&other)["type"], "TYPE65534");
+        assert_eq!(answer(&other)["data"], "\\# 2 0A00");
+        assert_eq!(text(unknown(53, &[3]).rdata()), "\\# 1 03");
+        let rrsig = unknown(46, &RRSIG_A);
+        assert_eq!(
+            text(rrsig.rdata()),
+            "A 13 2 300 1700000000 1690000000 4660 x.example. q80="
+        );
+        assert_eq!(answer(&rrsig)["data"]["signer"], "x.example.");
+    }
+
+    #[test]
+    fn csv_rows() {
+        let name = Name::from_ascii("x.example.").unwrap();
+        let a = Record::from_rdata(name.clone(), 60, RData::A("192.0.2.1".parse().unwrap()));
+        assert_eq!(csv(&a), "x.example.,A,IN,60,192.0.2.1");
+        let txt = TXT::new(vec!["v=spf1 a, mx".into(), "say \"hi\"".into()]);
+        let txt = Record::from_rdata(name, 60, RData::TXT(txt));
+        assert_eq!(
+            csv(&txt),
+            r#"x.example.,TXT,IN,60,"""v=spf1 a, mx"" ""say \""hi\""""""#
+        );
+    }
+
+    #[test]
+    fn character_strings() {
+        let txt = TXT::new(vec!["v=spf1 a, mx".into(), "say \"hi\" \\ \u{7}".into()]);
+        assert_eq!(
+            text(&RData::TXT(txt)),
+            r#""v=spf1 a, mx" "say \"hi\" \\ \007""#
+        );
+        let name = Name::from_ascii("ca.example").unwrap();
+        let caa = CAA::new_issue(false, Some(name), vec![KeyValue::new("account", "1")]);
+        assert_eq!(text(&RData::CAA(caa)), r#"0 issue "ca.example; account=1""#);
+        let caa = CAA::new_issuewild(true, None, vec![]);
+        assert_eq!(text(&RData::CAA(caa)), r#"128 issuewild """#);
+    }
+
+    #[test]
+    fn unicode() {
+        let names = unicode_names("xn--mnchen-3ya.example.");
+        assert_eq!(names["ascii_name"], "xn--mnchen-3ya.example.");
+        assert_eq!(names["unicode_name"], "münchen.example.");
+        assert!(names.get("unicode_error").is_none());
+        let names = unicode_names("xn--a.example");
+        assert_eq!(names["unicode_name"], "xn--a.example");
+        assert_eq!(names["unicode_error"], true);
+    }
+}
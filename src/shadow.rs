@@ -0,0 +1,251 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use rand::rngs::SmallRng;
+use rand::Rng;
+use trust_dns_resolver::error::{ResolveError, ResolveErrorKind};
+use trust_dns_resolver::lookup::Lookup;
+use trust_dns_resolver::proto::op::{Message, ResponseCode};
+use trust_dns_resolver::proto::rr::RecordType;
+
+use crate::metrics::{self, Metrics};
+use crate::upstream::Upstream;
+use crate::{lock_rng, rdata};
+
+/// Shadow queries in flight at most, further copies are skipped.
+const SHADOW_CONCURRENCY: usize = 32;
+
+/// An answer as compared: the sorted records without TTLs, or the response
+/// code of an answer without records. `None` for failures such as timeouts.
+type Compared = Option<Result<Vec<String>, ResponseCode>>;
+
+/// `upstream`, with a `sample` of its lookups repeated at `shadow` once
+/// answered, comparing the answers and their latency in the background.
+/// Clients only ever see the answers of `upstream`.
+pub struct Shadowed {
+    upstream: Arc<dyn Upstream>,
+    shadow: Arc<dyn Upstream>,
+    sample: f64,
+    rng: Arc<Mutex<SmallRng>>,
+    metrics: Arc<Metrics>,
+    inflight: Arc<AtomicUsize>,
+}
+
+impl Shadowed {
+    pub fn new(
+        upstream: Arc<dyn Upstream>,
+        shadow: Arc<dyn Upstream>,
+        sample: f64,
+        rng: Arc<Mutex<SmallRng>>,
+        metrics: Arc<Metrics>,
+    ) -> Self {
+        Self {
+            upstream,
+            shadow,
+            sample,
+            rng,
+            metrics,
+            inflight: Default::default(),
+        }
+    }
+
+    /// Whether a lookup is in the sample, rolled before its answer is
+    /// compared so that the others cost nothing.
+    fn sampled(&self) -> bool {
+        lock_rng(&self.rng).gen_bool(self.sample)
+    }
+
+    /// Sends a copy of the sampled lookup of `host` to the shadow upstream,
+    /// without waiting for it, unless too many are in flight.
+    fn copy(&self, host: &str, rtype: RecordType, answer: Compared, elapsed: Duration) {
+        let reserved = self
+            .inflight
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |n| {
+                (n < SHADOW_CONCURRENCY).then_some(n + 1)
+            });
+        if reserved.is_err() {
+            self.metrics.shadow(metrics::SHADOW_SKIPPED);
+            return;
+        }
+        let (shadow, metrics, inflight) = (
+            self.shadow.clone(),
+            self.metrics.clone(),
+            self.inflight.clone(),
+        );
+        let host = host.to_string();
+        async_std::task::spawn(async move {
+            let start = Instant::now();
+            let shadowed = compared(shadow.lookup(&host, rtype).await.as_ref());
+            let outcome = match (&answer, &shadowed) {
+                (Some(_), Some(_)) if answer == shadowed => metrics::SHADOW_AGREE,
+                (Some(_), Some(_)) => {
+                    tide::log::info!("shadow disagrees", { host: host });
+                    metrics::SHADOW_DISAGREE
+                }
+                _ => metrics::SHADOW_ERROR,
+            };
+            metrics.shadow(outcome);
+            if outcome != metrics::SHADOW_ERROR {
+                metrics.shadow_latency(elapsed, start.elapsed());
+            }
+            inflight.fetch_sub(1, Ordering::AcqRel);
+        });
+    }
+}
+
+fn compared(result: Result<&Lookup, &ResolveError>) -> Compared {
+    match result {
+        Ok(lookup) => {
+            let mut records = lookup
+                .record_iter()
+                .map(|record| {
+                    let rtype = rdata::type_name(record.rr_type());
+                    format!(
+                        "{} {} {}",
+                        record.name(),
+                        rtype,
+                        rdata::text(record.rdata())
+                    )
+                })
+                .collect::<Vec<_>>();
+            records.sort_unstable();
+            Some(Ok(records))
+        }
+        Err(err) => match err.kind() {
+            ResolveErrorKind::NoRecordsFound { response_code, .. } => Some(Err(*response_code)),
+            _ => None,
+        },
+    }
+}
+
+#[async_trait]
+impl Upstream for Shadowed {
+    fn name(&self) -> &str {
+        self.upstream.name()
+    }
+
+    async fn lookup(&self, host: &str, rtype: RecordType) -> Result<Lookup, ResolveError> {
+        let start = Instant::now();
+        let result = self.upstream.lookup(host, rtype).await;
+        if self.sampled() {
+            let answer = compared(result.as_ref());
+            self.copy(host, rtype, answer, start.elapsed());
+        }
+        result
+    }
+
+    async fn query(
+        &self,
+        host: &str,
+        rtype: RecordType,
+        dnssec_ok: bool,
+    ) -> Result<Message, ResolveError> {
+        self.upstream.query(host, rtype, dnssec_ok).await
+    }
+
+    async fn query_wire(
+        &self,
+        host: &str,
+        rtype: RecordType,
+        dnssec_ok: bool,
+    ) -> Result<Vec<u8>, ResolveError> {
+        self.upstream.query_wire(host, rtype, dnssec_ok).await
+    }
+
+    async fn probe(&self) {
+        self.upstream.probe().await
+    }
+
+    fn status(&self) -> Vec<serde_json::Value> {
+        self.upstream.status()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::Ordering;
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, Instant};
+
+    use rand::rngs::SmallRng;
+    use rand::SeedableRng;
+    use trust_dns_resolver::proto::rr::RecordType;
+
+    use super::Shadowed;
+    use crate::metrics::Metrics;
+    use crate::upstream::mock::Mock;
+    use crate::upstream::Upstream;
+
+    fn pair(shadow: Mock, sample: f64) -> (Shadowed, Arc<Metrics>) {
+        let primary = Mock::default()
+            .ips("one.example", &["192.0.2.1"])
+            .ips("two.example", &["192.0.2.2", "192.0.2.3"]);
+        let metrics = Arc::new(Metrics::default());
+        let shadowed = Shadowed::new(
+            Arc::new(primary),
+            Arc::new(shadow),
+            sample,
+            Arc::new(Mutex::new(SmallRng::seed_from_u64(0))),
+            metrics.clone(),
+        );
+        (shadowed, metrics)
+    }
+
+    async fn settle(shadowed: &Shadowed) {
+        while shadowed.inflight.load(Ordering::Acquire) > 0 {
+            async_std::task::sleep(Duration::from_millis(5)).await;
+        }
+    }
+
+    #[async_std::test]
+    async fn compare() {
+        let shadow = Mock::default()
+            .ips("one.example", &["192.0.2.1"])
+            .ips("two.example", &["192.0.2.3", "192.0.2.4"]);
+        let (shadowed, metrics) = pair(shadow, 1.0);
+        shadowed.lookup("one.example", RecordType::A).await.unwrap();
+        let lookup = shadowed.lookup("two.example", RecordType::A).await.unwrap();
+        assert_eq!(lookup.iter().next().unwrap().to_string(), "192.0.2.2");
+        assert!(shadowed
+            .lookup("none.example", RecordType::A)
+            .await
+            .is_err());
+        settle(&shadowed).await;
+        let s = metrics.render();
+        assert!(s.contains("bdns_shadow_total{result=\"agree\"} 2\n"));
+        assert!(s.contains("bdns_shadow_total{result=\"disagree\"} 1\n"));
+        assert!(s.contains("bdns_shadow_total{result=\"error\"} 0\n"));
+
+        let (shadowed, metrics) = pair(Mock::default().down(), 1.0);
+        shadowed.lookup("one.example", RecordType::A).await.unwrap();
+        settle(&shadowed).await;
+        assert!(metrics
+            .render()
+            .contains("bdns_shadow_total{result=\"error\"} 1\n"));
+    }
+
+    #[async_std::test]
+    async fn background() {
+        let shadow = Mock::default().delay(Duration::from_secs(1));
+        let (shadowed, metrics) = pair(shadow, 1.0);
+        let start = Instant::now();
+        for _ in 0..40 {
+            shadowed.lookup("one.example", RecordType::A).await.unwrap();
+        }
+        assert!(start.elapsed() < Duration::from_millis(500));
+        let s = metrics.render();
+        assert!(s.contains("bdns_shadow_total{result=\"skipped\"} 8\n"));
+        settle(&shadowed).await;
+
+        let (shadowed, metrics) = pair(Mock::default(), 0.05);
+        for _ in 0..100 {
+            shadowed.lookup("one.example", RecordType::A).await.unwrap();
+        }
+        settle(&shadowed).await;
+        let s = metrics.render();
+        assert!(!s.contains("bdns_shadow_total{result=\"disagree\"} 0\n"));
+        assert!(!s.contains("bdns_shadow_total{result=\"disagree\"} 100\n"));
+    }
+}
@@ -0,0 +1,54 @@
+use tide::http::mime;
+use tide::{Request, Response, StatusCode};
+
+use crate::State;
+
+/// Lookup page, a client of `/version` and `/r` like any other.
+const PAGE: &str = include_str!("ui.html");
+
+/// `/` with `ENABLE_UI=1`: the lookup page.
+pub async fn handler(_req: Request<State>) -> tide::Result {
+    Ok(Response::builder(StatusCode::Ok)
+        .body(PAGE)
+        .content_type(mime::HTML)
+        .build())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use tide::http::{Method, Request, Response, Url};
+
+    use crate::opts::Opts;
+    use crate::server;
+    use crate::tests::state;
+    use crate::upstream::mock::Mock;
+
+    #[async_std::test]
+    async fn page() {
+        let url = Url::parse("http://localhost/").unwrap();
+        let res: Response = server(state(Mock::default()))
+            .respond(Request::new(Method::Get, url.clone()))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), 404);
+
+        let mut state = state(Mock::default());
+        state.opts = Arc::new(Opts {
+            enable_ui: true,
+            ..Default::default()
+        });
+        let mut res: Response = server(state)
+            .respond(Request::new(Method::Get, url))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), 200);
+        assert_eq!(res.content_type().unwrap().essence(), "text/html");
+        let page = res.body_string().await.unwrap();
+        // Nothing is loaded from elsewhere.
+        assert!(!page.contains("src="));
+        assert!(!page.contains("http"));
+        assert!(page.contains("\"/r/\""));
+    }
+}
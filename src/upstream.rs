@@ -0,0 +1,960 @@
+use std::collections::hash_map::DefaultHasher;
+use std::future::Future;
+use std::hash::{Hash, Hasher};
+use std::io;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use async_trait::async_trait;
+use futures_util::future::{self, BoxFuture, Either};
+use serde::Serialize;
+use serde_json::json;
+use trust_dns_resolver::error::{ResolveError, ResolveErrorKind};
+use trust_dns_resolver::lookup::Lookup;
+use trust_dns_resolver::proto::op::Message;
+use trust_dns_resolver::proto::rr::{Name, RecordType};
+
+use crate::attempts;
+use crate::bind::{self, BoundResolver};
+use crate::wire;
+
+#[async_trait]
+pub trait Upstream: Send + Sync {
+    /// Name of the upstream for logs.
+    fn name(&self) -> &str;
+
+    async fn lookup(&self, host: &str, rtype: RecordType) -> Result<Lookup, ResolveError>;
+
+    /// Whole response to a single query, bypassing the resolver cache, with
+    /// the EDNS DO bit if `dnssec_ok`. Only transport failures are errors.
+    async fn query(
+        &self,
+        host: &str,
+        rtype: RecordType,
+        dnssec_ok: bool,
+    ) -> Result<Message, ResolveError>;
+
+    /// `query`, answering the response as the bytes the upstream sent.
+    async fn query_wire(
+        &self,
+        host: &str,
+        rtype: RecordType,
+        dnssec_ok: bool,
+    ) -> Result<Vec<u8>, ResolveError> {
+        Ok(self.query(host, rtype, dnssec_ok).await?.to_vec()?)
+    }
+
+    /// Refreshes what is known of the transports of the upstream.
+    async fn probe(&self) {}
+
+    /// Name and known capabilities of each upstream, for `/admin/upstreams`.
+    fn status(&self) -> Vec<serde_json::Value> {
+        vec![json!({ "name": self.name() })]
+    }
+}
+
+/// What an upstream was seen to support, `None` when the probe failed.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct Capabilities {
+    /// Answers to a query with an OPT record carry one too.
+    pub edns: Option<bool>,
+    /// UDP payload size advertised in the OPT record of the answer.
+    pub udp_payload: Option<u16>,
+    /// Answers over TCP, `false` when connections are refused.
+    pub tcp: Option<bool>,
+    /// Unix time of the last probe.
+    pub checked_at: Option<u64>,
+}
+
+impl Capabilities {
+    /// A root NS query over UDP with EDNS, then one over TCP without.
+    pub async fn probe(addr: SocketAddr) -> Self {
+        let mut message = wire::query(Name::root(), RecordType::NS, false);
+        wire::set_edns(&mut message, false);
+        let udp = wire::exchange_udp(addr, &message).await.ok();
+        let message = wire::query(Name::root(), RecordType::NS, false);
+        let tcp = match wire::exchange_tcp(addr, &message).await {
+            Ok(_) => Some(true),
+            Err(err) if err.kind() == io::ErrorKind::ConnectionRefused => Some(false),
+            Err(_) => None,
+        };
+        Self {
+            edns: udp.as_ref().map(|res| res.edns().is_some()),
+            udp_payload: udp
+                .as_ref()
+                .and_then(|res| res.edns())
+                .map(|edns| edns.max_payload()),
+            tcp,
+            checked_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .ok()
+                .map(|since| since.as_secs()),
+        }
+    }
+}
+
+/// Resolvers of one upstream, each with a slice of the cache behind its
+/// own lock. A host always goes to the same shard.
+pub struct Resolver {
+    name: String,
+    addr: SocketAddr,
+    shards: Vec<BoundResolver>,
+    /// Single queries go over TCP only, not UDP first.
+    tcp: bool,
+    capabilities: RwLock<Capabilities>,
+}
+
+impl Resolver {
+    /// The number of `shards` must be a power of two.
+    pub fn new(name: String, addr: SocketAddr, shards: Vec<BoundResolver>) -> Self {
+        assert!(shards.len().is_power_of_two());
+        Self {
+            name,
+            addr,
+            shards,
+            tcp: false,
+            capabilities: Default::default(),
+        }
+    }
+
+    /// Sends single queries over TCP, for shards configured with TCP only.
+    pub fn tcp(mut self) -> Self {
+        self.tcp = true;
+        self
+    }
+
+    async fn exchange(&self, message: &Message) -> io::Result<(Message, Vec<u8>)> {
+        if self.tcp {
+            wire::exchange_tcp_wire(self.addr, message).await
+        } else {
+            wire::exchange_wire(self.addr, message).await
+        }
+    }
+
+    fn shard(&self, host: &str) -> &BoundResolver {
+        &self.shards[shard_index(host, self.shards.len())]
+    }
+}
+
+/// Shard of `host` among `shards`, a power of two, ignoring case and the
+/// trailing dot like the cache does.
+fn shard_index(host: &str, shards: usize) -> usize {
+    let mut hasher = DefaultHasher::new();
+    host.trim_end_matches('.')
+        .to_ascii_lowercase()
+        .hash(&mut hasher);
+    hasher.finish() as usize & (shards - 1)
+}
+
+#[async_trait]
+impl Upstream for Resolver {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    async fn lookup(&self, host: &str, rtype: RecordType) -> Result<Lookup, ResolveError> {
+        self.shard(host)
+            .lookup(host, rtype, Default::default())
+            .await
+    }
+
+    async fn query(
+        &self,
+        host: &str,
+        rtype: RecordType,
+        dnssec_ok: bool,
+    ) -> Result<Message, ResolveError> {
+        let message = wire::query(Name::from_str(host)?, rtype, dnssec_ok);
+        Ok(self.exchange(&message).await?.0)
+    }
+
+    async fn query_wire(
+        &self,
+        host: &str,
+        rtype: RecordType,
+        dnssec_ok: bool,
+    ) -> Result<Vec<u8>, ResolveError> {
+        let message = wire::query(Name::from_str(host)?, rtype, dnssec_ok);
+        Ok(self.exchange(&message).await?.1)
+    }
+
+    async fn probe(&self) {
+        let capabilities = Capabilities::probe(self.addr).await;
+        *self.capabilities.write().unwrap() = capabilities;
+    }
+
+    fn status(&self) -> Vec<serde_json::Value> {
+        let capabilities = self.capabilities.read().unwrap().clone();
+        let mut status = json!({
+            "name": self.name,
+            "capabilities": capabilities,
+            "bind_addrs": bind::bind_addrs(),
+        });
+        if let Some(interface) = bind::interface() {
+            status["interface"] = interface.into();
+        }
+        vec![status]
+    }
+}
+
+/// TTLs trust-dns caps cached answers to.
+const MAX_TTL: u32 = 86400;
+
+/// Hedging stops once this many hedges are banked, so a burst of slow
+/// answers cannot exceed the ratio by much.
+const HEDGE_BURST: f64 = 10.0;
+
+pub struct Hedge {
+    /// Delay before the query is repeated at the next upstream.
+    pub after: Duration,
+    /// Upper bound on hedged queries as a fraction of all queries.
+    pub max_ratio: f64,
+}
+
+/// Upstreams in the configured order. A query goes to the first one and
+/// moves on to the next on timeouts and transport errors, answers
+/// including NXDOMAIN and SERVFAIL are final.
+pub struct Pool {
+    name: String,
+    upstreams: Vec<Arc<dyn Upstream>>,
+    hedge: Option<Hedge>,
+    hedge_budget: Mutex<f64>,
+    hedges: Arc<AtomicU64>,
+}
+
+impl Pool {
+    pub fn new(
+        upstreams: Vec<Arc<dyn Upstream>>,
+        hedge: Option<Hedge>,
+        hedges: Arc<AtomicU64>,
+    ) -> Self {
+        let name = upstreams
+            .iter()
+            .map(|upstream| upstream.name())
+            .collect::<Vec<_>>()
+            .join(",");
+        Self {
+            name,
+            upstreams,
+            hedge,
+            hedge_budget: Mutex::new(0.0),
+            hedges,
+        }
+    }
+
+    async fn run<'a, T, F>(&'a self, query: F) -> Result<T, ResolveError>
+    where
+        T: Send + 'a,
+        F: Fn(&'a dyn Upstream) -> BoxFuture<'a, Result<T, ResolveError>>,
+    {
+        let (mut result, hedged) = self.first(&query).await;
+        // A hedge already asked the second upstream.
+        let asked = if hedged { 2 } else { 1 };
+        for upstream in self.upstreams.iter().skip(asked) {
+            match &result {
+                Err(err) if !is_answer(err) => {
+                    attempts::note(upstream.name());
+                    result = query(upstream.as_ref()).await
+                }
+                _ => break,
+            }
+        }
+        result
+    }
+
+    /// Queries the first upstream, hedging at the second one if enabled,
+    /// and tells whether it did.
+    async fn first<'a, T, F>(&'a self, query: &F) -> (Result<T, ResolveError>, bool)
+    where
+        T: Send + 'a,
+        F: Fn(&'a dyn Upstream) -> BoxFuture<'a, Result<T, ResolveError>>,
+    {
+        attempts::note(self.upstreams[0].name());
+        let primary = query(self.upstreams[0].as_ref());
+        let (hedge, secondary) = match (&self.hedge, self.upstreams.get(1)) {
+            (Some(hedge), Some(secondary)) => (hedge, secondary),
+            _ => return (primary.await, false),
+        };
+        self.earn_hedge(hedge);
+        let primary = match future::select(primary, sleep(hedge.after)).await {
+            Either::Left((result, _)) => return (result, false),
+            Either::Right((_, primary)) => primary,
+        };
+        if !self.take_hedge() {
+            return (primary.await, false);
+        }
+        self.hedges.fetch_add(1, Ordering::Relaxed);
+        attempts::note(secondary.name());
+        (first_answer(primary, query(secondary.as_ref())).await, true)
+    }
+
+    /// Every query earns `max_ratio` of a hedge, a hedge spends one.
+    fn earn_hedge(&self, hedge: &Hedge) {
+        let mut budget = self.hedge_budget.lock().unwrap();
+        *budget = (*budget + hedge.max_ratio).min(HEDGE_BURST);
+    }
+
+    fn take_hedge(&self) -> bool {
+        let mut budget = self.hedge_budget.lock().unwrap();
+        if *budget < 1.0 {
+            return false;
+        }
+        *budget -= 1.0;
+        true
+    }
+}
+
+#[async_trait]
+impl Upstream for Pool {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    async fn lookup(&self, host: &str, rtype: RecordType) -> Result<Lookup, ResolveError> {
+        self.run(|upstream| upstream.lookup(host, rtype)).await
+    }
+
+    async fn query(
+        &self,
+        host: &str,
+        rtype: RecordType,
+        dnssec_ok: bool,
+    ) -> Result<Message, ResolveError> {
+        self.run(|upstream| upstream.query(host, rtype, dnssec_ok))
+            .await
+    }
+
+    async fn query_wire(
+        &self,
+        host: &str,
+        rtype: RecordType,
+        dnssec_ok: bool,
+    ) -> Result<Vec<u8>, ResolveError> {
+        self.run(|upstream| upstream.query_wire(host, rtype, dnssec_ok))
+            .await
+    }
+
+    async fn probe(&self) {
+        future::join_all(self.upstreams.iter().map(|upstream| upstream.probe())).await;
+    }
+
+    fn status(&self) -> Vec<serde_json::Value> {
+        self.upstreams
+            .iter()
+            .flat_map(|upstream| upstream.status())
+            .collect()
+    }
+}
+
+/// The same upstream over UDP and over TCP, for `PROTO=race`. Every query
+/// goes out over both at once, costing twice the queries of either.
+pub struct Race {
+    udp: Arc<dyn Upstream>,
+    tcp: Arc<dyn Upstream>,
+}
+
+impl Race {
+    pub fn new(udp: Arc<dyn Upstream>, tcp: Arc<dyn Upstream>) -> Self {
+        Self { udp, tcp }
+    }
+
+    async fn run<'a, T, F>(&'a self, query: F) -> Result<T, ResolveError>
+    where
+        T: Send + 'a,
+        F: Fn(&'a dyn Upstream) -> BoxFuture<'a, Result<T, ResolveError>>,
+    {
+        first_answer(query(self.udp.as_ref()), query(self.tcp.as_ref())).await
+    }
+}
+
+#[async_trait]
+impl Upstream for Race {
+    fn name(&self) -> &str {
+        self.udp.name()
+    }
+
+    async fn lookup(&self, host: &str, rtype: RecordType) -> Result<Lookup, ResolveError> {
+        self.run(|upstream| upstream.lookup(host, rtype)).await
+    }
+
+    async fn query(
+        &self,
+        host: &str,
+        rtype: RecordType,
+        dnssec_ok: bool,
+    ) -> Result<Message, ResolveError> {
+        self.run(|upstream| upstream.query(host, rtype, dnssec_ok))
+            .await
+    }
+
+    async fn query_wire(
+        &self,
+        host: &str,
+        rtype: RecordType,
+        dnssec_ok: bool,
+    ) -> Result<Vec<u8>, ResolveError> {
+        self.run(|upstream| upstream.query_wire(host, rtype, dnssec_ok))
+            .await
+    }
+
+    /// Both share an address, whose probe covers UDP and TCP.
+    async fn probe(&self) {
+        self.udp.probe().await
+    }
+
+    fn status(&self) -> Vec<serde_json::Value> {
+        self.udp.status()
+    }
+}
+
+/// Whichever of two queries answers first wins and the other is dropped,
+/// a timeout or transport error waits for the other one.
+async fn first_answer<T>(
+    a: BoxFuture<'_, Result<T, ResolveError>>,
+    b: BoxFuture<'_, Result<T, ResolveError>>,
+) -> Result<T, ResolveError> {
+    match future::select(a, b).await {
+        Either::Left((result, other)) | Either::Right((result, other)) => {
+            if result.as_ref().map_or_else(is_answer, |_| true) {
+                return result;
+            }
+            other.await
+        }
+    }
+}
+
+/// Whether `lookup` came from the cache. trust-dns does not tell, but an
+/// answer it caches expires after its lowest TTL while the records keep
+/// theirs, so one expiring a second or more before that is not fresh.
+pub fn from_cache(lookup: &Lookup, now: Instant) -> bool {
+    let ttl = lookup.record_iter().map(|record| record.ttl()).min();
+    match ttl {
+        Some(ttl) => {
+            let ttl = Duration::from_secs(ttl.min(MAX_TTL).into());
+            lookup.valid_until() + Duration::from_secs(1) <= now + ttl
+        }
+        None => false,
+    }
+}
+
+/// Whether the upstream answered, as opposed to timing out or failing.
+fn is_answer(err: &ResolveError) -> bool {
+    matches!(err.kind(), ResolveErrorKind::NoRecordsFound { .. })
+}
+
+fn sleep(duration: Duration) -> impl Future<Output = ()> {
+    Box::pin(async_std::task::sleep(duration))
+}
+
+#[cfg(test)]
+pub mod mock {
+    use std::collections::HashMap;
+    use std::net::IpAddr;
+    use std::str::FromStr;
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, Instant};
+
+    use async_trait::async_trait;
+    use trust_dns_resolver::error::{ResolveError, ResolveErrorKind};
+    use trust_dns_resolver::lookup::Lookup;
+    use trust_dns_resolver::proto::error::ProtoError;
+    use trust_dns_resolver::proto::op::{Message, MessageType, Query, ResponseCode};
+    use trust_dns_resolver::proto::rr::{Name, RData, Record, RecordType};
+
+    use super::Upstream;
+
+    pub const TTL: u32 = 300;
+
+    /// Upstream answering from a fixed table, unknown hosts are NXDOMAIN.
+    #[derive(Default)]
+    pub struct Mock {
+        answers: HashMap<String, Result<Vec<RData>, ResponseCode>>,
+        delay: Duration,
+        /// Extra delay of lookups of one type.
+        type_delay: Option<(RecordType, Duration)>,
+        negative_ttl: Option<u32>,
+        valid_until: Option<Instant>,
+        down: bool,
+        throttled: bool,
+        hang: bool,
+        /// Lookups of a host still to be answered without records.
+        empty: Mutex<HashMap<String, usize>>,
+    }
+
+    impl Mock {
+        /// Negative TTL reported with NXDOMAIN and NODATA.
+        pub fn negative_ttl(mut self, ttl: u32) -> Self {
+            self.negative_ttl = Some(ttl);
+            self
+        }
+
+        /// Answers as if from one cache entry expiring at `valid_until`.
+        pub fn valid_until(mut self, valid_until: Instant) -> Self {
+            self.valid_until = Some(valid_until);
+            self
+        }
+
+        /// Times out every query, like an unreachable server.
+        pub fn down(mut self) -> Self {
+            self.down = true;
+            self
+        }
+
+        /// Never answers, like a server dropping queries, for tests of the
+        /// deadlines around lookups.
+        pub fn hang(mut self) -> Self {
+            self.hang = true;
+            self
+        }
+
+        /// Refuses every query as over the upstream query budget.
+        pub fn throttled(mut self) -> Self {
+            self.throttled = true;
+            self
+        }
+
+        /// Answers the next `lookups` lookups of `host` with no records and
+        /// no SOA, like an upstream having a glitch.
+        pub fn empty(self, host: &str, lookups: usize) -> Self {
+            self.empty.lock().unwrap().insert(host.into(), lookups);
+            self
+        }
+
+        /// Answers only after `delay`.
+        pub fn delay(mut self, delay: Duration) -> Self {
+            self.delay = delay;
+            self
+        }
+
+        /// Waits out `delay`, forever with `hang`.
+        async fn wait(&self) {
+            if self.hang {
+                std::future::pending::<()>().await;
+            }
+            async_std::task::sleep(self.delay).await;
+        }
+
+        /// Answers `rtype` lookups only after `delay` more, like a
+        /// blackholed path for one family.
+        pub fn type_delay(mut self, rtype: RecordType, delay: Duration) -> Self {
+            self.type_delay = Some((rtype, delay));
+            self
+        }
+
+        pub fn ips(self, host: &str, ips: &[&str]) -> Self {
+            let rdata = ips
+                .iter()
+                .map(|v| match v.parse().unwrap() {
+                    IpAddr::V4(v) => RData::A(v),
+                    IpAddr::V6(v) => RData::AAAA(v),
+                })
+                .collect();
+            self.rdata(host, rdata)
+        }
+
+        pub fn ptr(self, ip: &str, names: &[&str]) -> Self {
+            let host = Name::from(ip.parse::<IpAddr>().unwrap()).to_string();
+            let rdata = names
+                .iter()
+                .map(|v| RData::PTR(Name::from_str(v).unwrap()))
+                .collect();
+            self.rdata(&host, rdata)
+        }
+
+        /// `alias` is a CNAME for `target`, followed like trust-dns does.
+        pub fn cname(self, alias: &str, target: &str) -> Self {
+            let target = Name::from_str(target).unwrap();
+            self.rdata(alias, vec![RData::CNAME(target)])
+        }
+
+        pub fn rdata(mut self, host: &str, rdata: Vec<RData>) -> Self {
+            let host = host.trim_end_matches('.');
+            self.answers.insert(host.into(), Ok(rdata));
+            self
+        }
+
+        pub fn rcode(mut self, host: &str, code: ResponseCode) -> Self {
+            let host = host.trim_end_matches('.');
+            self.answers.insert(host.into(), Err(code));
+            self
+        }
+    }
+
+    #[async_trait]
+    impl Upstream for Mock {
+        fn name(&self) -> &str {
+            "mock"
+        }
+
+        async fn lookup(&self, host: &str, rtype: RecordType) -> Result<Lookup, ResolveError> {
+            self.wait().await;
+            if let Some((_, delay)) = self.type_delay.filter(|(slow, _)| *slow == rtype) {
+                async_std::task::sleep(delay).await;
+            }
+            self.answer(host, rtype)
+        }
+
+        /// Adds the RRSIG records of `host` to the answers when `dnssec_ok`.
+        async fn query(
+            &self,
+            host: &str,
+            rtype: RecordType,
+            dnssec_ok: bool,
+        ) -> Result<Message, ResolveError> {
+            self.wait().await;
+            let mut message = Message::new();
+            message.set_message_type(MessageType::Response);
+            match self.answer(host, rtype) {
+                Ok(lookup) => message.add_answers(lookup.record_iter().cloned()),
+                Err(err) => match err.kind() {
+                    ResolveErrorKind::NoRecordsFound { response_code, .. } => {
+                        message.set_response_code(*response_code)
+                    }
+                    _ => return Err(err),
+                },
+            };
+            if let Some(Ok(rdata)) = self.answers.get(host.trim_end_matches('.')) {
+                let name = Name::from_str(host)?;
+                let sigs = rdata
+                    .iter()
+                    .filter(|rdata| dnssec_ok && rdata.to_record_type() == RecordType::Unknown(46))
+                    .map(|rdata| Record::from_rdata(name.clone(), TTL, rdata.clone()));
+                message.add_answers(sigs);
+            }
+            Ok(message)
+        }
+    }
+
+    impl Mock {
+        fn answer(&self, host: &str, rtype: RecordType) -> Result<Lookup, ResolveError> {
+            if self.down {
+                return Err(ResolveErrorKind::Timeout.into());
+            }
+            if self.throttled {
+                return Err(ProtoError::from(crate::egress::refused()).into());
+            }
+            let name = Name::from_str(host)?;
+            let query = Query::query(name.clone(), rtype);
+            if let Some(lookups) = self.empty.lock().unwrap().get_mut(host) {
+                if *lookups > 0 {
+                    *lookups -= 1;
+                    return Err(self.no_records(query, ResponseCode::NoError));
+                }
+            }
+            let rdata = match self.answers.get(host.trim_end_matches('.')) {
+                Some(Ok(rdata)) => rdata,
+                Some(Err(code)) => return Err(self.no_records(query, *code)),
+                None => return Err(self.no_records(query, ResponseCode::NXDomain)),
+            };
+            if let [RData::CNAME(target)] = rdata.as_slice() {
+                if rtype != RecordType::CNAME {
+                    let cname = Record::from_rdata(name, TTL, rdata[0].clone());
+                    let lookup = self.answer(&target.to_string(), rtype)?;
+                    let records = std::iter::once(cname)
+                        .chain(lookup.record_iter().cloned())
+                        .collect::<Vec<_>>();
+                    return Ok(self.lookup(query, records));
+                }
+            }
+            let records = rdata
+                .iter()
+                .filter(|rdata| match rtype {
+                    RecordType::ANY => true,
+                    rtype => rdata.to_record_type() == rtype,
+                })
+                .map(|rdata| Record::from_rdata(name.clone(), TTL, rdata.clone()))
+                .collect::<Vec<_>>();
+            if records.is_empty() {
+                return Err(self.no_records(query, ResponseCode::NoError));
+            }
+            Ok(self.lookup(query, records))
+        }
+
+        fn lookup(&self, query: Query, records: Vec<Record>) -> Lookup {
+            match self.valid_until {
+                Some(valid_until) => {
+                    Lookup::new_with_deadline(query, Arc::from(records), valid_until)
+                }
+                None => Lookup::new_with_max_ttl(query, Arc::from(records)),
+            }
+        }
+
+        fn no_records(&self, query: Query, response_code: ResponseCode) -> ResolveError {
+            ResolveErrorKind::NoRecordsFound {
+                query,
+                soa: None,
+                negative_ttl: self.negative_ttl,
+                response_code,
+                trusted: true,
+            }
+            .into()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+
+    use async_std::net::UdpSocket;
+    use trust_dns_resolver::lookup::Lookup;
+    use trust_dns_resolver::proto::op::{Edns, Message, MessageType, Query};
+    use trust_dns_resolver::proto::rr::{Name, RData, Record, RecordType};
+
+    use super::mock::Mock;
+    use super::{attempts, from_cache, shard_index, Capabilities, Hedge, Pool, Race, Upstream};
+
+    fn pool(max_ratio: f64) -> Pool {
+        let slow = Mock::default()
+            .ips("a.example", &["192.0.2.1"])
+            .delay(Duration::from_secs(5));
+        let fast = Mock::default().ips("a.example", &["192.0.2.2"]);
+        let hedge = Hedge {
+            after: Duration::from_millis(10),
+            max_ratio,
+        };
+        Pool::new(
+            vec![Arc::new(slow), Arc::new(fast)],
+            Some(hedge),
+            Arc::new(AtomicU64::new(0)),
+        )
+    }
+
+    #[async_std::test]
+    async fn hedge() {
+        let pool = pool(1.0);
+        let start = Instant::now();
+        let lookup = pool.lookup("a.example", RecordType::A).await.unwrap();
+        assert!(start.elapsed() < Duration::from_secs(1));
+        assert_eq!(lookup.iter().next().unwrap().to_string(), "192.0.2.2");
+        assert_eq!(pool.hedges.load(Ordering::Relaxed), 1);
+        // NXDOMAIN from the hedge is an answer too.
+        assert!(pool.lookup("none.example", RecordType::A).await.is_err());
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+
+    #[async_std::test]
+    async fn hedge_failover() {
+        let down = || Arc::new(Mock::default().down().delay(Duration::from_millis(20)));
+        let hedge = || Hedge {
+            after: Duration::from_millis(10),
+            max_ratio: 1.0,
+        };
+        let up = Arc::new(Mock::default().ips("a.example", &["192.0.2.3"]));
+        for (upstreams, answered) in [
+            (vec![down(), down()], false),
+            (vec![down(), down(), up], true),
+        ] {
+            let asked = upstreams.len();
+            let upstreams = upstreams
+                .into_iter()
+                .map(|upstream| upstream as Arc<dyn Upstream>)
+                .collect();
+            let pool = Pool::new(upstreams, Some(hedge()), Arc::new(AtomicU64::new(0)));
+            let (result, tries) = attempts::Traced::new(async {
+                (
+                    pool.lookup("a.example", RecordType::A).await,
+                    attempts::asked(),
+                )
+            })
+            .await;
+            assert_eq!(result.is_ok(), answered);
+            // The hedged upstream is not asked again on failover.
+            assert_eq!(tries.len(), asked);
+            assert_eq!(pool.hedges.load(Ordering::Relaxed), 1);
+        }
+    }
+
+    #[async_std::test]
+    async fn hedge_budget() {
+        let pool = pool(0.5);
+        assert!(!pool.take_hedge());
+        for _ in 0..3 {
+            pool.earn_hedge(pool.hedge.as_ref().unwrap());
+        }
+        assert!(pool.take_hedge());
+        assert!(!pool.take_hedge());
+    }
+
+    #[async_std::test]
+    async fn race() {
+        let slow = || {
+            Mock::default()
+                .ips("a.example", &["192.0.2.1"])
+                .delay(Duration::from_secs(5))
+        };
+        let fast = || Mock::default().ips("a.example", &["192.0.2.2"]);
+        for race in [
+            Race::new(Arc::new(slow()), Arc::new(fast())),
+            Race::new(Arc::new(fast()), Arc::new(slow())),
+        ] {
+            let start = Instant::now();
+            let lookup = race.lookup("a.example", RecordType::A).await.unwrap();
+            assert_eq!(lookup.iter().next().unwrap().to_string(), "192.0.2.2");
+            let message = race.query("a.example", RecordType::A, false).await.unwrap();
+            assert_eq!(message.answers()[0].rdata().to_string(), "192.0.2.2");
+            assert!(start.elapsed() < Duration::from_secs(1));
+        }
+        // A failed transport waits for the other one.
+        let race = Race::new(
+            Arc::new(Mock::default().down()),
+            Arc::new(
+                Mock::default()
+                    .ips("a.example", &["192.0.2.1"])
+                    .delay(Duration::from_millis(20)),
+            ),
+        );
+        let lookup = race.lookup("a.example", RecordType::A).await.unwrap();
+        assert_eq!(lookup.iter().next().unwrap().to_string(), "192.0.2.1");
+    }
+
+    #[test]
+    fn cache_hit() {
+        let name = Name::from_ascii("a.example.").unwrap();
+        let record = Record::from_rdata(name.clone(), 300, RData::A("192.0.2.1".parse().unwrap()));
+        let lookup = |valid_until| {
+            Lookup::new_with_deadline(
+                Query::query(name.clone(), RecordType::A),
+                Arc::from(vec![record.clone()]),
+                valid_until,
+            )
+        };
+        let now = Instant::now();
+        assert!(!from_cache(&lookup(now + Duration::from_secs(300)), now));
+        assert!(from_cache(&lookup(now + Duration::from_secs(250)), now));
+    }
+
+    #[async_std::test]
+    async fn capabilities() {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        async_std::task::spawn(async move {
+            let mut buf = [0; 512];
+            let (len, from) = socket.recv_from(&mut buf).await.unwrap();
+            let query = Message::from_vec(&buf[..len]).unwrap();
+            assert!(query.edns().is_some());
+            let mut edns = Edns::new();
+            edns.set_max_payload(4096);
+            let mut res = Message::new();
+            res.set_id(query.id())
+                .set_message_type(MessageType::Response)
+                .set_edns(edns);
+            socket.send_to(&res.to_vec().unwrap(), from).await.unwrap();
+        });
+        // Nothing listens on the TCP port.
+        let capabilities = Capabilities::probe(addr).await;
+        assert_eq!(capabilities.edns, Some(true));
+        assert_eq!(capabilities.udp_payload, Some(4096));
+        assert_eq!(capabilities.tcp, Some(false));
+        assert!(capabilities.checked_at.is_some());
+        let pool = Pool::new(
+            vec![Arc::new(Mock::default())],
+            None,
+            Arc::new(AtomicU64::new(0)),
+        );
+        pool.probe().await;
+        assert_eq!(pool.status(), vec![serde_json::json!({ "name": "mock" })]);
+    }
+
+    #[test]
+    fn shards() {
+        assert_eq!(
+            shard_index("One.Example.", 8),
+            shard_index("one.example", 8)
+        );
+        assert_eq!(shard_index("one.example", 1), 0);
+        let mut counts = [0; 8];
+        for i in 0..8000 {
+            counts[shard_index(&format!("host{}.example", i), 8)] += 1;
+        }
+        assert!(counts.iter().all(|&count| (800..1200).contains(&count)));
+    }
+
+    #[async_std::test]
+    async fn egress() {
+        let addr = answering_upstream().await;
+        let resolver =
+            crate::upstream_resolver(&addr.to_string(), crate::opts::DEFAULT_CACHE_SIZE, 1)
+                .unwrap();
+        let queries = || crate::egress::QUERIES.load(Ordering::Relaxed);
+        let before = queries();
+        resolver.lookup("a.example.", RecordType::A).await.unwrap();
+        // Other tests send queries too.
+        assert!(queries() > before);
+        let before = queries();
+        resolver
+            .query_wire("a.example.", RecordType::A, false)
+            .await
+            .unwrap();
+        assert!(queries() > before);
+    }
+
+    /// Answers every query with an A record, as a warm cache would hold.
+    async fn answering_upstream() -> std::net::SocketAddr {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        async_std::task::spawn(async move {
+            let mut buf = [0; 512];
+            loop {
+                let (len, from) = socket.recv_from(&mut buf).await.unwrap();
+                let query = Message::from_vec(&buf[..len]).unwrap();
+                let name = query.queries()[0].name().clone();
+                let a = RData::A("192.0.2.1".parse().unwrap());
+                let mut res = Message::new();
+                res.set_id(query.id())
+                    .set_message_type(MessageType::Response)
+                    .add_query(query.queries()[0].clone())
+                    .add_answer(Record::from_rdata(name, 300, a));
+                socket.send_to(&res.to_vec().unwrap(), from).await.unwrap();
+            }
+        });
+        addr
+    }
+
+    /// Cache hits from every CPU, one lock against eight:
+    /// `cargo test --release shard_throughput -- --ignored --nocapture`
+    #[async_std::test]
+    #[ignore]
+    async fn shard_throughput() {
+        const HOSTS: usize = 8;
+        const TASKS: usize = 64;
+        const LOOKUPS: usize = 5_000;
+        let addr = answering_upstream().await;
+        for shards in [1, 8] {
+            let resolver = Arc::new(
+                crate::upstream_resolver(
+                    &addr.to_string(),
+                    crate::opts::DEFAULT_CACHE_SIZE,
+                    shards,
+                )
+                .unwrap(),
+            );
+            for i in 0..HOSTS {
+                let host = format!("host{}.example.", i);
+                resolver.lookup(&host, RecordType::A).await.unwrap();
+            }
+            let start = Instant::now();
+            let tasks = (0..TASKS).map(|task| {
+                let resolver = resolver.clone();
+                async_std::task::spawn(async move {
+                    for i in 0..LOOKUPS {
+                        let host = format!("host{}.example.", (task + i) % HOSTS);
+                        resolver.lookup(&host, RecordType::A).await.unwrap();
+                    }
+                })
+            });
+            futures_util::future::join_all(tasks).await;
+            let elapsed = start.elapsed();
+            println!(
+                "{} shards: {:.0} lookups/s",
+                shards,
+                (TASKS * LOOKUPS) as f64 / elapsed.as_secs_f64()
+            );
+        }
+    }
+}
@@ -0,0 +1,117 @@
+use std::io;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use async_std::io::{ReadExt, WriteExt};
+use async_std::net::TcpStream;
+use trust_dns_resolver::proto::op::{Edns, Message, MessageType, OpCode, Query};
+use trust_dns_resolver::proto::rr::{Name, RecordType};
+
+use crate::{bind, egress};
+
+/// Applies to connecting and to every message read or sent.
+pub const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// EDNS buffer size advertised with the DO bit, per the DNS flag day 2020.
+const MAX_PAYLOAD: u16 = 1232;
+
+/// A recursive query, with an EDNS OPT record setting DO if `dnssec_ok`.
+pub fn query(name: Name, rtype: RecordType, dnssec_ok: bool) -> Message {
+    let mut message = Message::new();
+    message
+        .set_id(rand::random())
+        .set_message_type(MessageType::Query)
+        .set_op_code(OpCode::Query)
+        .set_recursion_desired(true)
+        .add_query(Query::query(name, rtype));
+    if dnssec_ok {
+        set_edns(&mut message, true);
+    }
+    message
+}
+
+/// Adds an EDNS OPT record advertising `MAX_PAYLOAD`.
+pub fn set_edns(message: &mut Message, dnssec_ok: bool) {
+    let mut edns = Edns::new();
+    edns.set_dnssec_ok(dnssec_ok).set_max_payload(MAX_PAYLOAD);
+    message.set_edns(edns);
+}
+
+/// Sends `message` over UDP, retrying over TCP if the answer is truncated.
+/// The answer comes with the bytes received.
+pub async fn exchange_wire(
+    server: SocketAddr,
+    message: &Message,
+) -> io::Result<(Message, Vec<u8>)> {
+    let res = udp_wire(server, message).await?;
+    if !res.0.truncated() {
+        return Ok(res);
+    }
+    exchange_tcp_wire(server, message).await
+}
+
+/// Sends `message` over UDP only, the answer may be truncated.
+pub async fn exchange_udp(server: SocketAddr, message: &Message) -> io::Result<Message> {
+    Ok(udp_wire(server, message).await?.0)
+}
+
+async fn udp_wire(server: SocketAddr, message: &Message) -> io::Result<(Message, Vec<u8>)> {
+    let buf = message.to_vec().map_err(invalid_data)?;
+    let socket = bind::udp_socket(server).await?;
+    egress::admit().await?;
+    async_std::io::timeout(TIMEOUT, socket.send_to(&buf, server)).await?;
+    let mut res = vec![0; u16::MAX.into()];
+    loop {
+        let (len, from) = async_std::io::timeout(TIMEOUT, socket.recv_from(&mut res)).await?;
+        // Ignores strays, as a spoofed answer would not know the ID.
+        match Message::from_vec(&res[..len]) {
+            Ok(answer) if from == server && answer.id() == message.id() => {
+                res.truncate(len);
+                return Ok((answer, res));
+            }
+            _ => continue,
+        }
+    }
+}
+
+/// Sends `message` over a new TCP connection.
+pub async fn exchange_tcp(server: SocketAddr, message: &Message) -> io::Result<Message> {
+    Ok(exchange_tcp_wire(server, message).await?.0)
+}
+
+/// `exchange_tcp`, with the answer also as the bytes received.
+pub async fn exchange_tcp_wire(
+    server: SocketAddr,
+    message: &Message,
+) -> io::Result<(Message, Vec<u8>)> {
+    let mut conn = bind::tcp_connect(server).await?;
+    write_tcp(&mut conn, message).await?;
+    read_tcp_wire(&mut conn).await
+}
+
+/// Writes a length-prefixed message, within the upstream query budget.
+pub async fn write_tcp(conn: &mut TcpStream, message: &Message) -> io::Result<()> {
+    let buf = message.to_vec().map_err(invalid_data)?;
+    let mut framed = (buf.len() as u16).to_be_bytes().to_vec();
+    framed.extend(buf);
+    egress::admit().await?;
+    async_std::io::timeout(TIMEOUT, conn.write_all(&framed)).await
+}
+
+/// Reads a length-prefixed message.
+pub async fn read_tcp(conn: &mut TcpStream) -> io::Result<Message> {
+    Ok(read_tcp_wire(conn).await?.0)
+}
+
+async fn read_tcp_wire(conn: &mut TcpStream) -> io::Result<(Message, Vec<u8>)> {
+    let mut len = [0; 2];
+    async_std::io::timeout(TIMEOUT, conn.read_exact(&mut len)).await?;
+    let mut buf = vec![0; u16::from_be_bytes(len).into()];
+    async_std::io::timeout(TIMEOUT, conn.read_exact(&mut buf)).await?;
+    let message = Message::from_vec(&buf).map_err(invalid_data)?;
+    Ok((message, buf))
+}
+
+fn invalid_data<E: std::error::Error + Send + Sync + 'static>(err: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, err)
+}
@@ -0,0 +1,135 @@
+use futures_util::stream::{self, StreamExt};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tide::{Endpoint, Request};
+use tide_websockets::{Message, WebSocket, WebSocketConnection};
+use trust_dns_resolver::proto::rr::RecordType;
+
+use crate::{
+    error_code, is_admin, lookup_addrs, type_allowed, validate_host, State, NOT_FOUND,
+    TYPE_NOT_ALLOWED,
+};
+
+/// A single lookup request, `type` is `A`, `AAAA` or omitted for both.
+#[derive(Deserialize)]
+struct Frame {
+    #[serde(default)]
+    id: Value,
+    host: String,
+    #[serde(rename = "type")]
+    rtype: Option<String>,
+}
+
+pub fn handler() -> impl Endpoint<State> {
+    WebSocket::new(serve)
+}
+
+/// Answers frames as their lookups complete, at most `ws_concurrency` at a
+/// time. Replies are written from this task only, so a client that stops
+/// reading stalls the pending lookups instead of growing a buffer.
+async fn serve(req: Request<State>, conn: WebSocketConnection) -> tide::Result<()> {
+    let state = req.state();
+    let admin = is_admin(&req);
+    let idle_timeout = state.opts.ws_idle_timeout;
+    let frames = stream::unfold(conn.clone(), move |mut conn| async move {
+        match async_std::future::timeout(idle_timeout, conn.next()).await {
+            Ok(Some(Ok(msg))) => Some((msg, conn)),
+            _ => None,
+        }
+    });
+    let replies = frames
+        .filter_map(|msg| async move {
+            match msg {
+                Message::Text(text) => Some(text),
+                Message::Binary(data) => Some(String::from_utf8_lossy(&data).into_owned()),
+                _ => None,
+            }
+        })
+        .map(|text| reply(state, admin, text))
+        .buffer_unordered(state.opts.ws_concurrency);
+    futures_util::pin_mut!(replies);
+    while let Some(reply) = replies.next().await {
+        conn.send_json(&reply).await?;
+    }
+    Ok(())
+}
+
+/// `admin` when the upgrade request carried `auth_token`.
+async fn reply(state: &State, admin: bool, text: String) -> Value {
+    let frame: Frame = match serde_json::from_str(&text) {
+        Ok(frame) => frame,
+        Err(_) => return json!({ "id": Value::Null, "error": "bad_frame" }),
+    };
+    let rtype = match frame.rtype.as_deref() {
+        None => None,
+        Some(t) if t.eq_ignore_ascii_case("A") => Some(RecordType::A),
+        Some(t) if t.eq_ignore_ascii_case("AAAA") => Some(RecordType::AAAA),
+        Some(_) => return json!({ "id": frame.id, "error": "bad_type" }),
+    };
+    if !type_allowed(&state.opts, admin, rtype) {
+        return json!({ "id": frame.id, "error": TYPE_NOT_ALLOWED });
+    }
+    if !validate_host(&frame.host) {
+        return json!({ "id": frame.id, "error": "bad_host" });
+    }
+    match lookup_addrs(state, &frame.host, rtype).await {
+        Ok(addrs) if addrs.is_empty() => json!({ "id": frame.id, "error": NOT_FOUND }),
+        Ok(addrs) => json!({ "id": frame.id, "addrs": addrs }),
+        Err(err) => json!({ "id": frame.id, "error": error_code(state, &frame.host, &err) }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use trust_dns_resolver::proto::op::ResponseCode;
+
+    use super::reply;
+    use crate::tests::state;
+    use crate::upstream::mock::Mock;
+
+    #[async_std::test]
+    async fn frames() {
+        let state = state(
+            Mock::default()
+                .ips("dual.example", &["192.0.2.1", "2001:db8::1"])
+                .rcode("broken.example", ResponseCode::ServFail),
+        );
+        let frame = |v: serde_json::Value| v.to_string();
+        assert_eq!(
+            reply(
+                &state,
+                false,
+                frame(json!({"id": 1, "host": "dual.example", "type": "AAAA"}))
+            )
+            .await,
+            json!({"id": 1, "addrs": ["2001:db8::1"]}),
+        );
+        assert_eq!(
+            reply(
+                &state,
+                false,
+                frame(json!({"id": 2, "host": "none.example"}))
+            )
+            .await,
+            json!({"id": 2, "error": "nx"}),
+        );
+        assert_eq!(
+            reply(
+                &state,
+                false,
+                frame(json!({"id": 3, "host": "broken.example"}))
+            )
+            .await,
+            json!({"id": 3, "error": "servfail"}),
+        );
+        assert_eq!(
+            reply(&state, false, frame(json!({"id": 4, "host": "bad_host"}))).await,
+            json!({"id": 4, "error": "bad_host"}),
+        );
+        assert_eq!(
+            reply(&state, false, "{".into()).await,
+            json!({"id": null, "error": "bad_frame"}),
+        );
+    }
+}
@@ -0,0 +1,343 @@
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
+use std::fmt::Write;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use psl::Psl;
+use serde::Deserialize;
+use serde_json::json;
+use tide::{Request, Response, StatusCode};
+
+use crate::{params, State};
+
+/// Zones counted at most. A new one past it replaces the least queried.
+pub const MAX_ZONES: usize = 10_000;
+
+/// Counts are halved this often, so the traffic of long ago fades out.
+pub const HALF_LIFE: Duration = Duration::from_secs(3600);
+
+/// Zones answered by `/stats/zones` without `n`.
+const DEFAULT_N: usize = 50;
+
+const LIMITS: &[params::Limit] = &[params::Limit {
+    key: "n",
+    min: 0,
+    max: MAX_ZONES as i128,
+}];
+
+/// Public suffix list registrable domains are taken from.
+pub enum Suffixes {
+    /// The list compiled in.
+    Builtin,
+    /// A list read from `PSL_FILE`, for suffixes newer than the build.
+    File(publicsuffix::List),
+}
+
+impl Suffixes {
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let list = publicsuffix::List::from_bytes(&fs::read(path)?)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        Ok(Self::File(list))
+    }
+
+    /// eTLD+1 of `host`, or `host` itself when it has none, as a bare
+    /// public suffix.
+    pub fn registrable(&self, host: &str) -> String {
+        let host = host.trim_end_matches('.').to_ascii_lowercase();
+        let domain = match self {
+            Self::Builtin => psl::List.domain(host.as_bytes()),
+            Self::File(list) => list.domain(host.as_bytes()),
+        };
+        match domain {
+            Some(domain) => String::from_utf8_lossy(domain.as_bytes()).into_owned(),
+            None => host,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Counts {
+    pub queries: u64,
+    /// Answers that came from the cache.
+    pub cache_hits: u64,
+    /// Timeouts, SERVFAIL and other upstream failures.
+    pub errors: u64,
+}
+
+/// Lookups by registrable domain, for attributing usage. At most
+/// `MAX_ZONES` are kept and every count halves each `HALF_LIFE`.
+pub struct ZoneStats {
+    suffixes: Suffixes,
+    zones: Mutex<Zones>,
+}
+
+struct Zones {
+    counts: HashMap<String, Counts>,
+    /// Min-heap of every zone by queries, as of when it was pushed. Counts
+    /// only grow between decays, so an entry is at most the current count
+    /// and is brought up to date when it reaches the top.
+    least: BinaryHeap<Reverse<(u64, String)>>,
+    decayed_at: Instant,
+}
+
+impl ZoneStats {
+    pub fn new(suffixes: Suffixes) -> Self {
+        Self {
+            suffixes,
+            zones: Mutex::new(Zones {
+                counts: HashMap::new(),
+                least: BinaryHeap::new(),
+                decayed_at: Instant::now(),
+            }),
+        }
+    }
+
+    pub fn record(&self, host: &str, cache_hit: bool, error: bool) {
+        let zone = self.suffixes.registrable(host);
+        let mut zones = self.zones.lock().unwrap();
+        zones.decay(Instant::now());
+        if !zones.counts.contains_key(&zone) {
+            if zones.counts.len() >= MAX_ZONES {
+                zones.evict();
+            }
+            zones.least.push(Reverse((1, zone.clone())));
+        }
+        let counts = zones.counts.entry(zone).or_default();
+        counts.queries += 1;
+        counts.cache_hits += u64::from(cache_hit);
+        counts.errors += u64::from(error);
+    }
+
+    /// The `n` most queried zones, most first.
+    pub fn top(&self, n: usize) -> Vec<(String, Counts)> {
+        let mut zones = self.zones.lock().unwrap();
+        zones.decay(Instant::now());
+        let mut top = zones
+            .counts
+            .iter()
+            .map(|(zone, counts)| (zone.clone(), *counts))
+            .collect::<Vec<_>>();
+        top.sort_by(|a, b| b.1.queries.cmp(&a.1.queries).then_with(|| a.0.cmp(&b.0)));
+        top.truncate(n);
+        top
+    }
+
+    /// Every zone as CSV, most queried first.
+    pub fn csv(&self) -> String {
+        let mut csv = String::from("zone,queries,cache_hits,errors\r\n");
+        for (zone, counts) in self.top(MAX_ZONES) {
+            let _ = write!(
+                csv,
+                "{},{},{},{}\r\n",
+                zone, counts.queries, counts.cache_hits, counts.errors
+            );
+        }
+        csv
+    }
+
+    /// Replaces `path` with `csv`, returning the zones written.
+    pub fn dump(&self, path: &Path) -> io::Result<usize> {
+        let csv = self.csv();
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, &csv)?;
+        fs::rename(&tmp, path)?;
+        Ok(csv.lines().count() - 1)
+    }
+}
+
+impl Zones {
+    fn decay(&mut self, now: Instant) {
+        let halvings = now.duration_since(self.decayed_at).as_secs() / HALF_LIFE.as_secs();
+        if halvings == 0 {
+            return;
+        }
+        let shift = halvings.min(63);
+        self.counts.retain(|_, counts| {
+            counts.queries >>= shift;
+            counts.cache_hits >>= shift;
+            counts.errors >>= shift;
+            counts.queries > 0
+        });
+        self.least = self
+            .counts
+            .iter()
+            .map(|(zone, counts)| Reverse((counts.queries, zone.clone())))
+            .collect();
+        self.decayed_at += HALF_LIFE * halvings as u32;
+    }
+
+    /// Removes the least queried zone, updating the stale entries above it.
+    fn evict(&mut self) {
+        while let Some(Reverse((queries, zone))) = self.least.pop() {
+            match self.counts.get(&zone) {
+                Some(counts) if counts.queries > queries => {
+                    self.least.push(Reverse((counts.queries, zone)));
+                }
+                Some(_) => {
+                    self.counts.remove(&zone);
+                    return;
+                }
+                None => {}
+            }
+        }
+    }
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct ZonesQuery {
+    n: Option<usize>,
+}
+
+/// `/stats/zones?n=50`: the zones looked up most, with their cache hit
+/// ratio and errors.
+pub async fn handler(req: Request<State>) -> tide::Result {
+    let query: ZonesQuery = params::query(&req, LIMITS)?;
+    let stats = match &req.state().zones {
+        Some(stats) => stats,
+        None => return Ok(Response::new(StatusCode::NotFound)),
+    };
+    let zones = stats
+        .top(query.n.unwrap_or(DEFAULT_N))
+        .into_iter()
+        .map(|(zone, counts)| {
+            json!({
+                "zone": zone,
+                "queries": counts.queries,
+                "cache_hits": counts.cache_hits,
+                "cache_hit_ratio": counts.cache_hits as f64 / counts.queries as f64,
+                "errors": counts.errors,
+            })
+        })
+        .collect::<Vec<_>>();
+    Ok(json!({ "zones": zones }).into())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+
+    use tide::http::{Method, Request, Response, Url};
+    use trust_dns_resolver::proto::op::ResponseCode;
+
+    use super::{Counts, Suffixes, ZoneStats, HALF_LIFE, MAX_ZONES};
+    use crate::server;
+    use crate::tests::state;
+    use crate::upstream::mock::Mock;
+
+    #[test]
+    fn registrable() {
+        let builtin = Suffixes::Builtin;
+        assert_eq!(builtin.registrable("www.Example.com."), "example.com");
+        assert_eq!(builtin.registrable("a.b.example.co.uk"), "example.co.uk");
+        assert_eq!(builtin.registrable("co.uk"), "co.uk");
+
+        let path = std::env::temp_dir().join(format!("bdns-psl-{}.dat", std::process::id()));
+        std::fs::write(&path, "// ===BEGIN ICANN DOMAINS===\ncom\nexample.com\n").unwrap();
+        let file = Suffixes::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(file.registrable("www.team.example.com"), "team.example.com");
+        assert_eq!(builtin.registrable("www.team.example.com"), "example.com");
+        assert!(Suffixes::load(&path).is_err());
+    }
+
+    #[test]
+    fn bounded() {
+        let stats = ZoneStats::new(Suffixes::Builtin);
+        for _ in 0..4 {
+            stats.record("www.example.com", true, false);
+        }
+        stats.record("example.net", false, true);
+        for i in 0..MAX_ZONES {
+            stats.record(&format!("host{}.example", i), false, false);
+        }
+        let top = stats.top(2);
+        assert_eq!(
+            top[0],
+            (
+                "example.com".into(),
+                Counts {
+                    queries: 4,
+                    cache_hits: 4,
+                    errors: 0
+                }
+            )
+        );
+        assert_eq!(stats.zones.lock().unwrap().counts.len(), MAX_ZONES);
+
+        let mut zones = stats.zones.lock().unwrap();
+        let decayed_at = zones.decayed_at;
+        zones.decay(decayed_at + HALF_LIFE);
+        assert_eq!(zones.counts.len(), 1);
+        assert_eq!(zones.counts["example.com"].queries, 2);
+        zones.decay(decayed_at + HALF_LIFE * 100);
+        assert!(zones.counts.is_empty());
+    }
+
+    #[test]
+    fn evict() {
+        let stats = ZoneStats::new(Suffixes::Builtin);
+        for (host, queries) in [("a.example", 3), ("b.example", 1), ("c.example", 2)] {
+            for _ in 0..queries {
+                stats.record(host, false, false);
+            }
+        }
+        let mut zones = stats.zones.lock().unwrap();
+        zones.evict();
+        assert!(!zones.counts.contains_key("b.example"));
+        zones.evict();
+        assert!(!zones.counts.contains_key("c.example"));
+        assert_eq!(zones.least.len(), 1);
+        zones.evict();
+        assert!(zones.counts.is_empty() && zones.least.is_empty());
+    }
+
+    #[async_std::test]
+    async fn handler() {
+        let url = Url::parse("http://localhost/stats/zones?n=2").unwrap();
+        let res: Response = server(state(Mock::default()))
+            .respond(Request::new(Method::Get, url.clone()))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), 404);
+
+        let mut state = state(
+            Mock::default()
+                .ips("www.example.com", &["192.0.2.1"])
+                .ips("api.example.com", &["192.0.2.2"])
+                .rcode("example.net", ResponseCode::ServFail)
+                .valid_until(Instant::now() + Duration::from_secs(100)),
+        );
+        state.zones = Some(Arc::new(ZoneStats::new(Suffixes::Builtin)));
+        let app = server(state.clone());
+        for path in ["/r/www.example.com", "/r/api.example.com", "/r/example.net"] {
+            let url = Url::parse("http://localhost").unwrap().join(path).unwrap();
+            app.respond::<_, Response>(Request::new(Method::Get, url))
+                .await
+                .unwrap();
+        }
+        let mut res: Response = app.respond(Request::new(Method::Get, url)).await.unwrap();
+        assert_eq!(res.status(), 200);
+        let body: serde_json::Value = res.body_json().await.unwrap();
+        assert_eq!(
+            body,
+            serde_json::json!({ "zones": [
+                { "zone": "example.com", "queries": 2, "cache_hits": 2, "cache_hit_ratio": 1.0, "errors": 0 },
+                { "zone": "example.net", "queries": 1, "cache_hits": 0, "cache_hit_ratio": 0.0, "errors": 1 },
+            ] })
+        );
+
+        let path = std::env::temp_dir().join(format!("bdns-zones-{}.csv", std::process::id()));
+        assert_eq!(state.zones.as_ref().unwrap().dump(&path).unwrap(), 2);
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            "zone,queries,cache_hits,errors\r\nexample.com,2,2,0\r\nexample.net,1,0,1\r\n"
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+}
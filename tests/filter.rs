@@ -0,0 +1,57 @@
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+
+use async_std::net::UdpSocket;
+use bdns_resolver::{server, AnswerFilter, ResolvedAnswer, State};
+use tide::http::{Method, Request, Response, Url};
+use trust_dns_resolver::proto::op::{Message, MessageType};
+use trust_dns_resolver::proto::rr::{RData, Record};
+
+/// Answers every query with an A record of 192.0.2.1.
+async fn upstream() -> SocketAddr {
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let addr = socket.local_addr().unwrap();
+    async_std::task::spawn(async move {
+        let mut buf = [0; 512];
+        loop {
+            let (len, from) = socket.recv_from(&mut buf).await.unwrap();
+            let query = Message::from_vec(&buf[..len]).unwrap();
+            let name = query.queries()[0].name().clone();
+            let a = RData::A(Ipv4Addr::new(192, 0, 2, 1));
+            let mut res = Message::new();
+            res.set_id(query.id())
+                .set_message_type(MessageType::Response)
+                .add_query(query.queries()[0].clone())
+                .add_answer(Record::from_rdata(name, 300, a));
+            socket.send_to(&res.to_vec().unwrap(), from).await.unwrap();
+        }
+    });
+    addr
+}
+
+/// Sends a CDN address to an internal mirror, as a site-specific filter.
+struct Mirror;
+
+impl AnswerFilter for Mirror {
+    fn filter(&self, _host: &str, mut answers: Vec<ResolvedAnswer>) -> Vec<ResolvedAnswer> {
+        for answer in &mut answers {
+            if answer.rdata() == &RData::A(Ipv4Addr::new(192, 0, 2, 1)) {
+                answer.set_rdata(RData::A(Ipv4Addr::new(198, 51, 100, 1)));
+            }
+        }
+        answers
+    }
+}
+
+#[async_std::test]
+async fn embedded() {
+    std::env::set_var("DNS", upstream().await.to_string());
+    let state = State::builder().filter(Arc::new(Mirror)).build().unwrap();
+    let url = Url::parse("http://localhost/r/cdn.example?t=A").unwrap();
+    let mut res: Response = server(state)
+        .respond(Request::new(Method::Get, url))
+        .await
+        .unwrap();
+    assert_eq!(res.status(), 200);
+    assert_eq!(res.body_string().await.unwrap(), "198.51.100.1");
+}
@@ -0,0 +1,40 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use async_std::channel;
+use async_std::io::{ReadExt, WriteExt};
+use async_std::net::TcpStream;
+use bdns_resolver::State;
+
+#[tokio::test]
+async fn ready_on_port_zero() {
+    std::env::set_var("ADDR", "127.0.0.1:0");
+    let (tx, rx) = channel::bounded(1);
+    let shutdowns = Arc::new(AtomicUsize::new(0));
+    let counted = shutdowns.clone();
+    let state = State::builder()
+        .on_ready(move |addrs| {
+            let tx = tx.clone();
+            async move { tx.send(addrs).await.unwrap() }
+        })
+        .on_shutdown(move || {
+            counted.fetch_add(1, Ordering::Relaxed);
+            async {}
+        })
+        .build()
+        .unwrap();
+    tokio::spawn(bdns_resolver::serve(state));
+    let addrs = rx.recv().await.unwrap();
+    assert_eq!(addrs.len(), 1);
+    assert_ne!(addrs[0].port(), 0);
+
+    let mut conn = TcpStream::connect(addrs[0]).await.unwrap();
+    conn.write_all(b"GET /ping HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+        .await
+        .unwrap();
+    let mut res = String::new();
+    conn.read_to_string(&mut res).await.unwrap();
+    assert!(res.starts_with("HTTP/1.1 200"));
+    // Shutdown hooks only run on a signal.
+    assert_eq!(shutdowns.load(Ordering::Relaxed), 0);
+}